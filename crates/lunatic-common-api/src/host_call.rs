@@ -0,0 +1,168 @@
+//! Tracking of the host function a process is currently calling.
+//!
+//! Wasmtime's call hook only tells us that a process entered *some* host function. Host functions
+//! registered through [`NamedHostFunctions`] record their own name right after the hook ran, so
+//! that the watchdog can report which host function a stuck process is waiting on.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use wasmtime::{Caller, IntoFunc, Linker, WasmRet, WasmTy};
+
+/// Name of the host function a process is inside of, updated on every host call.
+#[derive(Default)]
+pub struct HostCallName(Mutex<Option<Arc<str>>>);
+
+impl HostCallName {
+    /// Returns the name of the current (or last) host function, `None` if it wasn't registered
+    /// through [`NamedHostFunctions`].
+    pub fn get(&self) -> Option<Arc<str>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+thread_local! {
+    // The process entering a host function on this thread, set by the call hook.
+    static ENTERING: RefCell<Option<Arc<HostCallName>>> = const { RefCell::new(None) };
+}
+
+/// Should be called from the store's call hook right before a host function is entered.
+pub fn entering_host(name: &Arc<HostCallName>) {
+    *name.0.lock().unwrap() = None;
+    ENTERING.with(|entering| *entering.borrow_mut() = Some(name.clone()));
+}
+
+/// Should be called from the store's call hook once the host function returns.
+pub fn exiting_host() {
+    ENTERING.with(|entering| entering.borrow_mut().take());
+}
+
+// Called by named host functions before running.
+fn entered(function: &Arc<str>) {
+    ENTERING.with(|entering| {
+        if let Some(name) = entering.borrow_mut().take() {
+            *name.0.lock().unwrap() = Some(function.clone());
+        }
+    });
+}
+
+/// A host function that can be wrapped to record its name when called.
+pub trait HostFunction<T, Params, Results>: Send + Sync + 'static {
+    fn named(self, name: Arc<str>) -> impl IntoFunc<T, Params, Results>;
+}
+
+macro_rules! impl_host_function {
+    ($($args:ident)*) => {
+        #[allow(non_snake_case)]
+        impl<'c, T, F, $($args,)* R> HostFunction<T, (Caller<'c, T>, $($args,)*), R> for F
+        where
+            F: Fn(Caller<'_, T>, $($args),*) -> R + Send + Sync + 'static,
+            $($args: WasmTy,)*
+            R: WasmRet,
+        {
+            fn named(self, name: Arc<str>) -> impl IntoFunc<T, (Caller<'c, T>, $($args,)*), R> {
+                move |caller: Caller<'_, T>, $($args: $args),*| {
+                    entered(&name);
+                    self(caller, $($args),*)
+                }
+            }
+        }
+    };
+}
+
+impl_host_function!();
+impl_host_function!(A1);
+impl_host_function!(A1 A2);
+impl_host_function!(A1 A2 A3);
+impl_host_function!(A1 A2 A3 A4);
+impl_host_function!(A1 A2 A3 A4 A5);
+impl_host_function!(A1 A2 A3 A4 A5 A6);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7 A8);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7 A8 A9);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11);
+impl_host_function!(A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11 A12);
+
+macro_rules! named_async_funcs {
+    ($(($method:ident, $wrap:ident, $($args:ident)*))*) => {
+        /// Defines host functions that record their name (e.g. `lunatic::process::sleep_ms`) when
+        /// called, mirroring [`Linker::func_wrap`] and [`Linker::func_wrap1_async`].
+        pub trait NamedHostFunctions<T> {
+            fn func_wrap_named<Params, Args>(
+                &mut self,
+                module: &str,
+                name: &str,
+                func: impl HostFunction<T, Params, Args>,
+            ) -> Result<&mut Self>;
+
+            $(
+                fn $method<$($args,)* R>(
+                    &mut self,
+                    module: &str,
+                    name: &str,
+                    func: impl for<'a> Fn(Caller<'a, T>, $($args),*) -> Box<dyn Future<Output = R> + Send + 'a>
+                        + Send
+                        + Sync
+                        + 'static,
+                ) -> Result<&mut Self>
+                where
+                    $($args: WasmTy,)*
+                    R: WasmRet;
+            )*
+        }
+
+        impl<T> NamedHostFunctions<T> for Linker<T> {
+            fn func_wrap_named<Params, Args>(
+                &mut self,
+                module: &str,
+                name: &str,
+                func: impl HostFunction<T, Params, Args>,
+            ) -> Result<&mut Self> {
+                let named = func.named(format!("{module}::{name}").into());
+                self.func_wrap(module, name, named)
+            }
+
+            $(
+                #[allow(non_snake_case)]
+                fn $method<$($args,)* R>(
+                    &mut self,
+                    module: &str,
+                    name: &str,
+                    func: impl for<'a> Fn(Caller<'a, T>, $($args),*) -> Box<dyn Future<Output = R> + Send + 'a>
+                        + Send
+                        + Sync
+                        + 'static,
+                ) -> Result<&mut Self>
+                where
+                    $($args: WasmTy,)*
+                    R: WasmRet,
+                {
+                    let named: Arc<str> = format!("{module}::{name}").into();
+                    self.$wrap(module, name, move |caller, $($args),*| {
+                        entered(&named);
+                        func(caller, $($args),*)
+                    })
+                }
+            )*
+        }
+    };
+}
+
+named_async_funcs! {
+    (func_wrap1_async_named, func_wrap1_async, A1)
+    (func_wrap2_async_named, func_wrap2_async, A1 A2)
+    (func_wrap3_async_named, func_wrap3_async, A1 A2 A3)
+    (func_wrap4_async_named, func_wrap4_async, A1 A2 A3 A4)
+    (func_wrap5_async_named, func_wrap5_async, A1 A2 A3 A4 A5)
+    (func_wrap6_async_named, func_wrap6_async, A1 A2 A3 A4 A5 A6)
+    (func_wrap7_async_named, func_wrap7_async, A1 A2 A3 A4 A5 A6 A7)
+    (func_wrap8_async_named, func_wrap8_async, A1 A2 A3 A4 A5 A6 A7 A8)
+    (func_wrap9_async_named, func_wrap9_async, A1 A2 A3 A4 A5 A6 A7 A8 A9)
+    (func_wrap10_async_named, func_wrap10_async, A1 A2 A3 A4 A5 A6 A7 A8 A9 A10)
+    (func_wrap11_async_named, func_wrap11_async, A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11)
+}
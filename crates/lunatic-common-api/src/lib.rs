@@ -1,7 +1,11 @@
+pub mod host_call;
+
 use anyhow::{anyhow, Context, Result};
 use std::{fmt::Display, future::Future, io::Write, pin::Pin};
 use wasmtime::{Caller, Memory, Val};
 
+pub use host_call::NamedHostFunctions;
+
 const ALLOCATOR_FUNCTION_NAME: &str = "lunatic_alloc";
 const FREEING_FUNCTION_NAME: &str = "lunatic_free";
 
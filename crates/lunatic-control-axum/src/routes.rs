@@ -33,7 +33,9 @@ pub async fn register(
         .map_err(|e| ApiError::log_internal("Error generating random token for registration", e))?;
     let authentication_token = base64_url::encode(&authentication_token);
 
-    control.register(&reg, &cert_pem, &authentication_token);
+    control
+        .register(&reg, &cert_pem, &authentication_token)
+        .map_err(|e| ApiError::custom("registration_rejected", e.to_string()))?;
 
     ok(Registration {
         node_name: reg.node_name,
@@ -48,6 +50,8 @@ pub async fn register(
             get_module: format!("http://{host}/module/{{id}}"),
             add_module: format!("http://{host}/module"),
             get_nodes: format!("http://{host}/nodes"),
+            node_stats: format!("http://{host}/stats"),
+            deregister: format!("http://{host}/deregister"),
         },
     })
 }
@@ -64,6 +68,18 @@ pub async fn node_stopped(
     ok(())
 }
 
+pub async fn deregister(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+) -> ApiResponse<()> {
+    log::info!("Node {} deregistered", node_auth.node_name);
+
+    let control = control.as_ref();
+    control.deregister(node_auth.registration_id as u64);
+
+    ok(())
+}
+
 pub async fn node_started(
     node_auth: NodeAuth,
     control: Extension<Arc<ControlServer>>,
@@ -83,17 +99,40 @@ pub async fn node_started(
     })
 }
 
+pub async fn node_stats(
+    node_auth: NodeAuth,
+    control: Extension<Arc<ControlServer>>,
+    Json(data): Json<NodeStats>,
+) -> ApiResponse<()> {
+    let control = control.as_ref();
+    control.update_node_stats(node_auth.registration_id as u64, data);
+
+    ok(())
+}
+
 pub async fn list_nodes(
     _node_auth: NodeAuth,
     control: Extension<Arc<ControlServer>>,
 ) -> ApiResponse<NodesList> {
-    let control = control.as_ref();
+    ok(NodesList {
+        nodes: running_nodes(control.as_ref()),
+    })
+}
+
+// Read-only listing for `lunatic ps`, doesn't require a registration.
+pub async fn cluster(control: Extension<Arc<ControlServer>>) -> ApiResponse<NodesList> {
+    ok(NodesList {
+        nodes: running_nodes(control.as_ref()),
+    })
+}
+
+fn running_nodes(control: &ControlServer) -> Vec<NodeInfo> {
     let nds: Vec<_> = control
         .nodes
         .iter()
         .filter(|n| n.status < 2 && !n.node_address.is_empty())
         .collect();
-    let nodes: Vec<_> = control
+    control
         .registrations
         .iter()
         .filter_map(|r| {
@@ -103,11 +142,12 @@ pub async fn list_nodes(
                     id: *n.key(),
                     address: n.node_address.parse().unwrap(),
                     name: r.node_name.to_string(),
+                    attributes: serde_json::from_value(n.attributes.clone()).unwrap_or_default(),
+                    processes: n.processes.clone(),
+                    process_names: n.process_names.clone(),
                 })
         })
-        .collect();
-
-    ok(NodesList { nodes })
+        .collect()
 }
 
 pub async fn add_module(
@@ -143,8 +183,11 @@ pub fn init_routes() -> Router {
     Router::new()
         .route("/", post(register))
         .route("/stopped", post(node_stopped))
+        .route("/deregister", post(deregister))
         .route("/started", post(node_started))
+        .route("/stats", post(node_stats))
         .route("/nodes", get(list_nodes))
+        .route("/cluster", get(cluster))
         .route("/module", post(add_module))
         .route("/module/:id", get(get_module))
         .layer(DefaultBodyLimit::disable())
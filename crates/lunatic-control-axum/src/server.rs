@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     net::{SocketAddr, TcpListener},
     sync::{
         atomic::{self, AtomicU64},
@@ -6,11 +7,11 @@ use std::{
     },
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{Extension, Router};
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use lunatic_control::api::{NodeStart, Register};
+use lunatic_control::api::{NodeStart, NodeStats, Register};
 use rcgen::Certificate;
 use uuid::Uuid;
 
@@ -31,6 +32,8 @@ pub struct ControlServer {
 pub struct Registered {
     pub node_name: Uuid,
     pub csr_pem: String,
+    /// DER encoded public key of the first CSR registered with this node name
+    pub public_key: Vec<u8>,
     pub cert_pem: String,
     pub authentication_token: String,
 }
@@ -42,6 +45,8 @@ pub struct NodeDetails {
     pub stopped_at: Option<DateTime<Utc>>,
     pub node_address: String,
     pub attributes: serde_json::Value,
+    pub processes: HashMap<u64, usize>,
+    pub process_names: HashMap<u64, Vec<String>>,
 }
 
 impl ControlServer {
@@ -58,21 +63,54 @@ impl ControlServer {
         }
     }
 
-    pub fn register(&self, reg: &Register, cert_pem: &str, authentication_token: &str) {
-        let id = self
-            .next_registration_id
-            .fetch_add(1, atomic::Ordering::Relaxed);
+    /// Registers a node, or renews the registration of a returning node.
+    ///
+    /// A returning node must sign its CSR with the same key it used the first time, otherwise
+    /// anyone knowing its name could take over its registration.
+    pub fn register(
+        &self,
+        reg: &Register,
+        cert_pem: &str,
+        authentication_token: &str,
+    ) -> Result<u64> {
+        let public_key = lunatic_control::csr::public_key(&reg.csr_pem)
+            .ok_or_else(|| anyhow!("Invalid CSR for node name {}", reg.node_name))?;
+        let existing = self
+            .registrations
+            .iter()
+            .find(|r| r.node_name == reg.node_name)
+            .map(|r| (*r.key(), r.public_key == public_key));
+        let id = match existing {
+            Some((id, true)) => id,
+            Some((_, false)) => {
+                return Err(anyhow!(
+                    "Node name {} is registered with a different key",
+                    reg.node_name
+                ))
+            }
+            None => self
+                .next_registration_id
+                .fetch_add(1, atomic::Ordering::Relaxed),
+        };
         let registered = Registered {
             node_name: reg.node_name,
             csr_pem: reg.csr_pem.clone(),
+            public_key,
             cert_pem: cert_pem.to_owned(),
             authentication_token: authentication_token.to_owned(),
         };
         self.registrations.insert(id, registered);
+        Ok(id)
     }
 
     pub fn start_node(&self, registration_id: u64, data: NodeStart) -> (u64, String) {
-        let id = self.next_node_id.fetch_add(1, atomic::Ordering::Relaxed);
+        // Restarted nodes get their previous node id back
+        let id = self
+            .nodes
+            .iter()
+            .find(|n| n.registration_id == registration_id)
+            .map(|n| *n.key())
+            .unwrap_or_else(|| self.next_node_id.fetch_add(1, atomic::Ordering::Relaxed));
         let details = NodeDetails {
             registration_id,
             status: 0,
@@ -80,15 +118,34 @@ impl ControlServer {
             stopped_at: None,
             node_address: data.node_address.to_string(),
             attributes: serde_json::json!(data.attributes),
+            processes: HashMap::new(),
+            process_names: HashMap::new(),
         };
         self.nodes.insert(id, details);
         (id, data.node_address.to_string())
     }
 
     pub fn stop_node(&self, reg_id: u64) {
-        if let Some(mut node) = self.nodes.get_mut(&reg_id) {
-            node.status = 2;
-            node.stopped_at = Some(Utc::now());
+        for mut node in self.nodes.iter_mut() {
+            if node.registration_id == reg_id {
+                node.status = 2;
+                node.stopped_at = Some(Utc::now());
+            }
+        }
+    }
+
+    /// Removes a registration and its node.
+    pub fn deregister(&self, reg_id: u64) {
+        self.registrations.remove(&reg_id);
+        self.nodes.retain(|_, node| node.registration_id != reg_id);
+    }
+
+    pub fn update_node_stats(&self, reg_id: u64, stats: NodeStats) {
+        for mut node in self.nodes.iter_mut() {
+            if node.registration_id == reg_id {
+                node.processes = stats.processes.clone();
+                node.process_names = stats.process_names.clone();
+            }
         }
     }
 
@@ -125,3 +182,245 @@ pub async fn control_server_from_tcp(listener: TcpListener) -> Result<()> {
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use lunatic_distributed::distributed::server::gen_node_cert;
+
+    use super::*;
+
+    fn control_server() -> ControlServer {
+        let ca_cert_str = lunatic_distributed::distributed::server::test_root_cert();
+        let ca_cert = lunatic_distributed::control::cert::test_root_cert().unwrap();
+        let (ctrl_cert, ctrl_pk) =
+            lunatic_distributed::control::cert::default_server_certificates(&ca_cert).unwrap();
+        let quic_client =
+            lunatic_distributed::quic::new_quic_client(&ca_cert_str, &ctrl_cert, &ctrl_pk).unwrap();
+        ControlServer::new(ca_cert, quic_client)
+    }
+
+    fn register(control: &ControlServer, node_name: Uuid, cert: &Certificate) -> Result<u64> {
+        let reg = Register {
+            node_name,
+            csr_pem: cert.serialize_request_pem().unwrap(),
+        };
+        control.register(&reg, "cert", "token")
+    }
+
+    #[tokio::test]
+    async fn returning_node_keeps_registration() {
+        let control = control_server();
+        let node_name = Uuid::new_v4();
+        let cert = gen_node_cert(&node_name.to_string()).unwrap();
+
+        let id = register(&control, node_name, &cert).unwrap();
+        assert_eq!(register(&control, node_name, &cert).unwrap(), id);
+        let other = Uuid::new_v4();
+        let other_cert = gen_node_cert(&other.to_string()).unwrap();
+        assert_ne!(register(&control, other, &other_cert).unwrap(), id);
+        assert_eq!(control.registrations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn node_name_with_different_key_is_rejected() {
+        let control = control_server();
+        let node_name = Uuid::new_v4();
+        let cert = gen_node_cert(&node_name.to_string()).unwrap();
+        let id = register(&control, node_name, &cert).unwrap();
+
+        let impostor = gen_node_cert(&node_name.to_string()).unwrap();
+        assert!(register(&control, node_name, &impostor).is_err());
+        // The original registration is left untouched
+        assert_eq!(
+            control.registrations.get(&id).unwrap().public_key,
+            cert.get_key_pair().public_key_der()
+        );
+        assert!(register(&control, node_name, &cert).is_ok());
+    }
+
+    #[tokio::test]
+    async fn deregistered_node_is_removed() {
+        let control = control_server();
+        let node_name = Uuid::new_v4();
+        let cert = gen_node_cert(&node_name.to_string()).unwrap();
+        let id = register(&control, node_name, &cert).unwrap();
+        let other = Uuid::new_v4();
+        let other_id =
+            register(&control, other, &gen_node_cert(&other.to_string()).unwrap()).unwrap();
+        for reg_id in [id, other_id] {
+            let start = NodeStart {
+                node_address: ([127, 0, 0, 1], 0).into(),
+                attributes: HashMap::new(),
+            };
+            control.start_node(reg_id, start);
+        }
+
+        control.deregister(id);
+        assert!(control.registrations.get(&id).is_none());
+        assert!(control.nodes.iter().all(|n| n.registration_id == other_id));
+        assert_eq!(control.nodes.len(), 1);
+        // The name can be registered again, even with a different key
+        let new_cert = gen_node_cert(&node_name.to_string()).unwrap();
+        assert!(register(&control, node_name, &new_cert).is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_deregisters_over_http() {
+        use lunatic_distributed::control::Client;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: reqwest::Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(control_server_from_tcp(listener));
+
+        let http_client = reqwest::Client::new();
+        let mut registrations = Vec::new();
+        for _ in 0..2 {
+            let node_name = Uuid::new_v4();
+            let cert = gen_node_cert(&node_name.to_string()).unwrap();
+            let csr_pem = cert.serialize_request_pem().unwrap();
+            let reg = Client::register(&http_client, url.clone(), node_name, csr_pem)
+                .await
+                .unwrap();
+            registrations.push(reg);
+        }
+        let client = Client::new(
+            http_client.clone(),
+            registrations[0].clone(),
+            ([127, 0, 0, 1], 1).into(),
+            HashMap::new(),
+        )
+        .await
+        .unwrap();
+        let names = |nodes: Vec<lunatic_control::NodeInfo>| {
+            nodes.into_iter().map(|node| node.name).collect::<Vec<_>>()
+        };
+        let node_name = registrations[0].node_name.to_string();
+
+        let nodes = Client::list_nodes(&http_client, &registrations[1])
+            .await
+            .unwrap();
+        assert_eq!(names(nodes), [node_name]);
+        client.deregister().await.unwrap();
+        let nodes = Client::list_nodes(&http_client, &registrations[1])
+            .await
+            .unwrap();
+        assert!(names(nodes).is_empty());
+        // The registration is gone too
+        assert!(Client::list_nodes(&http_client, &registrations[0])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn client_without_node_is_not_listed() {
+        use lunatic_distributed::control::Client;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: reqwest::Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(control_server_from_tcp(listener));
+
+        let http_client = reqwest::Client::new();
+        let node_name = Uuid::new_v4();
+        let cert = gen_node_cert(&node_name.to_string()).unwrap();
+        let reg = Client::register(
+            &http_client,
+            url.clone(),
+            node_name,
+            cert.serialize_request_pem().unwrap(),
+        )
+        .await
+        .unwrap();
+        let client = Client::without_node(http_client.clone(), reg.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(client.node_id(), 0);
+        assert!(client.node_ids().is_empty());
+        assert!(Client::cluster(&http_client, url).await.unwrap().is_empty());
+        client.deregister().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stats_only_update_the_reporting_node() {
+        let control = control_server();
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let node_name = Uuid::new_v4();
+            let cert = gen_node_cert(&node_name.to_string()).unwrap();
+            let reg_id = register(&control, node_name, &cert).unwrap();
+            let start = NodeStart {
+                node_address: ([127, 0, 0, 1], 0).into(),
+                attributes: HashMap::new(),
+            };
+            ids.push((reg_id, control.start_node(reg_id, start).0));
+        }
+
+        let stats = NodeStats {
+            processes: HashMap::from([(1, 3)]),
+            process_names: HashMap::from([(1, vec!["worker".to_string()])]),
+        };
+        control.update_node_stats(ids[0].0, stats);
+        let reported = control.nodes.get(&ids[0].1).unwrap();
+        assert_eq!(reported.processes, HashMap::from([(1, 3)]));
+        assert_eq!(reported.process_names[&1], ["worker"]);
+        let other = control.nodes.get(&ids[1].1).unwrap();
+        assert!(other.processes.is_empty());
+        assert!(other.process_names.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reported_stats_are_listed_without_registration() {
+        use lunatic_distributed::control::Client;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: reqwest::Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(control_server_from_tcp(listener));
+
+        let http_client = reqwest::Client::new();
+        let node_name = Uuid::new_v4();
+        let cert = gen_node_cert(&node_name.to_string()).unwrap();
+        let csr_pem = cert.serialize_request_pem().unwrap();
+        let reg = Client::register(&http_client, url.clone(), node_name, csr_pem)
+            .await
+            .unwrap();
+        let attributes = HashMap::from([("region".to_string(), "eu".to_string())]);
+        let client = Client::new(
+            http_client.clone(),
+            reg,
+            ([127, 0, 0, 1], 1).into(),
+            attributes.clone(),
+        )
+        .await
+        .unwrap();
+        let stats = NodeStats {
+            processes: HashMap::from([(1, 2), (2, 1)]),
+            process_names: HashMap::from([(1, vec!["worker".to_string()])]),
+        };
+        client.report_stats(stats).await.unwrap();
+
+        // The cluster is listed without a registration
+        let nodes = Client::cluster(&http_client, url).await.unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, node_name.to_string());
+        assert_eq!(nodes[0].attributes, attributes);
+        assert_eq!(nodes[0].processes, HashMap::from([(1, 2), (2, 1)]));
+        assert_eq!(nodes[0].process_names[&1], ["worker"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_csr_is_rejected() {
+        let control = control_server();
+        let reg = Register {
+            node_name: Uuid::new_v4(),
+            csr_pem: "invalid".into(),
+        };
+        assert!(control.register(&reg, "cert", "token").is_err());
+        assert!(control.registrations.is_empty());
+    }
+}
@@ -9,7 +9,10 @@ use api::RequestBodyLimit;
 use lunatic::AbstractProcess;
 use submillisecond::{router, Application};
 
-use crate::routes::{add_module, get_module, list_nodes, node_started, node_stopped, register};
+use crate::routes::{
+    add_module, cluster, deregister, get_module, list_nodes, node_started, node_stopped,
+    register,
+};
 use crate::server::ControlServer;
 
 fn main() -> anyhow::Result<()> {
@@ -29,8 +32,10 @@ fn main() -> anyhow::Result<()> {
 
         POST "/" => register
         POST "/stopped" => node_stopped
+        POST "/deregister" => deregister
         POST "/started" => node_started
         GET "/nodes" => list_nodes
+        GET "/cluster" => cluster
         POST "/module" => add_module
         GET "/module/:id" => get_module
     })
@@ -1,3 +1,4 @@
+use lunatic::ap::ProcessRef;
 use lunatic_control::{
     api::{
         ControlUrls, ModuleBytes, ModuleId, NodeStart, NodeStarted, NodesList, Register,
@@ -12,7 +13,7 @@ use crate::{
         ok, ApiError, ApiResponse, ControlServerExtractor, HostExtractor, JsonExtractor, NodeAuth,
         PathExtractor,
     },
-    server::{ControlServerMessages, ControlServerRequests},
+    server::{ControlServer, ControlServerMessages, ControlServerRequests},
 };
 
 pub fn register(
@@ -30,12 +31,13 @@ pub fn register(
     })?;
     let authentication_token = base64_url::encode(&authentication_token);
 
-    ControlServerMessages::register(
+    ControlServerRequests::register(
         &control,
         reg.clone(),
         cert_pem.clone(),
         authentication_token.clone(),
-    );
+    )
+    .map_err(|err| ApiError::custom("registration_rejected", err))?;
 
     ok(Registration {
         node_name: reg.node_name,
@@ -50,6 +52,9 @@ pub fn register(
             get_module: format!("http://{host}/module/{{id}}"),
             add_module: format!("http://{host}/module"),
             get_nodes: format!("http://{host}/nodes"),
+            // Nodes don't report process counts to this server
+            node_stats: String::new(),
+            deregister: format!("http://{host}/deregister"),
         },
     })
 }
@@ -65,6 +70,17 @@ pub fn node_stopped(
     ok(())
 }
 
+pub fn deregister(
+    node_auth: NodeAuth,
+    ControlServerExtractor(control): ControlServerExtractor,
+) -> ApiResponse<()> {
+    info!("Node {} deregistered", node_auth.node_name);
+
+    control.deregister(node_auth.registration_id as u64);
+
+    ok(())
+}
+
 pub fn node_started(
     node_auth: NodeAuth,
     ControlServerExtractor(control): ControlServerExtractor,
@@ -87,12 +103,25 @@ pub fn list_nodes(
     _node_auth: NodeAuth,
     ControlServerExtractor(control): ControlServerExtractor,
 ) -> ApiResponse<NodesList> {
+    ok(NodesList {
+        nodes: running_nodes(&control),
+    })
+}
+
+// Read-only listing for `lunatic ps`, doesn't require a registration.
+pub fn cluster(ControlServerExtractor(control): ControlServerExtractor) -> ApiResponse<NodesList> {
+    ok(NodesList {
+        nodes: running_nodes(&control),
+    })
+}
+
+fn running_nodes(control: &ProcessRef<ControlServer>) -> Vec<NodeInfo> {
     let all_nodes = control.get_nodes();
     let nds: Vec<_> = all_nodes
         .into_values()
         .filter(|n| n.status < 2 && !n.node_address.is_empty())
         .collect();
-    let nodes: Vec<_> = control
+    control
         .get_registrations()
         .into_iter()
         .filter_map(|(k, r)| {
@@ -102,11 +131,12 @@ pub fn list_nodes(
                     id: k,
                     address: n.node_address.parse().unwrap(),
                     name: r.node_name.to_string(),
+                    attributes: Default::default(),
+                    processes: Default::default(),
+                    process_names: Default::default(),
                 })
         })
-        .collect();
-
-    ok(NodesList { nodes })
+        .collect()
 }
 
 pub fn add_module(
@@ -87,10 +87,19 @@ impl ControlServer {
         })
     }
 
-    #[handle_message]
-    pub fn register(&mut self, reg: Register, cert_pem: String, auth_token: String) {
-        let id = self.next_registration_id;
-        self.next_registration_id += 1;
+    #[handle_request]
+    pub fn register(
+        &mut self,
+        reg: Register,
+        cert_pem: String,
+        auth_token: String,
+    ) -> Result<u64, String> {
+        let existing = returning_registration(&self.registrations, &reg)?;
+        let id = existing.unwrap_or_else(|| {
+            let id = self.next_registration_id;
+            self.next_registration_id += 1;
+            id
+        });
         let registered = Registered {
             node_name: reg.node_name,
             csr_pem: reg.csr_pem,
@@ -99,12 +108,22 @@ impl ControlServer {
         };
         self.store.add_registration(id, &registered);
         self.registrations.insert(id, registered);
+        Ok(id)
     }
 
     #[handle_request]
     pub fn start_node(&mut self, registration_id: u64, data: NodeStart) -> (u64, String) {
-        let id = self.next_node_id;
-        self.next_node_id += 1;
+        // Restarted nodes get their previous node id back
+        let existing = self
+            .nodes
+            .iter()
+            .find(|(_, n)| n.registration_id == registration_id)
+            .map(|(id, _)| *id);
+        let id = existing.unwrap_or_else(|| {
+            let id = self.next_node_id;
+            self.next_node_id += 1;
+            id
+        });
         let details = NodeDetails {
             registration_id,
             status: 0,
@@ -120,13 +139,23 @@ impl ControlServer {
 
     #[handle_message]
     pub fn stop_node(&mut self, reg_id: u64) {
-        if let Some(mut node) = self.nodes.get_mut(&reg_id) {
-            node.status = 2;
-            node.stopped_at = Some(Utc::now());
-            self.store.add_node(reg_id, node);
+        for (id, node) in self.nodes.iter_mut() {
+            if node.registration_id == reg_id {
+                node.status = 2;
+                node.stopped_at = Some(Utc::now());
+                self.store.add_node(*id, node);
+            }
         }
     }
 
+    #[handle_message]
+    pub fn deregister(&mut self, reg_id: u64) {
+        self.registrations.remove(&reg_id);
+        self.store.remove_registration(reg_id);
+        self.nodes.retain(|_, node| node.registration_id != reg_id);
+        self.store.remove_nodes(reg_id);
+    }
+
     #[handle_request]
     pub fn add_module(&mut self, bytes: Vec<u8>) -> u64 {
         let id = self.next_module_id;
@@ -162,6 +191,31 @@ impl ControlServer {
     }
 }
 
+// Returns the registration of a returning node. A returning node must sign its CSR with the
+// same key it used the first time, otherwise anyone knowing its name could take over its
+// registration. The stored CSR is only ever replaced by one with the same key, so it holds the
+// key of the first registration.
+fn returning_registration(
+    registrations: &HashMap<u64, Registered>,
+    reg: &Register,
+) -> Result<Option<u64>, String> {
+    let public_key = lunatic_control::csr::public_key(&reg.csr_pem)
+        .ok_or_else(|| format!("Invalid CSR for node name {}", reg.node_name))?;
+    match registrations
+        .iter()
+        .find(|(_, r)| r.node_name == reg.node_name)
+    {
+        Some((id, r)) if lunatic_control::csr::public_key(&r.csr_pem) == Some(public_key) => {
+            Ok(Some(*id))
+        }
+        Some(_) => Err(format!(
+            "Node name {} is registered with a different key",
+            reg.node_name
+        )),
+        None => Ok(None),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BincodeJsonValue(pub serde_json::Value);
 
@@ -230,3 +284,70 @@ impl<'de> Deserialize<'de> for BincodeJsonValue {
         deserializer.deserialize_bytes(BincodeJsonValueVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two CSRs signed with the same key, and one signed with a different key
+    const CSR_KEY_1: &str = "-----BEGIN CERTIFICATE REQUEST-----
+MIHIMHECAQAwDzENMAsGA1UEAwwEbm9kZTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABAjlFd9KiSZCXg5dRlFwCVNjAuNtOwtQFCjPR3ZUo8Ijb1+BYxirM3OUILBM
+YGtIv5Psy1KjE9G/WL0BxoaqbbugADAKBggqhkjOPQQDAgNHADBEAiAgen8vwTcg
+9/kyPWmP2svHH224FGDIYUbPmFPYWNGQ/gIgb6RFHYU7/J3UwC1Y+SuF/fDtWTFX
+7axSPSdl+G6AqGI=
+-----END CERTIFICATE REQUEST-----";
+    const CSR_KEY_1_RENEWED: &str = "-----BEGIN CERTIFICATE REQUEST-----
+MIHKMHECAQAwDzENMAsGA1UEAwwEbm9kZTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABAjlFd9KiSZCXg5dRlFwCVNjAuNtOwtQFCjPR3ZUo8Ijb1+BYxirM3OUILBM
+YGtIv5Psy1KjE9G/WL0BxoaqbbugADAKBggqhkjOPQQDAgNJADBGAiEA+jrGPSG/
+yk5nXwHGtVH9kR1sx7DTAOJ7SxZODC/5i/YCIQCprziuQD737WfseT+41LjYUghz
+0xrjP5NmCiBHf/whag==
+-----END CERTIFICATE REQUEST-----";
+    const CSR_KEY_2: &str = "-----BEGIN CERTIFICATE REQUEST-----
+MIHIMHECAQAwDzENMAsGA1UEAwwEbm9kZTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABABTk8n4qRVx/TGwGhBfGLteiiuJAfrbhvgSDMOQ+8SJuEw6kyHm/yimgwwf
+N0n7kiasinFVssaCsotEIGyJKw+gADAKBggqhkjOPQQDAgNHADBEAiA4jUfG/rfy
+ZrEqjpIB4uHbhVAMq0yztnlVz02xPuBzmgIgKtTTrmdAk7ecL0hR0fP5GeBj6GXs
+GwtCpMPoYdsb3BU=
+-----END CERTIFICATE REQUEST-----";
+
+    fn registrations(node_name: Uuid) -> HashMap<u64, Registered> {
+        let registered = Registered {
+            node_name,
+            csr_pem: CSR_KEY_1.to_string(),
+            cert_pem: String::new(),
+            auth_token: String::new(),
+        };
+        HashMap::from([(7, registered)])
+    }
+
+    fn register(node_name: Uuid, csr_pem: &str) -> Register {
+        Register {
+            node_name,
+            csr_pem: csr_pem.to_string(),
+        }
+    }
+
+    #[test]
+    fn returning_node_keeps_registration() {
+        let node_name = Uuid::new_v4();
+        let registrations = registrations(node_name);
+        assert_eq!(
+            returning_registration(&registrations, &register(node_name, CSR_KEY_1_RENEWED)),
+            Ok(Some(7))
+        );
+        assert_eq!(
+            returning_registration(&registrations, &register(Uuid::new_v4(), CSR_KEY_2)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn node_name_with_different_key_is_rejected() {
+        let node_name = Uuid::new_v4();
+        let registrations = registrations(node_name);
+        assert!(returning_registration(&registrations, &register(node_name, CSR_KEY_2)).is_err());
+        assert!(returning_registration(&registrations, &register(node_name, "invalid")).is_err());
+    }
+}
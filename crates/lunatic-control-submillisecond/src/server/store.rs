@@ -165,6 +165,21 @@ impl ControlServerStore {
             .execute();
     }
 
+    pub fn remove_registration(&self, id: u64) {
+        self.client
+            .prepare_query("DELETE FROM registrations WHERE id = ?")
+            .bind(id as i64)
+            .execute();
+    }
+
+    /// Removes all nodes of the registration.
+    pub fn remove_nodes(&self, registration_id: u64) {
+        self.client
+            .prepare_query("DELETE FROM nodes WHERE registration_id = ?")
+            .bind(registration_id as i64)
+            .execute();
+    }
+
     pub fn add_node(&self, id: u64, node: &NodeDetails) {
         self.client
             .prepare_query(
@@ -28,6 +28,10 @@ pub struct ControlUrls {
     pub get_module: String,
     pub add_module: String,
     pub get_nodes: String,
+    #[serde(default)]
+    pub node_stats: String,
+    #[serde(default)]
+    pub deregister: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -42,6 +46,13 @@ pub struct NodeStarted {
     pub node_id: i64,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub processes: HashMap<u64, usize>,
+    #[serde(default)]
+    pub process_names: HashMap<u64, Vec<String>>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NodesList {
     pub nodes: Vec<NodeInfo>,
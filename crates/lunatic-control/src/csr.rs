@@ -0,0 +1,111 @@
+//! Minimal parsing of PEM encoded certificate signing requests.
+//!
+//! Control servers identify returning nodes by the public key in their CSR, so this only needs
+//! to walk the DER structure down to the `subjectPKInfo` field:
+//!
+//! ```text
+//! CertificationRequest ::= SEQUENCE {
+//!     certificationRequestInfo SEQUENCE {
+//!         version       INTEGER,
+//!         subject       Name,
+//!         subjectPKInfo SubjectPublicKeyInfo,
+//!         ...
+//!     },
+//!     ...
+//! }
+//! ```
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+
+/// Returns the DER encoded `SubjectPublicKeyInfo` of a PEM encoded CSR, or `None` if the CSR
+/// can't be parsed.
+pub fn public_key(csr_pem: &str) -> Option<Vec<u8>> {
+    let base64: String = csr_pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = STANDARD.decode(base64).ok()?;
+
+    let (request, _) = read_tlv(&der, SEQUENCE)?;
+    let (info, _) = read_tlv(request.content, SEQUENCE)?;
+    let (_version, rest) = read_tlv(info.content, INTEGER)?;
+    let (_subject, rest) = read_tlv(rest, SEQUENCE)?;
+    let (public_key, _) = read_tlv(rest, SEQUENCE)?;
+    Some(public_key.encoded.to_vec())
+}
+
+struct Tlv<'a> {
+    // The whole element, including tag and length
+    encoded: &'a [u8],
+    content: &'a [u8],
+}
+
+// Reads one DER element with the expected tag, returns it and the bytes following it.
+fn read_tlv(der: &[u8], tag: u8) -> Option<(Tlv<'_>, &[u8])> {
+    let (&actual_tag, rest) = der.split_first()?;
+    if actual_tag != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let len_bytes = (first & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > std::mem::size_of::<u32>() || rest.len() < len_bytes {
+            return None;
+        }
+        let (len, rest) = rest.split_at(len_bytes);
+        let len = len.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, rest)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header_len = der.len() - rest.len();
+    let (content, rest) = rest.split_at(len);
+    let tlv = Tlv {
+        encoded: &der[..header_len + len],
+        content,
+    };
+    Some((tlv, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csr(cert: &rcgen::Certificate) -> String {
+        cert.serialize_request_pem().unwrap()
+    }
+
+    #[test]
+    fn public_key_identifies_the_key_pair() {
+        let first =
+            rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["node".into()]))
+                .unwrap();
+        let second =
+            rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["node".into()]))
+                .unwrap();
+
+        let key = public_key(&csr(&first)).unwrap();
+        assert_eq!(key, first.get_key_pair().public_key_der());
+        // A new CSR signed with the same key has the same public key
+        assert_eq!(public_key(&csr(&first)), Some(key.clone()));
+        assert_ne!(public_key(&csr(&second)), Some(key));
+    }
+
+    #[test]
+    fn invalid_csr() {
+        assert_eq!(public_key(""), None);
+        assert_eq!(public_key("not base64"), None);
+        let pem =
+            csr(&rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![])).unwrap());
+        // Truncated DER
+        let truncated: String = pem.lines().take(3).collect::<Vec<_>>().join("\n");
+        assert_eq!(public_key(&truncated), None);
+    }
+}
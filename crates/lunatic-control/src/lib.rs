@@ -1,6 +1,7 @@
 pub mod api;
+pub mod csr;
 
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
@@ -9,4 +10,12 @@ pub struct NodeInfo {
     pub id: u64,
     pub address: SocketAddr,
     pub name: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Number of running processes per environment id, as last reported by the node
+    #[serde(default)]
+    pub processes: HashMap<u64, usize>,
+    /// Registry names of the running processes per environment id
+    #[serde(default)]
+    pub process_names: HashMap<u64, Vec<String>>,
 }
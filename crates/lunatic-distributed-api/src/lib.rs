@@ -1,13 +1,14 @@
 use std::{future::Future, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
-use lunatic_common_api::{get_memory, write_to_guest_vec, IntoTrap};
+use lunatic_common_api::{get_memory, write_to_guest_vec, IntoTrap, NamedHostFunctions};
 use lunatic_distributed::{
     distributed::message::{ClientError, Spawn, Val},
     DistributedCtx,
 };
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
+    config::ProcessConfig,
     env::Environment,
     message::{DataMessage, Message},
 };
@@ -23,34 +24,34 @@ where
     E: Environment + 'static,
     for<'a> &'a T: Send,
 {
-    linker.func_wrap("lunatic::distributed", "nodes_count", nodes_count)?;
-    linker.func_wrap("lunatic::distributed", "get_nodes", get_nodes)?;
-    linker.func_wrap("lunatic::distributed", "node_id", node_id)?;
-    linker.func_wrap("lunatic::distributed", "module_id", module_id)?;
-    linker.func_wrap8_async("lunatic::distributed", "spawn", spawn)?;
-    linker.func_wrap2_async("lunatic::distributed", "send", send)?;
-    linker.func_wrap4_async(
+    linker.func_wrap_named("lunatic::distributed", "nodes_count", nodes_count)?;
+    linker.func_wrap_named("lunatic::distributed", "get_nodes", get_nodes)?;
+    linker.func_wrap_named("lunatic::distributed", "node_id", node_id)?;
+    linker.func_wrap_named("lunatic::distributed", "module_id", module_id)?;
+    linker.func_wrap8_async_named("lunatic::distributed", "spawn", spawn)?;
+    linker.func_wrap2_async_named("lunatic::distributed", "send", send)?;
+    linker.func_wrap4_async_named(
         "lunatic::distributed",
         "send_receive_skip_search",
         send_receive_skip_search,
     )?;
-    linker.func_wrap5_async(
+    linker.func_wrap5_async_named(
         "lunatic::distributed",
         "exec_lookup_nodes",
         exec_lookup_nodes,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::distributed",
         "copy_lookup_nodes_results",
         copy_lookup_nodes_results,
     )?;
-    linker.func_wrap1_async("lunatic::distributed", "test_root_cert", test_root_cert)?;
-    linker.func_wrap5_async(
+    linker.func_wrap1_async_named("lunatic::distributed", "test_root_cert", test_root_cert)?;
+    linker.func_wrap5_async_named(
         "lunatic::distributed",
         "default_server_certificates",
         default_server_certificates,
     )?;
-    linker.func_wrap7_async("lunatic::distributed", "sign_node", sign_node)?;
+    linker.func_wrap7_async_named("lunatic::distributed", "sign_node", sign_node)?;
     Ok(())
 }
 
@@ -316,9 +317,10 @@ where
             Certificate::from_params(cert_params).or_trap("lunatic::distributed::sign_node")?;
 
         let Ok(cert_pem) = CertificateSigningRequest::from_pem(csr_pem)
-            .and_then(|sign_request| sign_request.serialize_pem_with_signer(&ca_cert)) else {
-                return Ok(0);
-            };
+            .and_then(|sign_request| sign_request.serialize_pem_with_signer(&ca_cert))
+        else {
+            return Ok(0);
+        };
 
         let data = bincode::serialize(&cert_pem).or_trap("lunatic::distributed::sign_node")?;
         let ptr = write_to_guest_vec(&mut caller, &memory, &data, len_ptr)
@@ -348,11 +350,13 @@ where
 // * 0      on success - The ID of the newly created process is written to `id_ptr`
 // * 1      If node does not exist
 // * 2      If module does not exist
+// * 3      If node is in maintenance mode
 // * 9027   If node connection error occurred
 //
 // Traps:
 // * If the function string is not a valid utf8 string.
 // * If the params array is in a wrong format.
+// * If the config can't be used on other nodes, e.g. it uses a virtual network.
 // * If any memory outside the guest heap space is referenced.
 #[allow(clippy::too_many_arguments)]
 fn spawn<T, E>(
@@ -417,6 +421,11 @@ where
                     .clone(),
             ),
         };
+        if !config.can_spawn_remotely() {
+            return Err(anyhow!(
+                "lunatic::distributed::spawn: Config can't be used on remote nodes"
+            ));
+        }
         let config: Vec<u8> =
             rmp_serde::to_vec(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
 
@@ -439,13 +448,7 @@ where
         {
             Ok(process_id) => (process_id, 0),
             Err(error) => {
-                let (code, message): (u32, String) = match error {
-                    ClientError::Unexpected(cause) => Err(anyhow!(cause)),
-                    ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
-                    ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
-                    ClientError::Connection(cause) => Ok((9027, cause)),
-                    _ => Err(anyhow!("unreachable")),
-                }?;
+                let (code, message) = spawn_error(error)?;
                 (
                     caller
                         .data_mut()
@@ -622,6 +625,18 @@ where
     })
 }
 
+// Maps a failed remote spawn to the error code and message returned to the guest
+fn spawn_error(error: ClientError) -> Result<(u32, String)> {
+    match error {
+        ClientError::Unexpected(cause) => Err(anyhow!(cause)),
+        ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
+        ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
+        ClientError::NodeInMaintenance => Ok((3, "Node is in maintenance mode.".to_string())),
+        ClientError::Connection(cause) => Ok((9027, cause)),
+        _ => Err(anyhow!("unreachable")),
+    }
+}
+
 // Returns the id of the node that the current process is running on
 fn node_id<T, E>(caller: Caller<T>) -> u64
 where
@@ -644,3 +659,19 @@ where
 {
     caller.data().module_id()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spawn_on_node_in_maintenance_returns_code_3() {
+        let (code, message) = spawn_error(ClientError::NodeInMaintenance).unwrap();
+        assert_eq!(code, 3);
+        assert_eq!(message, "Node is in maintenance mode.");
+
+        assert_eq!(spawn_error(ClientError::NodeNotFound).unwrap().0, 1);
+        assert_eq!(spawn_error(ClientError::ModuleNotFound).unwrap().0, 2);
+        assert!(spawn_error(ClientError::Unexpected("boom".to_string())).is_err());
+    }
+}
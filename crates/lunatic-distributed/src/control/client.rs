@@ -44,7 +44,16 @@ impl Client {
             },
         )
         .await?;
+        Self::connect(http_client, reg, node_id).await
+    }
+
+    /// Creates a client for a registration that doesn't run a node, like a deployment talking to
+    /// other nodes. It isn't listed as a node and has the node ID 0, which no node gets.
+    pub async fn without_node(http_client: HttpClient, reg: Registration) -> Result<Self> {
+        Self::connect(http_client, reg, 0).await
+    }
 
+    async fn connect(http_client: HttpClient, reg: Registration, node_id: u64) -> Result<Self> {
         let client = Client {
             inner: Arc::new(InnerClient {
                 reg,
@@ -221,8 +230,65 @@ impl Client {
         Ok(())
     }
 
+    /// Lists the running nodes, without starting a node for the registration.
+    pub async fn list_nodes(http_client: &HttpClient, reg: &Registration) -> Result<Vec<NodeInfo>> {
+        let resp: NodesList = http_client
+            .get(&reg.urls.nodes)
+            .bearer_auth(&reg.authentication_token)
+            .header(
+                "x-lunatic-node-name",
+                &reg.node_name.hyphenated().to_string(),
+            )
+            .send()
+            .await
+            .with_context(|| format!("Error sending HTTP GET request: {}.", &reg.urls.nodes))?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.nodes)
+    }
+
+    /// Lists the running nodes through the read-only endpoint of the control server, without
+    /// registering.
+    pub async fn cluster(http_client: &HttpClient, control_url: Url) -> Result<Vec<NodeInfo>> {
+        let url = control_url.join("cluster")?;
+        let resp: NodesList = http_client
+            .get(url.clone())
+            .send()
+            .await
+            .with_context(|| format!("Error sending HTTP GET request: {url}."))?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.nodes)
+    }
+
+    /// Reports the running processes per environment to the control server.
+    pub async fn report_stats(&self, stats: NodeStats) -> Result<()> {
+        // Control servers that don't track process counts don't return a URL
+        if self.inner.reg.urls.node_stats.is_empty() {
+            return Ok(());
+        }
+        self.post::<_, ()>(&self.inner.reg.urls.node_stats, stats)
+            .await?;
+        Ok(())
+    }
+
     pub async fn notify_node_stopped(&self) -> Result<()> {
-        self.post(&self.inner.reg.urls.node_stopped, ()).await?;
+        self.post::<_, ()>(&self.inner.reg.urls.node_stopped, ())
+            .await?;
+        Ok(())
+    }
+
+    /// Removes the registration and its node from the control server, for clients that only
+    /// register to talk to other nodes.
+    pub async fn deregister(&self) -> Result<()> {
+        // Control servers that can't remove registrations don't return a URL
+        if self.inner.reg.urls.deregister.is_empty() {
+            return self.notify_node_stopped().await;
+        }
+        self.post::<_, ()>(&self.inner.reg.urls.deregister, ())
+            .await?;
         Ok(())
     }
 
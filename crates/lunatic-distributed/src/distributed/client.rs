@@ -160,11 +160,11 @@ async fn forward_node_messages(client: Client, mut rx: UnboundedReceiver<SendReq
 
 async fn try_node_info_forever(node_id: u64, client: &Client) -> NodeInfo {
     loop {
-        let node_info = client.inner.control_client.node_info(node_id);
-        if node_info.is_none() {
-            client.inner.control_client.refresh_nodes().await.ok();
-        } else {
-            return node_info.unwrap();
+        match client.inner.control_client.node_info(node_id) {
+            Some(node_info) => return node_info,
+            None => {
+                client.inner.control_client.refresh_nodes().await.ok();
+            }
         }
     }
 }
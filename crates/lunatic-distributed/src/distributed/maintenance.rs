@@ -0,0 +1,133 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time::Instant};
+
+/// Maintenance mode of a node.
+///
+/// While active, the node rejects new spawns and holds back inbound messages until maintenance
+/// ends. Maintenance is bounded and ends on its own once the deadline passes, so a forgotten
+/// [`Maintenance::exit`] can't stall the cluster.
+#[derive(Clone)]
+pub struct Maintenance {
+    // Deadline of the current maintenance window, `None` if not in maintenance
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            deadline: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl Maintenance {
+    /// Enter maintenance mode for at most `max_duration`.
+    pub fn enter(&self, max_duration: Duration) {
+        log::info!("Entering maintenance mode for at most {max_duration:?}");
+        self.deadline
+            .send_replace(Some(Instant::now() + max_duration));
+    }
+
+    /// Leave maintenance mode, releasing all held back messages.
+    pub fn exit(&self) {
+        if self.deadline.send_replace(None).is_some() {
+            log::info!("Leaving maintenance mode");
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(*self.deadline.borrow(), Some(deadline) if deadline > Instant::now())
+    }
+
+    /// Waits until the node is out of maintenance mode.
+    pub async fn wait(&self) {
+        let mut receiver = self.deadline.subscribe();
+        loop {
+            let deadline = match *receiver.borrow_and_update() {
+                Some(deadline) if deadline > Instant::now() => deadline,
+                _ => return,
+            };
+            tokio::select! {
+                _ = receiver.changed() => {}
+                _ = tokio::time::sleep_until(deadline) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enter_and_exit() {
+        let maintenance = Maintenance::default();
+        assert!(!maintenance.is_active());
+        maintenance.enter(Duration::from_secs(60));
+        assert!(maintenance.is_active());
+        // Clones share the same state
+        assert!(maintenance.clone().is_active());
+        maintenance.exit();
+        assert!(!maintenance.is_active());
+    }
+
+    #[test]
+    fn expires_after_max_duration() {
+        let maintenance = Maintenance::default();
+        maintenance.enter(Duration::ZERO);
+        assert!(!maintenance.is_active());
+    }
+
+    #[tokio::test]
+    async fn wait_returns_once_the_deadline_passes() {
+        let maintenance = Maintenance::default();
+        // Not in maintenance
+        tokio::time::timeout(Duration::from_millis(10), maintenance.wait())
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        maintenance.enter(Duration::from_millis(50));
+        tokio::time::timeout(Duration::from_secs(5), maintenance.wait())
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(!maintenance.is_active());
+    }
+
+    #[tokio::test]
+    async fn wait_returns_on_exit() {
+        let maintenance = Maintenance::default();
+        maintenance.enter(Duration::from_secs(60));
+        let waiting = tokio::spawn({
+            let maintenance = maintenance.clone();
+            async move { maintenance.wait().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        maintenance.exit();
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_follows_an_extended_deadline() {
+        let maintenance = Maintenance::default();
+        let started = Instant::now();
+        maintenance.enter(Duration::from_millis(20));
+        let waiting = tokio::spawn({
+            let maintenance = maintenance.clone();
+            async move { maintenance.wait().await }
+        });
+        maintenance.enter(Duration::from_millis(100));
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -37,6 +37,7 @@ pub enum ClientError {
     NodeNotFound,
     ModuleNotFound,
     ProcessNotFound,
+    NodeInMaintenance,
 }
 
 impl Default for ClientError {
@@ -1,5 +1,7 @@
 pub mod client;
+pub mod maintenance;
 pub mod message;
 pub mod server;
 
 pub use client::Client;
+pub use maintenance::Maintenance;
@@ -1,6 +1,6 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::Path, sync::Arc};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 
 use lunatic_process::{
     env::{Environment, Environments},
@@ -10,10 +10,14 @@ use lunatic_process::{
     Signal,
 };
 use rcgen::*;
+use uuid::Uuid;
 use wasmtime::ResourceLimiter;
 
 use crate::{
-    distributed::message::{Request, Response},
+    distributed::{
+        message::{Request, Response},
+        Maintenance,
+    },
     quic::{self, SendStream},
     DistributedCtx, DistributedProcessState,
 };
@@ -25,6 +29,7 @@ pub struct ServerCtx<T, E: Environment> {
     pub modules: Modules<T>,
     pub distributed: DistributedProcessState,
     pub runtime: WasmtimeRuntime,
+    pub maintenance: Maintenance,
 }
 
 impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
@@ -34,6 +39,7 @@ impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
             modules: self.modules.clone(),
             distributed: self.distributed.clone(),
             runtime: self.runtime.clone(),
+            maintenance: self.maintenance.clone(),
         }
     }
 }
@@ -48,13 +54,81 @@ pub fn root_cert(ca_cert: &str) -> Result<String> {
 }
 
 pub fn gen_node_cert(node_name: &str) -> Result<Certificate> {
+    Certificate::from_params(node_cert_params(node_name))
+        .map_err(|_| anyhow!("Error while generating node certificate."))
+}
+
+/// Returns the node name and certificate persisted in `dir`, creating them on first use.
+///
+/// Reusing them lets the control server recognize a restarted node.
+pub fn load_or_gen_node_identity(dir: &Path) -> Result<(Uuid, Certificate)> {
+    let name_path = dir.join("name");
+    let key_path = dir.join("key.pem");
+    if name_path.exists() && key_path.exists() {
+        let node_name = Uuid::parse_str(std::fs::read_to_string(&name_path)?.trim())
+            .with_context(|| format!("Invalid node name in {}", name_path.display()))?;
+        let mut params = node_cert_params(&node_name.as_hyphenated().to_string());
+        params.key_pair = Some(
+            KeyPair::from_pem(&std::fs::read_to_string(&key_path)?)
+                .with_context(|| format!("Invalid node key in {}", key_path.display()))?,
+        );
+        let cert = Certificate::from_params(params)
+            .map_err(|_| anyhow!("Error while loading node certificate."))?;
+        return Ok((node_name, cert));
+    }
+
+    let node_name = Uuid::new_v4();
+    let cert = gen_node_cert(&node_name.as_hyphenated().to_string())?;
+    std::fs::create_dir_all(dir)?;
+    // The key is written first, a key without a name is regenerated on next start
+    write_atomically(
+        &key_path,
+        cert.serialize_private_key_pem().as_bytes(),
+        0o600,
+    )?;
+    write_atomically(
+        &name_path,
+        node_name.as_hyphenated().to_string().as_bytes(),
+        0o644,
+    )?;
+    Ok((node_name, cert))
+}
+
+// Writes the file next to `path` with the permissions `mode` and then moves it into place, so that
+// `path` is never partially written or readable with other permissions.
+fn write_atomically(path: &Path, contents: &[u8], #[allow(unused)] mode: u32) -> Result<()> {
+    use std::io::Write;
+
+    let tmp_path = path.with_extension("tmp");
+    // Left over from a crash while writing
+    match std::fs::remove_file(&tmp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    let mut file = options
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+fn node_cert_params(node_name: &str) -> CertificateParams {
     let mut params = CertificateParams::new(vec![node_name.to_string()]);
     params
         .distinguished_name
         .push(DnType::OrganizationName, "Lunatic Inc.");
     params.distinguished_name.push(DnType::CommonName, "Node");
-    Certificate::from_params(params)
-        .map_err(|_| anyhow!("Error while generating node certificate."))
+    params
 }
 
 pub async fn node_server<T, E>(
@@ -125,16 +199,21 @@ where
             process_id,
             tag,
             data,
-        } => match handle_process_message(ctx, environment_id, process_id, tag, data).await {
-            Ok(_) => {
-                let mut data = super::message::pack_response(msg_id, Response::Sent);
-                send.send(&mut data).await?;
-            }
-            Err(error) => {
-                let mut data = super::message::pack_response(msg_id, Response::Error(error));
-                send.send(&mut data).await?;
+        } => {
+            // Messages are held back, not rejected, during maintenance. Requests on a stream are
+            // handled in order, so this also preserves message ordering.
+            ctx.maintenance.wait().await;
+            match handle_process_message(ctx, environment_id, process_id, tag, data).await {
+                Ok(_) => {
+                    let mut data = super::message::pack_response(msg_id, Response::Sent);
+                    send.send(&mut data).await?;
+                }
+                Err(error) => {
+                    let mut data = super::message::pack_response(msg_id, Response::Error(error));
+                    send.send(&mut data).await?;
+                }
             }
-        },
+        }
     };
     Ok(())
 }
@@ -144,6 +223,10 @@ where
     T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + Sync + 'static,
     E: Environment + 'static,
 {
+    if ctx.maintenance.is_active() {
+        return Ok(Err(ClientError::NodeInMaintenance));
+    }
+
     let Spawn {
         environment_id,
         module_id,
@@ -221,3 +304,29 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_identity_is_persisted() {
+        let dir =
+            std::env::temp_dir().join(format!("lunatic-identity-test-{}", std::process::id()));
+        let (name, cert) = load_or_gen_node_identity(&dir).unwrap();
+        let (loaded_name, loaded_cert) = load_or_gen_node_identity(&dir).unwrap();
+        let key_metadata = std::fs::metadata(dir.join("key.pem"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded_name, name);
+        assert_eq!(
+            loaded_cert.serialize_private_key_pem(),
+            cert.serialize_private_key_pem()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(key_metadata.unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
@@ -8,6 +8,7 @@ use lunatic_process::{
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
     state::ProcessState,
 };
+use lunatic_seq_api::Sequences;
 use std::sync::Arc;
 
 pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
@@ -30,6 +31,7 @@ pub struct DistributedProcessState {
     node_id: u64,
     pub control: control::Client,
     pub node_client: distributed::Client,
+    sequences: Arc<Sequences>,
 }
 
 impl DistributedProcessState {
@@ -37,15 +39,22 @@ impl DistributedProcessState {
         node_id: u64,
         control_client: control::Client,
         node_client: distributed::Client,
+        sequences: Arc<Sequences>,
     ) -> Result<Self> {
         Ok(Self {
             node_id,
             control: control_client,
             node_client,
+            sequences,
         })
     }
 
     pub fn node_id(&self) -> u64 {
         self.node_id
     }
+
+    /// Node-level sequences, shared with processes spawned by remote nodes.
+    pub fn sequences(&self) -> &Arc<Sequences> {
+        &self.sequences
+    }
 }
@@ -2,10 +2,11 @@ use std::{
     convert::TryInto,
     future::Future,
     io::{Read, Write},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, Result};
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_networking_api::NetworkingCtx;
 use lunatic_process_api::ProcessCtx;
 use tokio::time::{timeout, Duration};
@@ -21,38 +22,50 @@ use lunatic_process::{
 pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
-    linker.func_wrap("lunatic::message", "create_data", create_data)?;
-    linker.func_wrap("lunatic::message", "write_data", write_data)?;
-    linker.func_wrap("lunatic::message", "read_data", read_data)?;
-    linker.func_wrap("lunatic::message", "seek_data", seek_data)?;
-    linker.func_wrap("lunatic::message", "get_tag", get_tag)?;
-    linker.func_wrap("lunatic::message", "get_process_id", get_process_id)?;
-    linker.func_wrap("lunatic::message", "data_size", data_size)?;
-    linker.func_wrap("lunatic::message", "push_module", push_module)?;
-    linker.func_wrap("lunatic::message", "take_module", take_module)?;
-    linker.func_wrap("lunatic::message", "push_tcp_stream", push_tcp_stream)?;
-    linker.func_wrap("lunatic::message", "take_tcp_stream", take_tcp_stream)?;
-    linker.func_wrap("lunatic::message", "push_tls_stream", push_tls_stream)?;
-    linker.func_wrap("lunatic::message", "take_tls_stream", take_tls_stream)?;
-    linker.func_wrap("lunatic::message", "send", send)?;
-    linker.func_wrap3_async(
+    linker.func_wrap_named("lunatic::message", "create_data", create_data)?;
+    linker.func_wrap_named("lunatic::message", "write_data", write_data)?;
+    linker.func_wrap_named("lunatic::message", "read_data", read_data)?;
+    linker.func_wrap_named("lunatic::message", "seek_data", seek_data)?;
+    linker.func_wrap_named("lunatic::message", "get_tag", get_tag)?;
+    linker.func_wrap_named("lunatic::message", "get_process_id", get_process_id)?;
+    linker.func_wrap_named(
+        "lunatic::message",
+        "link_died_reason_size",
+        link_died_reason_size,
+    )?;
+    linker.func_wrap_named("lunatic::message", "link_died_reason", link_died_reason)?;
+    linker.func_wrap_named("lunatic::message", "data_size", data_size)?;
+    linker.func_wrap_named("lunatic::message", "push_module", push_module)?;
+    linker.func_wrap_named("lunatic::message", "take_module", take_module)?;
+    linker.func_wrap_named("lunatic::message", "push_config", push_config)?;
+    linker.func_wrap_named("lunatic::message", "take_config", take_config)?;
+    linker.func_wrap_named("lunatic::message", "push_tcp_stream", push_tcp_stream)?;
+    linker.func_wrap_named("lunatic::message", "take_tcp_stream", take_tcp_stream)?;
+    linker.func_wrap_named("lunatic::message", "push_tls_stream", push_tls_stream)?;
+    linker.func_wrap_named("lunatic::message", "take_tls_stream", take_tls_stream)?;
+    linker.func_wrap_named("lunatic::message", "send", send)?;
+    linker.func_wrap3_async_named(
         "lunatic::message",
         "send_receive_skip_search",
         send_receive_skip_search,
     )?;
-    linker.func_wrap3_async("lunatic::message", "receive", receive)?;
-    linker.func_wrap("lunatic::message", "push_udp_socket", push_udp_socket)?;
-    linker.func_wrap("lunatic::message", "take_udp_socket", take_udp_socket)?;
+    linker.func_wrap3_async_named("lunatic::message", "receive", receive)?;
+    linker.func_wrap_named("lunatic::message", "push_udp_socket", push_udp_socket)?;
+    linker.func_wrap_named("lunatic::message", "take_udp_socket", take_udp_socket)?;
 
     Ok(())
 }
 
-// There are two kinds of messages a lunatic process can receive:
+// There are three kinds of messages a lunatic process can receive:
 //
 // 1. **Data message** that contains a buffer of raw `u8` data and host side resources.
 // 2. **LinkDied message**, representing a `LinkDied` signal that was turned into a message. The
 //    process can control if when a link dies the process should die too, or just receive a
-//    `LinkDied` message notifying it about the link's death.
+//    `LinkDied` message notifying it about the link's death. The message carries the failure
+//    reason of the linked process, like its panic message.
+// 3. **Shutdown message**, sent when the node stops to processes that asked for it with
+//    `lunatic::process::notify_shutdown`. The process has until the end of the node's shutdown
+//    grace period to finish, after that it's killed.
 //
 // All messages have a `tag` allowing for selective receives. If there are already messages in the
 // receiving queue, they will be first searched for a specific tag and the first match returned.
@@ -161,12 +174,13 @@ fn write_data<T: ProcessState + ProcessCtx<T>>(
         .or_trap("lunatic::message::write_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.write(buffer).or_trap("lunatic::message::write_data")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     // Put message back after writing to it.
     caller.data_mut().message_scratch_area().replace(message);
@@ -196,12 +210,13 @@ fn read_data<T: ProcessState + ProcessCtx<T>>(
         .or_trap("lunatic::message::read_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.read(buffer).or_trap("lunatic::message::read_data")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     // Put message back after reading from it.
     caller.data_mut().message_scratch_area().replace(message);
@@ -223,12 +238,13 @@ fn seek_data<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, index: u64)
         .or_trap("lunatic::message::seek_data")?;
     match &mut message {
         Message::Data(data) => data.seek(index as usize),
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(())
 }
@@ -259,6 +275,46 @@ fn get_process_id<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Res
     Ok(message.process_id().unwrap_or(0))
 }
 
+// Returns the size of the failure reason if the message is a link died signal, or 0 if any other
+// message type.
+//
+// Traps:
+// * If it's called without a message being inside of the scratch area.
+fn link_died_reason_size<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u32> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .or_trap("lunatic::message::link_died_reason_size")?;
+    Ok(message
+        .link_died_reason()
+        .map_or(0, |reason| reason.len() as u32))
+}
+
+// Writes the failure reason of a link died signal to the guest memory, e.g. the panic message of
+// the linked process. `lunatic::message::link_died_reason_size` can be used to get the size.
+//
+// Traps:
+// * If it's called without a link died message being inside of the scratch area.
+// * If any memory outside the guest heap space is referenced.
+fn link_died_reason<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    reason_ptr: u32,
+) -> Result<()> {
+    let reason = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .and_then(Message::link_died_reason)
+        .or_trap("lunatic::message::link_died_reason")?
+        .to_string();
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, reason_ptr as usize, reason.as_bytes())
+        .or_trap("lunatic::message::link_died_reason")?;
+    Ok(())
+}
+
 // Returns the size in bytes of the message buffer.
 //
 // Traps:
@@ -271,12 +327,13 @@ fn data_size<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u
         .or_trap("lunatic::message::data_size")?;
     let bytes = match message {
         Message::Data(data) => data.size(),
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
 
     Ok(bytes as u64)
@@ -305,12 +362,13 @@ fn push_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
         .or_trap("lunatic::message::push_module")?;
     let index = match message {
         Message::Data(data) => data.add_resource(module) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -334,16 +392,90 @@ fn take_module<T: ProcessState + ProcessCtx<T> + NetworkingCtx + 'static>(
         Message::Data(data) => data
             .take_module(index as usize)
             .or_trap("lunatic::message::take_module")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().module_resources_mut().add(module))
 }
 
+// Adds a configuration to the message that is currently in the scratch area and returns the new
+// location of it. This will remove the configuration from the current process' resources.
+//
+// This delegates the permissions of the configuration to the receiver, it can spawn processes with
+// them. Configurations can't be sent to processes on other nodes.
+//
+// Traps:
+// * If config ID doesn't exist
+// * If no data message is in the scratch area.
+fn push_config<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    config_id: u64,
+) -> Result<u64>
+where
+    T::Config: 'static,
+{
+    let config = caller
+        .data_mut()
+        .config_resources_mut()
+        .remove(config_id)
+        .or_trap("lunatic::message::push_config")?;
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::push_config")?;
+    let index = match message {
+        Message::Data(data) => data.add_resource(Arc::new(config)) as u64,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(_) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    Ok(index)
+}
+
+// Takes the configuration from the message that is currently in the scratch area by index, puts
+// it into the process' resources and returns the resource ID.
+//
+// Traps:
+// * If index ID doesn't exist or matches the wrong resource (not a configuration).
+// * If no data message is in the scratch area.
+fn take_config<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
+    mut caller: Caller<T>,
+    index: u64,
+) -> Result<u64>
+where
+    T::Config: 'static,
+{
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::take_config")?;
+    let config = match message {
+        Message::Data(data) => data
+            .take_config::<T::Config>(index as usize)
+            .or_trap("lunatic::message::take_config")?,
+        Message::LinkDied(..) => {
+            return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
+        }
+        Message::ProcessDied(_) => {
+            return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
+        }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
+    };
+    let config = Arc::try_unwrap(config).unwrap_or_else(|config| config.as_ref().clone());
+    Ok(caller.data_mut().config_resources_mut().add(config))
+}
+
 // Adds a tcp stream resource to the message that is currently in the scratch area and returns
 // the new location of it. This will remove the tcp stream from  the current process' resources.
 //
@@ -366,12 +498,13 @@ fn push_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .or_trap("lunatic::message::push_tcp_stream")?;
     let index = match message {
         Message::Data(data) => data.add_resource(stream) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -395,12 +528,13 @@ fn take_tcp_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         Message::Data(data) => data
             .take_tcp_stream(index as usize)
             .or_trap("lunatic::message::take_tcp_stream")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().tcp_stream_resources_mut().add(tcp_stream))
 }
@@ -428,12 +562,13 @@ fn push_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .or_trap("lunatic::message::push_tls_stream")?;
     let index = match message {
         Message::Data(data) => data.add_resource(stream) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -457,12 +592,13 @@ fn take_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         Message::Data(data) => data
             .take_tls_stream(index as usize)
             .or_trap("lunatic::message::take_tls_stream")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().tls_stream_resources_mut().add(tls_stream))
 }
@@ -557,6 +693,8 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
 // * 0    if it's a data message.
 // * 1    if it's a link died signal.
 // * 2    if it's a process died signal.
+// * 3    if it's a shutdown request, the node is stopping. Only processes that called
+//        `lunatic::process::notify_shutdown` receive it.
 // * 9027 if call timed out.
 //
 // Traps:
@@ -594,8 +732,9 @@ fn receive<T: ProcessState + ProcessCtx<T> + Send>(
         } {
             let result = match message {
                 Message::Data(_) => 0,
-                Message::LinkDied(_) => 1,
+                Message::LinkDied(..) => 1,
                 Message::ProcessDied(_) => 2,
+                Message::Shutdown => 3,
             };
             // Put the message into the scratch area
             caller.data_mut().message_scratch_area().replace(message);
@@ -627,12 +766,13 @@ fn push_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         .or_trap("lunatic::message::push_udp_socket")?;
     let index = match message {
         Message::Data(data) => data.add_resource(socket) as u64,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(index)
 }
@@ -656,12 +796,13 @@ fn take_udp_socket<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
         Message::Data(data) => data
             .take_udp_socket(index as usize)
             .or_trap("lunatic::message::take_udp_socket")?,
-        Message::LinkDied(_) => {
+        Message::LinkDied(..) => {
             return Err(anyhow!("Unexpected `Message::LinkDied` in scratch area"))
         }
         Message::ProcessDied(_) => {
             return Err(anyhow!("Unexpected `Message::ProcessDied` in scratch area"))
         }
+        Message::Shutdown => return Err(anyhow!("Unexpected `Message::Shutdown` in scratch area")),
     };
     Ok(caller.data_mut().udp_resources_mut().add(udp_socket))
 }
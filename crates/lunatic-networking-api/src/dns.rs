@@ -1,13 +1,16 @@
+use std::collections::HashMap;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use std::vec::IntoIter;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use tokio::time::timeout;
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_error_api::ErrorCtx;
 
 use crate::NetworkingCtx;
@@ -30,17 +33,86 @@ impl Iterator for DnsIterator {
     }
 }
 
+/// Name resolution overrides of a process configuration.
+///
+/// Static host entries take precedence over the host OS resolver. Names without a dot are also
+/// looked up with each search domain appended, before falling back to the name itself.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DnsOverrides {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    search_domains: Vec<String>,
+}
+
+impl DnsOverrides {
+    /// Resolve `name` to `ip`. Can be called multiple times for the same name.
+    pub fn add_host<S: AsRef<str>>(&mut self, name: S, ip: IpAddr) {
+        self.hosts
+            .entry(name.as_ref().to_lowercase())
+            .or_default()
+            .push(ip);
+    }
+
+    pub fn add_search_domain<S: Into<String>>(&mut self, domain: S) {
+        self.search_domains.push(domain.into());
+    }
+
+    /// Resolves a `host:port` string.
+    pub async fn lookup(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        let host_port = name
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)));
+        match host_port {
+            Some((host, port)) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                self.lookup_host(host, port).await
+            }
+            // Let the OS resolver report the malformed name
+            None => Ok(tokio::net::lookup_host(name).await?.collect()),
+        }
+    }
+
+    /// Resolves `host` and returns the addresses with `port` attached.
+    pub async fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addrs) = self.static_host(host, port) {
+            return Ok(addrs);
+        }
+        if !host.contains('.') && !host.contains(':') {
+            for domain in self.search_domains.iter() {
+                let name = format!("{host}.{domain}");
+                if let Some(addrs) = self.static_host(&name, port) {
+                    return Ok(addrs);
+                }
+                let addrs: Vec<SocketAddr> =
+                    match tokio::net::lookup_host((name.as_str(), port)).await {
+                        Ok(addrs) => addrs.collect(),
+                        Err(_) => continue,
+                    };
+                if !addrs.is_empty() {
+                    return Ok(addrs);
+                }
+            }
+        }
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    fn static_host(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        self.hosts
+            .get(&host.to_lowercase())
+            .map(|ips| ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+}
+
 // Register DNS networking APIs to the linker
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
-    linker.func_wrap4_async("lunatic::networking", "resolve", resolve)?;
-    linker.func_wrap(
+    linker.func_wrap4_async_named("lunatic::networking", "resolve", resolve)?;
+    linker.func_wrap_named(
         "lunatic::networking",
         "drop_dns_iterator",
         drop_dns_iterator,
     )?;
-    linker.func_wrap("lunatic::networking", "resolve_next", resolve_next)?;
+    linker.func_wrap_named("lunatic::networking", "resolve_next", resolve_next)?;
     Ok(())
 }
 
@@ -76,7 +148,7 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::network::resolve::not_valid_utf8_string")?;
 
         // Check for timeout during lookup
-        let lookup_host = tokio::net::lookup_host(name);
+        let lookup_host = state.dns_overrides().lookup(name);
         let (iter_or_error_id, result) = if let Ok(result) = match timeout_duration {
             // Without timeout
             u64::MAX => Ok(lookup_host.await),
@@ -85,11 +157,9 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
         } {
             match result {
                 Ok(sockets) => {
-                    // This is a bug in clippy, this collect is not needless
-                    #[allow(clippy::needless_collect)]
-                    let id = state.dns_resources_mut().add(DnsIterator::new(
-                        sockets.collect::<Vec<SocketAddr>>().into_iter(),
-                    ));
+                    let id = state
+                        .dns_resources_mut()
+                        .add(DnsIterator::new(sockets.into_iter()));
                     (id, 0)
                 }
                 Err(error) => {
@@ -200,3 +270,23 @@ fn resolve_next<T: NetworkingCtx>(
         None => Ok(1),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn static_hosts_and_search_domains() {
+        let mut overrides = DnsOverrides::default();
+        overrides.add_host("Database", "10.0.0.1".parse().unwrap());
+        overrides.add_host("cache.internal", "10.0.0.2".parse().unwrap());
+        overrides.add_search_domain("internal");
+
+        let addrs = overrides.lookup("database:5432").await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.1:5432".parse().unwrap()]);
+        let addrs = overrides.lookup("cache:6379").await.unwrap();
+        assert_eq!(addrs, vec!["10.0.0.2:6379".parse().unwrap()]);
+        let addrs = overrides.lookup("[::1]:80").await.unwrap();
+        assert_eq!(addrs, vec!["[::1]:80".parse().unwrap()]);
+    }
+}
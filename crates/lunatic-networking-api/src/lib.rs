@@ -2,16 +2,20 @@ mod dns;
 mod tcp;
 mod tls_tcp;
 mod udp;
+mod virtual_network;
 
 use std::convert::TryInto;
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
 use lunatic_error_api::ErrorCtx;
-use tokio::io::{split, ReadHalf, WriteHalf};
+use tokio::io::{split, AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
 
@@ -24,11 +28,15 @@ use wasmtime::{Caller, Linker};
 
 use lunatic_common_api::IntoTrap;
 
-pub use dns::DnsIterator;
+pub use dns::{DnsIterator, DnsOverrides};
+pub use virtual_network::{
+    NetworkConditions, VirtualNetwork, VirtualTcpListener, VirtualTcpReader, VirtualTcpStream,
+    VirtualTcpWriter, VirtualUdpSocket,
+};
 
 pub struct TcpConnection {
-    pub reader: Mutex<OwnedReadHalf>,
-    pub writer: Mutex<OwnedWriteHalf>,
+    pub reader: Mutex<TcpReader>,
+    pub writer: Mutex<TcpWriter>,
     pub read_timeout: Mutex<Option<Duration>>,
     pub write_timeout: Mutex<Option<Duration>>,
     pub peek_timeout: Mutex<Option<Duration>>,
@@ -70,9 +78,21 @@ impl TlsConnection {
 impl TcpConnection {
     pub fn new(stream: TcpStream) -> Self {
         let (read_half, write_half) = stream.into_split();
+        Self::from_halves(TcpReader::Socket(read_half), TcpWriter::Socket(write_half))
+    }
+
+    pub fn new_virtual(stream: VirtualTcpStream) -> Self {
+        let (read_half, write_half) = stream.into_split();
+        Self::from_halves(
+            TcpReader::Virtual(read_half),
+            TcpWriter::Virtual(write_half),
+        )
+    }
+
+    fn from_halves(reader: TcpReader, writer: TcpWriter) -> Self {
         TcpConnection {
-            reader: Mutex::new(read_half),
-            writer: Mutex::new(write_half),
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
             read_timeout: Mutex::new(None),
             write_timeout: Mutex::new(None),
             peek_timeout: Mutex::new(None),
@@ -80,11 +100,220 @@ impl TcpConnection {
     }
 }
 
-pub type TcpListenerResources = HashMapId<TcpListener>;
+/// A TCP listener on the host or on the process' [`VirtualNetwork`].
+pub enum TcpListenerResource {
+    Socket(TcpListener),
+    Virtual(VirtualTcpListener),
+}
+
+impl TcpListenerResource {
+    pub async fn accept(&self) -> io::Result<(TcpConnection, SocketAddr)> {
+        match self {
+            Self::Socket(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((TcpConnection::new(stream), addr))
+            }
+            Self::Virtual(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((TcpConnection::new_virtual(stream), addr))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Socket(listener) => listener.local_addr(),
+            Self::Virtual(listener) => Ok(listener.local_addr()),
+        }
+    }
+}
+
+pub enum TcpReader {
+    Socket(OwnedReadHalf),
+    Virtual(VirtualTcpReader),
+}
+
+impl TcpReader {
+    /// Receives data without removing it from the stream.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Socket(reader) => reader.peek(buf).await,
+            Self::Virtual(reader) => reader.peek(buf).await,
+        }
+    }
+}
+
+impl AsyncRead for TcpReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Socket(reader) => Pin::new(reader).poll_read(cx, buf),
+            Self::Virtual(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+pub enum TcpWriter {
+    Socket(OwnedWriteHalf),
+    Virtual(VirtualTcpWriter),
+}
+
+impl TcpWriter {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Socket(writer) => writer.peer_addr(),
+            Self::Virtual(writer) => Ok(writer.peer_addr()),
+        }
+    }
+}
+
+impl AsyncWrite for TcpWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Socket(writer) => Pin::new(writer).poll_write(cx, buf),
+            Self::Virtual(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Socket(writer) => Pin::new(writer).poll_write_vectored(cx, bufs),
+            Self::Virtual(writer) => Pin::new(writer).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Socket(writer) => writer.is_write_vectored(),
+            Self::Virtual(writer) => writer.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Socket(writer) => Pin::new(writer).poll_flush(cx),
+            Self::Virtual(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Socket(writer) => Pin::new(writer).poll_shutdown(cx),
+            Self::Virtual(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A UDP socket on the host or on the process' [`VirtualNetwork`].
+pub enum UdpSocketResource {
+    Socket(UdpSocket),
+    Virtual(VirtualUdpSocket),
+}
+
+impl UdpSocketResource {
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Self::Socket(socket) => socket.connect(addr).await,
+            Self::Virtual(socket) => {
+                socket.connect(addr);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        match self {
+            Self::Socket(socket) => socket.send_to(buf, target).await,
+            Self::Virtual(socket) => Ok(socket.send_to(buf, target)),
+        }
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Socket(socket) => socket.send(buf).await,
+            Self::Virtual(socket) => socket.send(buf),
+        }
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Socket(socket) => socket.recv_from(buf).await,
+            Self::Virtual(socket) => socket.recv_from(buf).await,
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Socket(socket) => socket.recv(buf).await,
+            Self::Virtual(socket) => socket.recv(buf).await,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Socket(socket) => socket.local_addr(),
+            Self::Virtual(socket) => Ok(socket.local_addr()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Socket(socket) => socket.peer_addr(),
+            Self::Virtual(socket) => socket.peer_addr(),
+        }
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        match self {
+            Self::Socket(socket) => socket.set_broadcast(broadcast),
+            Self::Virtual(socket) => {
+                socket.set_broadcast(broadcast);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        match self {
+            Self::Socket(socket) => socket.broadcast(),
+            Self::Virtual(socket) => Ok(socket.broadcast()),
+        }
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match self {
+            Self::Socket(socket) => socket.set_ttl(ttl),
+            Self::Virtual(socket) => {
+                socket.set_ttl(ttl);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        match self {
+            Self::Socket(socket) => socket.ttl(),
+            Self::Virtual(socket) => Ok(socket.ttl()),
+        }
+    }
+}
+
+pub type TcpListenerResources = HashMapId<TcpListenerResource>;
 pub type TlsListenerResources = HashMapId<TlsListener>;
 pub type TcpStreamResources = HashMapId<Arc<TcpConnection>>;
 pub type TlsStreamResources = HashMapId<Arc<TlsConnection>>;
-pub type UdpResources = HashMapId<Arc<UdpSocket>>;
+pub type UdpResources = HashMapId<Arc<UdpSocketResource>>;
 pub type DnsResources = HashMapId<DnsIterator>;
 
 pub trait NetworkingCtx {
@@ -100,6 +329,20 @@ pub trait NetworkingCtx {
     fn udp_resources_mut(&mut self) -> &mut UdpResources;
     fn dns_resources(&self) -> &DnsResources;
     fn dns_resources_mut(&mut self) -> &mut DnsResources;
+    fn dns_overrides(&self) -> &DnsOverrides;
+    /// The in-memory network TCP and UDP sockets are opened on, `None` to use the host network.
+    fn virtual_network(&self) -> Option<&VirtualNetwork>;
+}
+
+/// Networking settings of a process configuration.
+///
+/// Configurations created by a process start with its DNS overrides and virtual network, so the
+/// settings given to the entry process apply to all processes spawned from it.
+pub trait NetworkingConfigCtx {
+    fn dns_overrides(&self) -> &DnsOverrides;
+    fn set_dns_overrides(&mut self, dns_overrides: DnsOverrides);
+    fn virtual_network(&self) -> Option<&VirtualNetwork>;
+    fn set_virtual_network(&mut self, virtual_network: Option<VirtualNetwork>);
 }
 
 // Register the networking APIs to the linker
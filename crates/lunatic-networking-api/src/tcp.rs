@@ -12,50 +12,50 @@ use tokio::{
 };
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_error_api::ErrorCtx;
 
 use crate::dns::DnsIterator;
-use crate::{socket_address, NetworkingCtx, TcpConnection};
+use crate::{socket_address, NetworkingCtx, TcpConnection, TcpListenerResource};
 
 // Register TCP networking APIs to the linker
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
-    linker.func_wrap6_async("lunatic::networking", "tcp_bind", tcp_bind)?;
-    linker.func_wrap(
+    linker.func_wrap6_async_named("lunatic::networking", "tcp_bind", tcp_bind)?;
+    linker.func_wrap_named(
         "lunatic::networking",
         "drop_tcp_listener",
         drop_tcp_listener,
     )?;
-    linker.func_wrap("lunatic::networking", "tcp_local_addr", tcp_local_addr)?;
-    linker.func_wrap3_async("lunatic::networking", "tcp_accept", tcp_accept)?;
-    linker.func_wrap7_async("lunatic::networking", "tcp_connect", tcp_connect)?;
-    linker.func_wrap2_async("lunatic::networking", "tcp_peer_addr", tcp_peer_addr)?;
-    linker.func_wrap("lunatic::networking", "drop_tcp_stream", drop_tcp_stream)?;
-    linker.func_wrap("lunatic::networking", "clone_tcp_stream", clone_tcp_stream)?;
-    linker.func_wrap4_async(
+    linker.func_wrap_named("lunatic::networking", "tcp_local_addr", tcp_local_addr)?;
+    linker.func_wrap3_async_named("lunatic::networking", "tcp_accept", tcp_accept)?;
+    linker.func_wrap7_async_named("lunatic::networking", "tcp_connect", tcp_connect)?;
+    linker.func_wrap2_async_named("lunatic::networking", "tcp_peer_addr", tcp_peer_addr)?;
+    linker.func_wrap_named("lunatic::networking", "drop_tcp_stream", drop_tcp_stream)?;
+    linker.func_wrap_named("lunatic::networking", "clone_tcp_stream", clone_tcp_stream)?;
+    linker.func_wrap4_async_named(
         "lunatic::networking",
         "tcp_write_vectored",
         tcp_write_vectored,
     )?;
-    linker.func_wrap4_async("lunatic::networking", "tcp_peek", tcp_peek)?;
-    linker.func_wrap4_async("lunatic::networking", "tcp_read", tcp_read)?;
-    linker.func_wrap2_async("lunatic::networking", "set_read_timeout", set_read_timeout)?;
-    linker.func_wrap2_async(
+    linker.func_wrap4_async_named("lunatic::networking", "tcp_peek", tcp_peek)?;
+    linker.func_wrap4_async_named("lunatic::networking", "tcp_read", tcp_read)?;
+    linker.func_wrap2_async_named("lunatic::networking", "set_read_timeout", set_read_timeout)?;
+    linker.func_wrap2_async_named(
         "lunatic::networking",
         "set_write_timeout",
         set_write_timeout,
     )?;
-    linker.func_wrap2_async("lunatic::networking", "set_peek_timeout", set_peek_timeout)?;
-    linker.func_wrap1_async("lunatic::networking", "get_read_timeout", get_read_timeout)?;
-    linker.func_wrap1_async(
+    linker.func_wrap2_async_named("lunatic::networking", "set_peek_timeout", set_peek_timeout)?;
+    linker.func_wrap1_async_named("lunatic::networking", "get_read_timeout", get_read_timeout)?;
+    linker.func_wrap1_async_named(
         "lunatic::networking",
         "get_write_timeout",
         get_write_timeout,
     )?;
-    linker.func_wrap1_async("lunatic::networking", "get_peek_timeout", get_peek_timeout)?;
-    linker.func_wrap2_async("lunatic::networking", "tcp_flush", tcp_flush)?;
+    linker.func_wrap1_async_named("lunatic::networking", "get_peek_timeout", get_peek_timeout)?;
+    linker.func_wrap2_async_named("lunatic::networking", "tcp_flush", tcp_flush)?;
     Ok(())
 }
 
@@ -91,7 +91,16 @@ fn tcp_bind<T: NetworkingCtx + ErrorCtx + Send>(
             flow_info,
             scope_id,
         )?;
-        let (tcp_listener_or_error_id, result) = match TcpListener::bind(socket_addr).await {
+        let virtual_network = caller.data().virtual_network().cloned();
+        let listener = match virtual_network {
+            Some(network) => network
+                .bind_tcp(socket_addr)
+                .map(TcpListenerResource::Virtual),
+            None => TcpListener::bind(socket_addr)
+                .await
+                .map(TcpListenerResource::Socket),
+        };
+        let (tcp_listener_or_error_id, result) = match listener {
             Ok(listener) => (
                 caller.data_mut().tcp_listener_resources_mut().add(listener),
                 0,
@@ -193,7 +202,7 @@ fn tcp_accept<T: NetworkingCtx + ErrorCtx + Send>(
                 let stream_id = caller
                     .data_mut()
                     .tcp_stream_resources_mut()
-                    .add(Arc::new(TcpConnection::new(stream)));
+                    .add(Arc::new(stream));
                 let dns_iter_id = caller
                     .data_mut()
                     .dns_resources_mut()
@@ -260,7 +269,18 @@ fn tcp_connect<T: NetworkingCtx + ErrorCtx + Send>(
             scope_id,
         )?;
 
-        let connect = TcpStream::connect(socket_addr);
+        let virtual_network = caller.data().virtual_network().cloned();
+        let connect = async {
+            match virtual_network {
+                Some(network) => network
+                    .connect_tcp(socket_addr)
+                    .await
+                    .map(TcpConnection::new_virtual),
+                None => TcpStream::connect(socket_addr)
+                    .await
+                    .map(TcpConnection::new),
+            }
+        };
         if let Ok(result) = match timeout_duration {
             // Without timeout
             u64::MAX => Ok(connect.await),
@@ -272,7 +292,7 @@ fn tcp_connect<T: NetworkingCtx + ErrorCtx + Send>(
                     caller
                         .data_mut()
                         .tcp_stream_resources_mut()
-                        .add(Arc::new(TcpConnection::new(stream))),
+                        .add(Arc::new(stream)),
                     0,
                 ),
                 Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
@@ -12,10 +12,11 @@ use tokio::{
 };
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_error_api::ErrorCtx;
 
 use crate::dns::DnsIterator;
+use crate::virtual_network::tls_unsupported;
 use crate::{socket_address, NetworkingCtx, TlsConnection, TlsListener};
 use tokio_rustls::rustls::{self, OwnedTrustAnchor};
 use tokio_rustls::{webpki, TlsAcceptor, TlsConnector, TlsStream};
@@ -24,44 +25,44 @@ use tokio_rustls::{webpki, TlsAcceptor, TlsConnector, TlsStream};
 pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
-    linker.func_wrap10_async("lunatic::networking", "tls_bind", tls_bind)?;
-    linker.func_wrap(
+    linker.func_wrap10_async_named("lunatic::networking", "tls_bind", tls_bind)?;
+    linker.func_wrap_named(
         "lunatic::networking",
         "drop_tls_listener",
         drop_tls_listener,
     )?;
-    linker.func_wrap("lunatic::networking", "tls_local_addr", tls_local_addr)?;
-    linker.func_wrap3_async("lunatic::networking", "tls_accept", tls_accept)?;
-    linker.func_wrap7_async("lunatic::networking", "tls_connect", tls_connect)?;
-    linker.func_wrap("lunatic::networking", "drop_tls_stream", drop_tls_stream)?;
-    linker.func_wrap("lunatic::networking", "clone_tls_stream", clone_tls_stream)?;
-    linker.func_wrap4_async(
+    linker.func_wrap_named("lunatic::networking", "tls_local_addr", tls_local_addr)?;
+    linker.func_wrap3_async_named("lunatic::networking", "tls_accept", tls_accept)?;
+    linker.func_wrap7_async_named("lunatic::networking", "tls_connect", tls_connect)?;
+    linker.func_wrap_named("lunatic::networking", "drop_tls_stream", drop_tls_stream)?;
+    linker.func_wrap_named("lunatic::networking", "clone_tls_stream", clone_tls_stream)?;
+    linker.func_wrap4_async_named(
         "lunatic::networking",
         "tls_write_vectored",
         tls_write_vectored,
     )?;
-    linker.func_wrap4_async("lunatic::networking", "tls_read", tls_read)?;
-    linker.func_wrap2_async(
+    linker.func_wrap4_async_named("lunatic::networking", "tls_read", tls_read)?;
+    linker.func_wrap2_async_named(
         "lunatic::networking",
         "set_tls_read_timeout",
         set_tls_read_timeout,
     )?;
-    linker.func_wrap2_async(
+    linker.func_wrap2_async_named(
         "lunatic::networking",
         "set_tls_write_timeout",
         set_tls_write_timeout,
     )?;
-    linker.func_wrap1_async(
+    linker.func_wrap1_async_named(
         "lunatic::networking",
         "get_tls_read_timeout",
         get_tls_read_timeout,
     )?;
-    linker.func_wrap1_async(
+    linker.func_wrap1_async_named(
         "lunatic::networking",
         "get_tls_write_timeout",
         get_tls_write_timeout,
     )?;
-    linker.func_wrap2_async("lunatic::networking", "tls_flush", tls_flush)?;
+    linker.func_wrap2_async_named("lunatic::networking", "tls_flush", tls_flush)?;
     Ok(())
 }
 
@@ -116,7 +117,8 @@ fn tls_local_addr<T: NetworkingCtx + ErrorCtx>(
 //
 // Returns:
 // * 0 on success - The ID of the newly created TLS listener is written to **id_u64_ptr**
-// * 1 on error   - The error ID is written to **id_u64_ptr**
+// * 1 on error   - The error ID is written to **id_u64_ptr**, also if the process uses the
+//                  virtual network
 //
 // Traps:
 // * If any memory outside the guest heap space is referenced.
@@ -160,7 +162,12 @@ fn tls_bind<T: NetworkingCtx + ErrorCtx + Send>(
             flow_info,
             scope_id,
         )?;
-        let (tls_listener_or_error_id, result) = match TcpListener::bind(socket_addr).await {
+        let listener = if caller.data().virtual_network().is_some() {
+            Err(tls_unsupported())
+        } else {
+            TcpListener::bind(socket_addr).await
+        };
+        let (tls_listener_or_error_id, result) = match listener {
             Ok(listener) => (
                 caller
                     .data_mut()
@@ -280,10 +287,7 @@ fn load_private_key(file: &[u8]) -> io::Result<rustls::PrivateKey> {
     // Load and return a single private key.
     let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
     if keys.len() != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "expected a single private key",
-        ));
+        return Err(io::Error::other("expected a single private key"));
     }
 
     Ok(rustls::PrivateKey(keys[0].clone()))
@@ -293,10 +297,7 @@ fn load_certs(file: &[u8]) -> io::Result<rustls::Certificate> {
     let mut reader = io::BufReader::new(file);
     let certs = rustls_pemfile::certs(&mut reader)?;
     if certs.len() != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "expected a single private key",
-        ));
+        return Err(io::Error::other("expected a single private key"));
     }
 
     Ok(rustls::Certificate(certs[0].clone()))
@@ -308,7 +309,8 @@ fn load_certs(file: &[u8]) -> io::Result<rustls::Certificate> {
 //
 // Returns:
 // * 0 on success - The ID of the newly created TLS stream is written to **id_ptr**.
-// * 1 on error   - The error ID is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, also if the process uses the virtual
+//                  network
 // * 9027 if the operation timed out
 //
 // Traps:
@@ -405,7 +407,16 @@ fn tls_connect<T: NetworkingCtx + ErrorCtx + Send>(
             .with_no_client_auth(); // i guess this was previously the default?
 
         let connector = TlsConnector::from(Arc::new(config));
-        let connect = TcpStream::connect((&socket_addr[..], port as u16));
+        // The name resolution counts towards the timeout too
+        let dns_overrides = caller.data().dns_overrides();
+        let virtual_network = caller.data().virtual_network().is_some();
+        let connect = async {
+            if virtual_network {
+                return Err(tls_unsupported());
+            }
+            let addrs = dns_overrides.lookup_host(&socket_addr, port as u16).await?;
+            TcpStream::connect(&addrs[..]).await
+        };
         if let Ok(result) = match timeout_duration {
             // Without timeout
             u64::MAX => Ok(connect.await),
@@ -0,0 +1,611 @@
+//! An in-memory network, so that networking guests can be tested without binding host ports.
+//!
+//! Processes with a [`VirtualNetwork`] in their configuration bind and connect their TCP and UDP
+//! sockets on it instead of on the host. Any address can be bound, and every bound address is
+//! reachable from all processes sharing the network. [`NetworkConditions`] add latency to
+//! connections and datagrams, and drop a share of the UDP datagrams.
+//!
+//! TLS isn't available on the virtual network, `tls_bind` and `tls_connect` fail with
+//! [`ErrorKind::Unsupported`] for processes using it.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::Sleep,
+};
+
+// Buffer size of each direction of a TCP connection
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+// Ports assigned when binding to port 0 start here
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+const EPHEMERAL_PORTS: u32 = (u16::MAX - FIRST_EPHEMERAL_PORT) as u32 + 1;
+
+// A UDP datagram and the address it was sent from
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to TCP connects and writes, and to the delivery of UDP datagrams
+    pub latency: Duration,
+    /// Share of UDP datagrams that are dropped, from 0.0 to 1.0
+    pub loss: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct VirtualNetwork {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    conditions: Mutex<NetworkConditions>,
+    tcp_listeners: Mutex<HashMap<SocketAddr, UnboundedSender<VirtualTcpStream>>>,
+    udp_sockets: Mutex<HashMap<SocketAddr, UnboundedSender<Datagram>>>,
+    next_port: AtomicU32,
+    // State of the generator deciding which datagrams are lost, so that runs are reproducible
+    loss_state: AtomicU64,
+}
+
+impl Debug for VirtualNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtualNetwork")
+            .field("conditions", &self.conditions())
+            .finish()
+    }
+}
+
+impl VirtualNetwork {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        let network = Self::default();
+        network.set_conditions(conditions);
+        network
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        *self.inner.conditions.lock().unwrap()
+    }
+
+    /// Changes the conditions of the network, applies to already open sockets too.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        *self.inner.conditions.lock().unwrap() = conditions;
+    }
+
+    pub fn bind_tcp(&self, addr: SocketAddr) -> io::Result<VirtualTcpListener> {
+        let (sender, incoming) = unbounded_channel();
+        let local_addr = self.bind(&self.inner.tcp_listeners, addr, sender)?;
+        Ok(VirtualTcpListener {
+            network: self.clone(),
+            local_addr,
+            incoming: tokio::sync::Mutex::new(incoming),
+        })
+    }
+
+    pub async fn connect_tcp(&self, addr: SocketAddr) -> io::Result<VirtualTcpStream> {
+        self.delay().await;
+        let listener = lookup(&self.inner.tcp_listeners, addr)
+            .ok_or_else(|| io::Error::from(ErrorKind::ConnectionRefused))?;
+        let local_addr = SocketAddr::new(local_ip(addr), self.ephemeral_port());
+        let (client, server) = tokio::io::duplex(TCP_BUFFER_SIZE);
+        listener
+            .send(VirtualTcpStream::new(
+                self.clone(),
+                server,
+                addr,
+                local_addr,
+            ))
+            .map_err(|_| io::Error::from(ErrorKind::ConnectionRefused))?;
+        Ok(VirtualTcpStream::new(
+            self.clone(),
+            client,
+            local_addr,
+            addr,
+        ))
+    }
+
+    pub fn bind_udp(&self, addr: SocketAddr) -> io::Result<VirtualUdpSocket> {
+        let (sender, incoming) = unbounded_channel();
+        let local_addr = self.bind(&self.inner.udp_sockets, addr, sender)?;
+        Ok(VirtualUdpSocket {
+            network: self.clone(),
+            local_addr,
+            peer_addr: Mutex::new(None),
+            incoming: tokio::sync::Mutex::new(incoming),
+            broadcast: AtomicBool::new(false),
+            ttl: AtomicU32::new(64),
+        })
+    }
+
+    // Registers `sender` under `addr`, or under a free port if the port of `addr` is 0.
+    fn bind<T>(
+        &self,
+        sockets: &Mutex<HashMap<SocketAddr, T>>,
+        mut addr: SocketAddr,
+        sender: T,
+    ) -> io::Result<SocketAddr> {
+        let mut sockets = sockets.lock().unwrap();
+        if addr.port() == 0 {
+            // Each ephemeral port is tried at most once
+            let port = (0..EPHEMERAL_PORTS)
+                .map(|_| self.ephemeral_port())
+                .find(|&port| !in_use(&sockets, SocketAddr::new(addr.ip(), port)))
+                .ok_or(ErrorKind::AddrInUse)?;
+            addr.set_port(port);
+        } else if in_use(&sockets, addr) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        sockets.insert(addr, sender);
+        Ok(addr)
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        let next = self.inner.next_port.fetch_add(1, Ordering::Relaxed);
+        FIRST_EPHEMERAL_PORT + (next % EPHEMERAL_PORTS) as u16
+    }
+
+    async fn delay(&self) {
+        let latency = self.conditions().latency;
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+    }
+
+    fn send_datagram(&self, data: &[u8], from: SocketAddr, to: SocketAddr) {
+        let conditions = self.conditions();
+        if conditions.loss > 0.0 && self.next_random() < conditions.loss {
+            return;
+        }
+        // Datagrams to addresses nobody is bound to are dropped, like on a real network
+        let Some(socket) = lookup(&self.inner.udp_sockets, to) else {
+            return;
+        };
+        let datagram = (data.to_vec(), from);
+        if conditions.latency.is_zero() {
+            socket.send(datagram).ok();
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(conditions.latency).await;
+                socket.send(datagram).ok();
+            });
+        }
+    }
+
+    // Returns a number in [0, 1), using splitmix64 with a fixed seed.
+    fn next_random(&self) -> f64 {
+        let state = self
+            .inner
+            .loss_state
+            .fetch_add(0x9E3779B97F4A7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// An address is in use if the port is bound on the same IP, or if either of them is unspecified.
+fn in_use<T>(sockets: &HashMap<SocketAddr, T>, addr: SocketAddr) -> bool {
+    if addr.ip().is_unspecified() {
+        return sockets.keys().any(|bound| bound.port() == addr.port());
+    }
+    sockets.contains_key(&addr) || sockets.contains_key(&unspecified(addr))
+}
+
+// Finds the socket bound to `addr`, or to the unspecified address of the same port.
+fn lookup<T: Clone>(sockets: &Mutex<HashMap<SocketAddr, T>>, addr: SocketAddr) -> Option<T> {
+    let sockets = sockets.lock().unwrap();
+    sockets
+        .get(&addr)
+        .or_else(|| sockets.get(&unspecified(addr)))
+        .cloned()
+}
+
+// The unspecified address of the same family and port as `addr`.
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+// TLS only runs on the host network.
+pub(crate) fn tls_unsupported() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "TLS is not available on the virtual network",
+    )
+}
+
+// Address the connecting side of a connection to `peer` gets.
+fn local_ip(peer: SocketAddr) -> IpAddr {
+    match peer.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    }
+}
+
+pub struct VirtualTcpListener {
+    network: VirtualNetwork,
+    local_addr: SocketAddr,
+    incoming: tokio::sync::Mutex<UnboundedReceiver<VirtualTcpStream>>,
+}
+
+impl VirtualTcpListener {
+    pub async fn accept(&self) -> io::Result<(VirtualTcpStream, SocketAddr)> {
+        let stream = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+        let peer_addr = stream.peer_addr;
+        Ok((stream, peer_addr))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for VirtualTcpListener {
+    fn drop(&mut self) {
+        let mut listeners = self.network.inner.tcp_listeners.lock().unwrap();
+        listeners.remove(&self.local_addr);
+    }
+}
+
+pub struct VirtualTcpStream {
+    network: VirtualNetwork,
+    stream: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl VirtualTcpStream {
+    fn new(
+        network: VirtualNetwork,
+        stream: DuplexStream,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> Self {
+        Self {
+            network,
+            stream,
+            local_addr,
+            peer_addr,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn into_split(self) -> (VirtualTcpReader, VirtualTcpWriter) {
+        let (reader, writer) = split(self.stream);
+        (
+            VirtualTcpReader {
+                reader,
+                peeked: Vec::new(),
+            },
+            VirtualTcpWriter {
+                network: self.network,
+                writer,
+                peer_addr: self.peer_addr,
+                delay: None,
+            },
+        )
+    }
+}
+
+pub struct VirtualTcpReader {
+    reader: ReadHalf<DuplexStream>,
+    // Data returned by `peek` that wasn't read yet
+    peeked: Vec<u8>,
+}
+
+impl VirtualTcpReader {
+    /// Receives data without removing it from the stream.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.peeked.is_empty() {
+            let mut data = vec![0; buf.len()];
+            let n = self.reader.read(&mut data).await?;
+            data.truncate(n);
+            self.peeked = data;
+        }
+        let n = self.peeked.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.peeked[..n]);
+        Ok(n)
+    }
+}
+
+impl AsyncRead for VirtualTcpReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.peeked.is_empty() {
+            return Pin::new(&mut self.reader).poll_read(cx, buf);
+        }
+        let n = self.peeked.len().min(buf.remaining());
+        buf.put_slice(&self.peeked[..n]);
+        self.peeked.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct VirtualTcpWriter {
+    network: VirtualNetwork,
+    writer: WriteHalf<DuplexStream>,
+    peer_addr: SocketAddr,
+    // Latency of the write in progress
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl VirtualTcpWriter {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncWrite for VirtualTcpWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.delay.is_none() {
+            let latency = this.network.conditions().latency;
+            if !latency.is_zero() {
+                this.delay = Some(Box::pin(tokio::time::sleep(latency)));
+            }
+        }
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+        }
+        let written = ready!(Pin::new(&mut this.writer).poll_write(cx, buf));
+        this.delay = None;
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+pub struct VirtualUdpSocket {
+    network: VirtualNetwork,
+    local_addr: SocketAddr,
+    peer_addr: Mutex<Option<SocketAddr>>,
+    incoming: tokio::sync::Mutex<UnboundedReceiver<Datagram>>,
+    broadcast: AtomicBool,
+    ttl: AtomicU32,
+}
+
+impl VirtualUdpSocket {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer_addr
+            .lock()
+            .unwrap()
+            .ok_or_else(|| ErrorKind::NotConnected.into())
+    }
+
+    /// Sends to and only receives from `addr` from now on.
+    pub fn connect(&self, addr: SocketAddr) {
+        *self.peer_addr.lock().unwrap() = Some(addr);
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: SocketAddr) -> usize {
+        self.network.send_datagram(buf, self.local_addr, target);
+        buf.len()
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.send_to(buf, self.peer_addr()?))
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        loop {
+            let (data, from) = incoming
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+            // Connected sockets drop datagrams from other addresses
+            if matches!(*self.peer_addr.lock().unwrap(), Some(peer) if peer != from) {
+                continue;
+            }
+            // Like on a real socket, the rest of a datagram that doesn't fit is lost
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            return Ok((n, from));
+        }
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).await.map(|(n, _)| n)
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) {
+        self.broadcast.store(broadcast, Ordering::Relaxed);
+    }
+
+    pub fn broadcast(&self) -> bool {
+        self.broadcast.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ttl(&self, ttl: u32) {
+        self.ttl.store(ttl, Ordering::Relaxed);
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for VirtualUdpSocket {
+    fn drop(&mut self) {
+        let mut sockets = self.network.inner.udp_sockets.lock().unwrap();
+        sockets.remove(&self.local_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn tcp_connections() {
+        let network = VirtualNetwork::default();
+        let listener = network.bind_tcp(addr("10.0.0.1:80")).unwrap();
+        let client = network.connect_tcp(addr("10.0.0.1:80")).await.unwrap();
+        let (server, client_addr) = listener.accept().await.unwrap();
+        assert_eq!(client.local_addr(), client_addr);
+        assert_eq!(server.peer_addr(), client_addr);
+        assert_eq!(client.peer_addr(), addr("10.0.0.1:80"));
+
+        let (_, mut client) = client.into_split();
+        let (mut server, _) = server.into_split();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(server.peek(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn tcp_addresses() {
+        let network = VirtualNetwork::default();
+        let listener = network.bind_tcp(addr("0.0.0.0:80")).unwrap();
+        let err = network.bind_tcp(addr("10.0.0.1:80")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        // Listeners on the unspecified address accept connections to any address
+        network.connect_tcp(addr("10.0.0.1:80")).await.unwrap();
+
+        drop(listener);
+        let err = network
+            .connect_tcp(addr("10.0.0.1:80"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        let listener = network.bind_tcp(addr("10.0.0.1:0")).unwrap();
+        assert!(listener.local_addr().port() >= FIRST_EPHEMERAL_PORT);
+        network.connect_tcp(listener.local_addr()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ephemeral_ports_run_out() {
+        let network = VirtualNetwork::default();
+        let sockets: Vec<_> = (0..EPHEMERAL_PORTS)
+            .map(|_| network.bind_udp(addr("10.0.0.1:0")).unwrap())
+            .collect();
+        let err = network.bind_udp(addr("10.0.0.1:0")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        // Other IPs still have all ports free
+        network.bind_udp(addr("10.0.0.2:0")).unwrap();
+
+        drop(sockets);
+        network.bind_udp(addr("10.0.0.1:0")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn udp_datagrams() {
+        let network = VirtualNetwork::default();
+        let a = network.bind_udp(addr("10.0.0.1:53")).unwrap();
+        let b = network.bind_udp(addr("10.0.0.2:53")).unwrap();
+        let c = network.bind_udp(addr("10.0.0.3:53")).unwrap();
+
+        b.connect(a.local_addr());
+        c.send_to(b"from c", b.local_addr());
+        a.send_to(b"from a", b.local_addr());
+        // Connected sockets only receive from their peer
+        let mut buf = [0; 16];
+        assert_eq!(b.recv(&mut buf).await.unwrap(), 6);
+        assert_eq!(&buf[..6], b"from a");
+
+        b.send(b"too long").unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(a.recv_from(&mut buf).await.unwrap(), (3, b.local_addr()));
+        assert_eq!(&buf, b"too");
+    }
+
+    #[tokio::test]
+    async fn udp_loss() {
+        let network = VirtualNetwork::new(NetworkConditions {
+            latency: Duration::ZERO,
+            loss: 0.5,
+        });
+        let a = network.bind_udp(addr("10.0.0.1:53")).unwrap();
+        let b = network.bind_udp(addr("10.0.0.2:53")).unwrap();
+        for i in 0..1000u32 {
+            a.send_to(&i.to_le_bytes(), b.local_addr());
+        }
+        drop(a);
+        let mut incoming = b.incoming.lock().await;
+        let mut received = 0;
+        while incoming.try_recv().is_ok() {
+            received += 1;
+        }
+        assert!((400..600).contains(&received), "received {received}");
+    }
+
+    #[tokio::test]
+    async fn latency() {
+        let latency = Duration::from_millis(50);
+        let network = VirtualNetwork::new(NetworkConditions { latency, loss: 0.0 });
+        let _listener = network.bind_tcp(addr("10.0.0.1:80")).unwrap();
+        let started = Instant::now();
+        network.connect_tcp(addr("10.0.0.1:80")).await.unwrap();
+        assert!(started.elapsed() >= latency);
+
+        let a = network.bind_udp(addr("10.0.0.1:53")).unwrap();
+        let b = network.bind_udp(addr("10.0.0.2:53")).unwrap();
+        let started = Instant::now();
+        a.send_to(b"ping", b.local_addr());
+        b.recv(&mut [0; 4]).await.unwrap();
+        assert!(started.elapsed() >= latency);
+    }
+}
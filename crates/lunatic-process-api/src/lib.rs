@@ -4,14 +4,15 @@ use std::{
     io::Write,
     path::Path,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, Result};
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_distributed::DistributedCtx;
 use lunatic_error_api::ErrorCtx;
+use lunatic_networking_api::NetworkingConfigCtx;
 use lunatic_process::{
     config::ProcessConfig,
     env::Environment,
@@ -19,7 +20,7 @@ use lunatic_process::{
     message::Message,
     runtimes::{wasmtime::WasmtimeCompiledModule, RawWasm},
     state::ProcessState,
-    DeathReason, Process, Signal, WasmProcess,
+    DeathReason, Process, ProcessExit, Signal, WasmProcess,
 };
 use lunatic_wasi_api::LunaticWasiCtx;
 use wasmtime::{Caller, Linker, ResourceLimiter, Val};
@@ -34,6 +35,11 @@ pub trait ProcessConfigCtx {
     fn set_can_create_configs(&mut self, can: bool);
     fn can_spawn_processes(&self) -> bool;
     fn set_can_spawn_processes(&mut self, can: bool);
+    /// Time at which processes using this config lose their permissions, `None` if never.
+    fn expires_at(&self) -> Option<SystemTime>;
+    fn set_expires_at(&mut self, expires_at: Option<SystemTime>);
+    fn deny_namespace(&mut self, namespace: String);
+    fn denied_namespaces(&self) -> &[String];
     fn can_access_fs_location(&self, path: &Path) -> Result<(), String>;
 }
 
@@ -58,7 +64,7 @@ where
         + ResourceLimiter
         + 'static,
     for<'a> &'a T: Send,
-    T::Config: ProcessConfigCtx,
+    T::Config: ProcessConfigCtx + NetworkingConfigCtx,
     E: Environment + 'static,
 {
     #[cfg(feature = "metrics")]
@@ -92,8 +98,8 @@ where
         "Duration of module compilation"
     );
 
-    linker.func_wrap("lunatic::process", "compile_module", compile_module)?;
-    linker.func_wrap("lunatic::process", "drop_module", drop_module)?;
+    linker.func_wrap_named("lunatic::process", "compile_module", compile_module)?;
+    linker.func_wrap_named("lunatic::process", "drop_module", drop_module)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -116,72 +122,86 @@ where
         "number of configs currently in memory"
     );
 
-    linker.func_wrap("lunatic::process", "create_config", create_config)?;
-    linker.func_wrap("lunatic::process", "drop_config", drop_config)?;
-    linker.func_wrap(
+    linker.func_wrap_named("lunatic::process", "create_config", create_config)?;
+    linker.func_wrap_named("lunatic::process", "derive_config", derive_config)?;
+    linker.func_wrap_named("lunatic::process", "drop_config", drop_config)?;
+    linker.func_wrap_named(
         "lunatic::process",
         "config_set_max_memory",
         config_set_max_memory,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_get_max_memory",
         config_get_max_memory,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_set_max_fuel",
         config_set_max_fuel,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_get_max_fuel",
         config_get_max_fuel,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_can_compile_modules",
         config_can_compile_modules,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_set_can_compile_modules",
         config_set_can_compile_modules,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_can_create_configs",
         config_can_create_configs,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_set_can_create_configs",
         config_set_can_create_configs,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_can_spawn_processes",
         config_can_spawn_processes,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::process",
         "config_set_can_spawn_processes",
         config_set_can_spawn_processes,
     )?;
 
-    linker.func_wrap8_async("lunatic::process", "spawn", spawn)?;
-    linker.func_wrap11_async("lunatic::process", "get_or_spawn", get_or_spawn)?;
-    linker.func_wrap1_async("lunatic::process", "sleep_ms", sleep_ms)?;
-    linker.func_wrap("lunatic::process", "die_when_link_dies", die_when_link_dies)?;
-
-    linker.func_wrap("lunatic::process", "process_id", process_id)?;
-    linker.func_wrap("lunatic::process", "environment_id", environment_id)?;
-    linker.func_wrap("lunatic::process", "link", link)?;
-    linker.func_wrap("lunatic::process", "unlink", unlink)?;
-    linker.func_wrap("lunatic::process", "monitor", monitor)?;
-    linker.func_wrap("lunatic::process", "stop_monitoring", stop_monitoring)?;
-    linker.func_wrap("lunatic::process", "kill", kill)?;
-    linker.func_wrap("lunatic::process", "exists", exists)?;
+    linker.func_wrap_named(
+        "lunatic::process",
+        "config_deny_namespace",
+        config_deny_namespace,
+    )?;
+    linker.func_wrap_named(
+        "lunatic::process",
+        "config_set_expires_after",
+        config_set_expires_after,
+    )?;
+
+    linker.func_wrap8_async_named("lunatic::process", "spawn", spawn)?;
+    linker.func_wrap11_async_named("lunatic::process", "get_or_spawn", get_or_spawn)?;
+    linker.func_wrap1_async_named("lunatic::process", "sleep_ms", sleep_ms)?;
+    linker.func_wrap_named("lunatic::process", "die_when_link_dies", die_when_link_dies)?;
+    linker.func_wrap_named("lunatic::process", "notify_shutdown", notify_shutdown)?;
+
+    linker.func_wrap_named("lunatic::process", "process_id", process_id)?;
+    linker.func_wrap_named("lunatic::process", "environment_id", environment_id)?;
+    linker.func_wrap_named("lunatic::process", "link", link)?;
+    linker.func_wrap_named("lunatic::process", "unlink", unlink)?;
+    linker.func_wrap_named("lunatic::process", "monitor", monitor)?;
+    linker.func_wrap_named("lunatic::process", "stop_monitoring", stop_monitoring)?;
+    linker.func_wrap_named("lunatic::process", "kill", kill)?;
+    linker.func_wrap_named("lunatic::process", "exit", exit)?;
+    linker.func_wrap_named("lunatic::process", "exists", exists)?;
     Ok(())
 }
 
@@ -271,12 +291,48 @@ fn drop_module<T: ProcessState + ProcessCtx<T>>(
 
 // Create a new configuration with all permissions denied.
 //
-// There is no memory or fuel limit set on the newly created configuration.
+// The newly created configuration has the same memory and fuel limits, and the same DNS overrides
+// as the calling process.
 //
 // Returns:
 // * ID of newly created configuration in case of success
 // * -1 in case the process doesn't have permission to create new configurations
 fn create_config<T>(mut caller: Caller<T>) -> i64
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx + NetworkingConfigCtx,
+{
+    if !caller.data().config().can_create_configs() {
+        return -1;
+    }
+    let mut config = T::Config::default();
+    // New configurations can't lift the limits and namespace restrictions of the creator
+    let own_config = caller.data().config();
+    config.set_max_memory(config.get_max_memory().min(own_config.get_max_memory()));
+    if let Some(own_max_fuel) = own_config.get_max_fuel() {
+        config.set_max_fuel(Some(own_max_fuel));
+    }
+    for namespace in caller.data().config().denied_namespaces() {
+        config.deny_namespace(namespace.clone());
+    }
+    config.set_expires_at(own_config.expires_at());
+    config.set_dns_overrides(own_config.dns_overrides().clone());
+    config.set_virtual_network(own_config.virtual_network().cloned());
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("lunatic.process.configs.created");
+    #[cfg(feature = "metrics")]
+    metrics::increment_gauge!("lunatic.process.configs.active", 1.0);
+    caller.data_mut().config_resources_mut().add(config) as i64
+}
+
+// Creates a new configuration with the same limits and permissions as the one of the calling
+// process. The setters can only narrow it down further, so this is the starting point for
+// delegating a subset of the process' own capabilities.
+//
+// Returns:
+// * config ID if the process has permissions to create new configurations
+// * -1 if the process can't create new configurations
+fn derive_config<T>(mut caller: Caller<T>) -> i64
 where
     T: ProcessState + ProcessCtx<T>,
     T::Config: ProcessConfigCtx,
@@ -284,7 +340,7 @@ where
     if !caller.data().config().can_create_configs() {
         return -1;
     }
-    let config = T::Config::default();
+    let config = caller.data().config().as_ref().clone();
     #[cfg(feature = "metrics")]
     metrics::increment_counter!("lunatic.process.configs.created");
     #[cfg(feature = "metrics")]
@@ -316,6 +372,7 @@ fn drop_config<T: ProcessState + ProcessCtx<T>>(
 //
 // Traps:
 // * If max_memory is bigger than the platform maximum.
+// * If max_memory is bigger than the calling process' own memory limit.
 // * If the config ID doesn't exist.
 fn config_set_max_memory<T: ProcessState + ProcessCtx<T>>(
     mut caller: Caller<T>,
@@ -324,6 +381,11 @@ fn config_set_max_memory<T: ProcessState + ProcessCtx<T>>(
 ) -> Result<()> {
     let max_memory = usize::try_from(max_memory)
         .or_trap("lunatic::process::config_set_max_memory: max_memory exceeds platform max")?;
+    if max_memory > caller.data().config().get_max_memory() {
+        return Err(anyhow!(
+            "lunatic::process::config_set_max_memory: max_memory exceeds the process' own limit"
+        ));
+    }
     caller
         .data_mut()
         .config_resources_mut()
@@ -355,6 +417,7 @@ fn config_get_max_memory<T: ProcessState + ProcessCtx<T>>(
 // A value of 0 indicates no fuel limit.
 //
 // Traps:
+// * If the calling process has a fuel limit and max_fuel is 0 or exceeds it.
 // * If the config ID doesn't exist.
 fn config_set_max_fuel<T: ProcessState + ProcessCtx<T>>(
     mut caller: Caller<T>,
@@ -365,6 +428,13 @@ fn config_set_max_fuel<T: ProcessState + ProcessCtx<T>>(
         0 => None,
         max_fuel => Some(max_fuel),
     };
+    if let Some(own_max_fuel) = caller.data().config().get_max_fuel() {
+        if !matches!(max_fuel, Some(max_fuel) if max_fuel <= own_max_fuel) {
+            return Err(anyhow!(
+                "lunatic::process::config_set_max_fuel: max_fuel exceeds the process' own limit"
+            ));
+        }
+    }
 
     caller
         .data_mut()
@@ -419,12 +489,18 @@ where
 // Wasm modules.
 //
 // Traps:
+// * If the permission is granted, but the calling process doesn't have it.
 // * If the config ID doesn't exist.
 fn config_set_can_compile_modules<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
 where
     T: ProcessState + ProcessCtx<T>,
     T::Config: ProcessConfigCtx,
 {
+    if can != 0 && !caller.data().config().can_compile_modules() {
+        return Err(anyhow!(
+            "lunatic::process::config_set_can_compile_modules: Process can't grant a permission it doesn't have"
+        ));
+    }
     caller
         .data_mut()
         .config_resources_mut()
@@ -457,12 +533,18 @@ where
 // other configuration.
 //
 // Traps:
+// * If the permission is granted, but the calling process doesn't have it.
 // * If the config ID doesn't exist.
 fn config_set_can_create_configs<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
 where
     T: ProcessState + ProcessCtx<T>,
     T::Config: ProcessConfigCtx,
 {
+    if can != 0 && !caller.data().config().can_create_configs() {
+        return Err(anyhow!(
+            "lunatic::process::config_set_can_create_configs: Process can't grant a permission it doesn't have"
+        ));
+    }
     caller
         .data_mut()
         .config_resources_mut()
@@ -494,12 +576,18 @@ where
 // sub-processes.
 //
 // Traps:
+// * If the permission is granted, but the calling process doesn't have it.
 // * If the config ID doesn't exist.
 fn config_set_can_spawn_processes<T>(mut caller: Caller<T>, config_id: u64, can: u32) -> Result<()>
 where
     T: ProcessState + ProcessCtx<T>,
     T::Config: ProcessConfigCtx,
 {
+    if can != 0 && !caller.data().config().can_spawn_processes() {
+        return Err(anyhow!(
+            "lunatic::process::config_set_can_spawn_processes: Process can't grant a permission it doesn't have"
+        ));
+    }
     caller
         .data_mut()
         .config_resources_mut()
@@ -509,6 +597,69 @@ where
     Ok(())
 }
 
+// Forbids processes spawned from this configuration to import host functions from the given
+// namespace, e.g. `lunatic::networking`. Nested namespaces are denied too. Spawning a module that
+// imports from a denied namespace fails.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the namespace string is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn config_deny_namespace<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    namespace_ptr: u32,
+    namespace_len: u32,
+) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let namespace = memory
+        .data(&caller)
+        .get(namespace_ptr as usize..(namespace_ptr + namespace_len) as usize)
+        .or_trap("lunatic::process::config_deny_namespace")?;
+    let namespace = std::str::from_utf8(namespace)
+        .or_trap("lunatic::process::config_deny_namespace")?
+        .to_string();
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_deny_namespace: Config ID doesn't exist")?
+        .deny_namespace(namespace);
+    Ok(())
+}
+
+// Makes processes spawned from this configuration lose their permissions after `timeout_ms`
+// milliseconds. Once expired, they can't compile modules, create configurations, spawn processes
+// or grant access to their preopened directories anymore. Directories that were already opened
+// stay accessible.
+//
+// Traps:
+// * If the calling process' own permissions expire before the new time.
+// * If the config ID doesn't exist.
+fn config_set_expires_after<T>(mut caller: Caller<T>, config_id: u64, timeout_ms: u64) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let expires_at = SystemTime::now() + Duration::from_millis(timeout_ms);
+    if matches!(caller.data().config().expires_at(), Some(own) if own < expires_at) {
+        return Err(anyhow!(
+            "lunatic::process::config_set_expires_after: Permissions can't outlive the process' own"
+        ));
+    }
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_expires_after: Config ID doesn't exist")?
+        .set_expires_at(Some(expires_at));
+    Ok(())
+}
+
 // Spawns a new process using the passed in function inside a module as the entry point.
 //
 // If **link** is not 0, it will link the child and parent processes. The value of the **link**
@@ -899,6 +1050,42 @@ where
     })
 }
 
+// Terminates the calling process with an exit code and a reason. If the entry process exits this
+// way, the code becomes the exit code of the lunatic CLI and the reason is logged. Codes outside
+// of 0..=255 don't fit into an exit status and make the CLI exit with 1.
+//
+// An exit code of 0 is a normal exit, any other code a failure that is reported to links.
+// If `reason_len` is 0, no reason is attached.
+//
+// Traps:
+// * Always, to terminate the process.
+// * If the reason is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn exit<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    code: u32,
+    reason_ptr: u32,
+    reason_len: u32,
+) -> Result<()> {
+    let reason = match reason_len {
+        0 => None,
+        _ => {
+            let memory = get_memory(&mut caller)?;
+            let reason = memory
+                .data(&caller)
+                .get(reason_ptr as usize..(reason_ptr + reason_len) as usize)
+                .or_trap("lunatic::process::exit")?;
+            let reason = std::str::from_utf8(reason).or_trap("lunatic::process::exit")?;
+            Some(reason.to_string())
+        }
+    };
+    Err(ProcessExit {
+        code: code as i32,
+        reason,
+    }
+    .into())
+}
+
 // lunatic::process::sleep_ms(millis: u64)
 //
 // Suspend process for `millis`.
@@ -927,6 +1114,22 @@ fn die_when_link_dies<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, tr
         .expect("The signal is sent to itself and the receiver must exist at this point");
 }
 
+// Defines if this process is notified when the node shuts down.
+//
+// If `notify != 0` a shutdown message is put into the mailbox when the node starts shutting down,
+// `lunatic::message::receive` returns 3 for it. The process then has until the end of the node's
+// grace period to finish before it's killed.
+//
+// Newly spawned processes aren't notified, they keep running until they are killed.
+fn notify_shutdown<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, notify: u32) {
+    caller
+        .data_mut()
+        .signal_mailbox()
+        .0
+        .send(Signal::NotifyShutdown(notify != 0))
+        .expect("The signal is sent to itself and the receiver must exist at this point");
+}
+
 // Returns ID of the process currently running
 fn process_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().id()
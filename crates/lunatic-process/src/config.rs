@@ -21,4 +21,16 @@ pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
     fn get_max_fuel(&self) -> Option<u64>;
     fn set_max_memory(&mut self, max_memory: usize);
     fn get_max_memory(&self) -> usize;
+
+    /// Returns `false` if processes using this configuration are not allowed to import host
+    /// functions from `namespace`. Checked when a module is instantiated.
+    fn can_import(&self, _namespace: &str) -> bool {
+        true
+    }
+
+    /// Returns `false` if the configuration holds state that is lost when it's serialized, so it
+    /// can't be used to spawn processes on other nodes.
+    fn can_spawn_remotely(&self) -> bool {
+        true
+    }
 }
@@ -1,10 +1,15 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use dashmap::DashMap;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tokio::sync::RwLock;
 
 use crate::{Process, Signal};
 
@@ -13,9 +18,12 @@ pub trait Environment: Send + Sync {
     fn id(&self) -> u64;
     fn get_next_process_id(&self) -> u64;
     fn get_process(&self, id: u64) -> Option<Arc<dyn Process>>;
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>);
+    /// Adds a process to the environment, fails if the environment reached its process limit.
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>) -> Result<()>;
     fn remove_process(&self, id: u64);
     fn process_count(&self) -> usize;
+    /// Checks the process limit early, before any work is done to spawn a process. The slot is
+    /// only taken by [`Environment::add_process`].
     async fn can_spawn_next_process(&self) -> Result<Option<()>>;
     fn send(&self, id: u64, signal: Signal);
 }
@@ -33,6 +41,11 @@ pub struct LunaticEnvironment {
     environment_id: u64,
     next_process_id: Arc<AtomicU64>,
     processes: Arc<DashMap<u64, Arc<dyn Process>>>,
+    // Number of processes in `processes`, updated atomically to enforce `max_processes`
+    process_slots: Arc<AtomicUsize>,
+    max_processes: Option<usize>,
+    // Named processes, shared by all processes of the environment
+    registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
 }
 
 impl LunaticEnvironment {
@@ -40,7 +53,79 @@ impl LunaticEnvironment {
         Self {
             environment_id: id,
             processes: Arc::new(DashMap::new()),
+            process_slots: Arc::new(AtomicUsize::new(0)),
             next_process_id: Arc::new(AtomicU64::new(1)),
+            max_processes: None,
+            registry: Default::default(),
+        }
+    }
+
+    /// Limit the number of processes that can run at the same time in this environment.
+    pub fn with_max_processes(mut self, max_processes: Option<usize>) -> Self {
+        self.max_processes = max_processes;
+        self
+    }
+
+    pub fn registry(&self) -> &Arc<RwLock<HashMap<String, (u64, u64)>>> {
+        &self.registry
+    }
+
+    /// Registry names of the running processes on node `node_id`.
+    pub async fn process_names(&self, node_id: u64) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .registry
+            .read()
+            .await
+            .iter()
+            .filter(|(_, (node, process))| *node == node_id && self.processes.contains_key(process))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn limit_reached(&self, max_processes: usize) -> anyhow::Error {
+        anyhow!(
+            "Environment {} reached its limit of {max_processes} processes",
+            self.environment_id
+        )
+    }
+
+    /// Kills all processes in the environment.
+    pub fn kill_all(&self) {
+        for process in self.processes.iter() {
+            process.send(Signal::Kill);
+        }
+    }
+
+    /// Sends a shutdown request to all processes in the environment.
+    pub fn request_shutdown(&self) {
+        for process in self.processes.iter() {
+            process.send(Signal::Shutdown);
+        }
+    }
+
+    /// Asks all processes in the environment to shut down and kills the ones still running after
+    /// `grace_period`. Resolves once the environment is empty, returns how many processes were
+    /// killed.
+    pub async fn shutdown(&self, grace_period: Duration) -> usize {
+        self.request_shutdown();
+        if tokio::time::timeout(grace_period, self.empty())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let remaining = self.process_count();
+        self.kill_all();
+        self.empty().await;
+        remaining
+    }
+
+    // Resolves once all processes in the environment finished.
+    async fn empty(&self) {
+        while !self.processes.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
     }
 }
@@ -51,7 +136,18 @@ impl Environment for LunaticEnvironment {
         self.processes.get(&id).map(|x| x.clone())
     }
 
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>) {
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>) -> Result<()> {
+        let max_processes = self.max_processes;
+        self.process_slots
+            .fetch_update(
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                |slots| match max_processes {
+                    Some(max_processes) if slots >= max_processes => None,
+                    _ => Some(slots + 1),
+                },
+            )
+            .map_err(|_| self.limit_reached(max_processes.unwrap_or_default()))?;
         self.processes.insert(id, proc);
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
@@ -63,10 +159,13 @@ impl Environment for LunaticEnvironment {
             self.processes.len() as f64,
             &labels
         );
+        Ok(())
     }
 
     fn remove_process(&self, id: u64) {
-        self.processes.remove(&id);
+        if self.processes.remove(&id).is_some() {
+            self.process_slots.fetch_sub(1, Ordering::AcqRel);
+        }
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
         #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -98,21 +197,94 @@ impl Environment for LunaticEnvironment {
     }
 
     async fn can_spawn_next_process(&self) -> Result<Option<()>> {
-        // Don't impose any limits to process spawning
-        Ok(Some(()))
+        match self.max_processes {
+            Some(max_processes) if self.process_slots.load(Ordering::Acquire) >= max_processes => {
+                Err(self.limit_reached(max_processes))
+            }
+            _ => Ok(Some(())),
+        }
     }
 }
 
 #[derive(Clone, Default)]
 pub struct LunaticEnvironments {
     envs: Arc<DashMap<u64, Arc<LunaticEnvironment>>>,
+    max_processes: Option<usize>,
+}
+
+impl LunaticEnvironments {
+    /// Limit the number of processes in every environment created from now on.
+    pub fn set_max_processes(&mut self, max_processes: Option<usize>) {
+        self.max_processes = max_processes;
+    }
+
+    /// Removes the environment `id`, its processes keep running.
+    pub fn remove(&self, id: u64) {
+        self.envs.remove(&id);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
+    }
+
+    /// Kills all processes in all environments.
+    pub fn kill_all(&self) {
+        for env in self.envs.iter() {
+            env.kill_all();
+        }
+    }
+
+    /// Asks all processes to shut down and waits up to `grace_period` for them to finish. The
+    /// processes still running after it are killed, returns how many of them there were.
+    pub async fn shutdown(&self, grace_period: Duration) -> usize {
+        for env in self.envs.iter() {
+            env.request_shutdown();
+        }
+        tokio::time::timeout(grace_period, async {
+            while self.process_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .ok();
+        let remaining = self.process_count();
+        if remaining > 0 {
+            self.kill_all();
+        }
+        remaining
+    }
+
+    /// Number of processes running in all environments.
+    pub fn process_count(&self) -> usize {
+        self.envs.iter().map(|env| env.process_count()).sum()
+    }
+
+    /// Number of processes running in each environment, by environment id.
+    pub fn process_counts(&self) -> HashMap<u64, usize> {
+        self.envs
+            .iter()
+            .map(|env| (*env.key(), env.process_count()))
+            .collect()
+    }
+
+    /// Registry names of the running processes on node `node_id`, by environment id.
+    pub async fn process_names(&self, node_id: u64) -> HashMap<u64, Vec<String>> {
+        // Don't hold on to the map while waiting for the registries
+        let envs: Vec<_> = self.envs.iter().map(|env| env.clone()).collect();
+        let mut names = HashMap::new();
+        for env in envs {
+            let env_names = env.process_names(node_id).await;
+            if !env_names.is_empty() {
+                names.insert(env.id(), env_names);
+            }
+        }
+        names
+    }
 }
 
 #[async_trait]
 impl Environments for LunaticEnvironments {
     type Env = LunaticEnvironment;
     async fn create(&self, id: u64) -> Arc<Self::Env> {
-        let env = Arc::new(LunaticEnvironment::new(id));
+        let env = Arc::new(LunaticEnvironment::new(id).with_max_processes(self.max_processes));
         self.envs.insert(id, env.clone());
         #[cfg(feature = "metrics")]
         metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
@@ -123,3 +295,56 @@ impl Environments for LunaticEnvironments {
         self.envs.get(&id).map(|e| e.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::WasmProcess;
+
+    fn process(id: u64) -> Arc<dyn Process> {
+        Arc::new(WasmProcess::new(id, unbounded_channel().0))
+    }
+
+    #[tokio::test]
+    async fn process_limit() {
+        let env = LunaticEnvironment::new(1).with_max_processes(Some(2));
+        env.add_process(1, process(1)).unwrap();
+        assert!(env.can_spawn_next_process().await.is_ok());
+        env.add_process(2, process(2)).unwrap();
+        assert!(env.can_spawn_next_process().await.is_err());
+        assert!(env.add_process(3, process(3)).is_err());
+        assert!(env.get_process(3).is_none());
+
+        // Removing a process frees its slot, removing it again doesn't
+        env.remove_process(1);
+        env.remove_process(1);
+        env.add_process(3, process(3)).unwrap();
+        assert!(env.add_process(4, process(4)).is_err());
+        assert_eq!(env.process_count(), 2);
+    }
+
+    #[test]
+    fn concurrent_spawns_respect_the_process_limit() {
+        let env = LunaticEnvironment::new(1).with_max_processes(Some(10));
+        let added: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|thread| {
+                    let env = &env;
+                    scope.spawn(move || {
+                        (0..10)
+                            .filter(|i| {
+                                let id = thread * 10 + i;
+                                env.add_process(id, process(id)).is_ok()
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(added, 10);
+        assert_eq!(env.process_count(), 10);
+    }
+}
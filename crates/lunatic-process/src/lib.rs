@@ -2,9 +2,12 @@ pub mod config;
 pub mod env;
 pub mod mailbox;
 pub mod message;
+pub mod profiler;
 pub mod runtimes;
+pub mod scheduler;
 pub mod state;
 pub mod wasm;
+pub mod watchdog;
 
 use std::{collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc};
 
@@ -24,6 +27,16 @@ use tokio::{
 
 use crate::{mailbox::MessageMailbox, message::Message};
 
+tokio::task_local! {
+    // ID of the process running on the current task
+    pub(crate) static CURRENT_PROCESS_ID: u64;
+}
+
+/// Returns the ID of the process running on the current task, if any.
+pub fn current_process_id() -> Option<u64> {
+    CURRENT_PROCESS_ID.try_with(|id| *id).ok()
+}
+
 #[cfg(feature = "metrics")]
 pub fn describe_metrics() {
     use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
@@ -141,6 +154,10 @@ pub enum Signal {
     Monitor(Arc<dyn Process>),
     StopMonitoring { process_id: u64 },
     ProcessDied(u64),
+    // Sent to all processes when the node stops. It's turned into a `Shutdown` message if the
+    // process asked to be notified, giving it a chance to finish before it's killed.
+    Shutdown,
+    NotifyShutdown(bool),
 }
 
 impl Debug for Signal {
@@ -155,19 +172,67 @@ impl Debug for Signal {
             Self::Monitor(p) => write!(f, "Monitor {}", p.id()),
             Self::StopMonitoring { process_id } => write!(f, "UnMonitor {process_id}"),
             Self::ProcessDied(_) => write!(f, "ProcessDied"),
+            Self::Shutdown => write!(f, "Shutdown"),
+            Self::NotifyShutdown(_) => write!(f, "NotifyShutdown"),
         }
     }
 }
 
 // The reason of a process' death
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum DeathReason {
     // Process finished normaly.
     Normal,
-    Failure,
+    // Process failed, e.g. panicked or was killed. Contains the failure reason.
+    Failure(String),
     NoProcess,
 }
 
+impl DeathReason {
+    // Text delivered to linked processes that receive the death as a message.
+    fn link_died_reason(self) -> String {
+        match self {
+            DeathReason::Normal => String::new(),
+            DeathReason::Failure(reason) => reason,
+            DeathReason::NoProcess => "Process doesn't exist".to_string(),
+        }
+    }
+}
+
+/// An explicit exit of a process with an exit code and an optional reason.
+///
+/// An exit of the entry process becomes the exit code of the lunatic CLI.
+#[derive(Clone, Debug)]
+pub struct ProcessExit {
+    pub code: i32,
+    pub reason: Option<String>,
+}
+
+impl ProcessExit {
+    /// Returns the exit status to report to the operating system.
+    ///
+    /// Only the lowest 8 bits of an exit status are kept, so codes outside of `0..=255` are
+    /// reported as 1. Otherwise a failure like 256 would look like a success.
+    pub fn status(&self) -> i32 {
+        match self.code {
+            0..=255 => self.code,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process exited with code {}", self.code)?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProcessExit {}
+
 /// The reason of a process finishing
 pub enum Finished<T> {
     /// This just means that the process finished without external interaction.
@@ -294,6 +359,8 @@ where
     // If the value is set to false, instead of dying too the process will receive a message about
     // the linked process' death.
     let mut die_when_link_dies = true;
+    // Shutdown requests are only turned into messages for processes that expect them
+    let mut notify_shutdown = false;
     // Process linked to this one
     let mut links = HashMap::new();
     // Processes monitoring this one
@@ -355,13 +422,13 @@ where
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
                         match reason {
-                            DeathReason::Failure | DeathReason::NoProcess => {
+                            DeathReason::Failure(_) | DeathReason::NoProcess => {
                                 if die_when_link_dies {
                                     // Even this was not a **kill** signal it has the same effect on
                                     // this process and should be propagated as such.
                                     break Finished::KillSignal
                                 } else {
-                                    let message = Message::LinkDied(tag);
+                                    let message = Message::LinkDied(tag, reason.link_died_reason());
 
                                     #[cfg(feature = "metrics")]
                                     metrics::increment_counter!("lunatic.process.messages.send", &labels);
@@ -387,6 +454,14 @@ where
                     Ok(Signal::ProcessDied(id)) => {
                         message_mailbox.push(Message::ProcessDied(id));
                     }
+                    Ok(Signal::NotifyShutdown(value)) => notify_shutdown = value,
+                    // Let the process know that the node is stopping, if it asked for it.
+                    // Otherwise it keeps running until it's killed.
+                    Ok(Signal::Shutdown) => {
+                        if notify_shutdown {
+                            message_mailbox.push(Message::Shutdown);
+                        }
+                    }
                     Err(_) => {
                         debug_assert!(has_sender);
                         has_sender = false;
@@ -403,29 +478,40 @@ where
     let result = match result {
         Finished::Normal(result) => {
             let result: ExecutionResult<_> = result.into();
-
-            if let Some(failure) = result.failure() {
-                let registry = result.state().registry().read().await;
-                let name = registry
-                    .iter()
-                    .filter(|(_, (_, process_id))| process_id == &id)
-                    .map(|(name, _)| name.splitn(4, '/').last().unwrap_or(name.as_str()))
-                    .collect::<NameOrID>()
-                    .or_id(id);
-                warn!(
-                    "Process {} failed, notifying: {} links {}",
-                    name,
-                    links.len(),
-                    // If the log level is WARN instruct user how to display the stacktrace
-                    if !log_enabled!(Level::Debug) {
-                        "\n\t\t\t    (Set ENV variable `RUST_LOG=lunatic=debug` to show stacktrace)"
-                    } else {
-                        ""
-                    }
-                );
+            // Exits with a non-zero code fail with their code and reason
+            let failure = match &result.result {
+                ResultValue::Exited(exit) => Some(exit.to_string()),
+                _ => result.failure().map(str::to_string),
+            };
+
+            if let Some(failure) = failure {
+                {
+                    let registry = result.state().registry().read().await;
+                    let name = registry
+                        .iter()
+                        .filter(|(_, (_, process_id))| process_id == &id)
+                        .map(|(name, _)| name.splitn(4, '/').last().unwrap_or(name.as_str()))
+                        .collect::<NameOrID>()
+                        .or_id(id);
+                    warn!(
+                        "Process {} failed, notifying: {} links {}",
+                        name,
+                        links.len(),
+                        // If the log level is WARN instruct user how to display the stacktrace
+                        if !log_enabled!(Level::Debug) {
+                            "\n\t\t\t    (Set ENV variable `RUST_LOG=lunatic=debug` to show stacktrace)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
                 debug!("{}", failure);
 
-                Err(anyhow!(failure.to_string()))
+                match result.result {
+                    // Keep the exit code available to whoever awaits the process
+                    ResultValue::Exited(exit) => Err(exit.into()),
+                    _ => Err(anyhow!(failure)),
+                }
             } else {
                 Ok(result.into_state())
             }
@@ -441,14 +527,14 @@ where
         }
     };
 
-    let reason = match result {
+    let reason = match &result {
         Ok(_) => DeathReason::Normal,
-        Err(_) => DeathReason::Failure,
+        Err(error) => DeathReason::Failure(error.to_string()),
     };
 
     // Notify all links that we finished
     for (proc, tag) in links.values() {
-        proc.send(Signal::LinkDied(id, *tag, reason));
+        proc.send(Signal::LinkDied(id, *tag, reason.clone()));
     }
 
     // Notify all monitoring processes we died
@@ -486,7 +572,10 @@ where
     };
     let fut = func(process.clone(), message_mailbox.clone());
     let signal_mailbox = Arc::new(Mutex::new(signal_mailbox));
-    let join = tokio::task::spawn(new(fut, id, env.clone(), signal_mailbox, message_mailbox));
+    let join = tokio::task::spawn(CURRENT_PROCESS_ID.scope(
+        id,
+        new(fut, id, env.clone(), signal_mailbox, message_mailbox),
+    ));
     (join, process)
 }
 
@@ -566,4 +655,22 @@ pub enum ResultValue {
     Ok,
     Failed(String),
     SpawnError(String),
+    // Exited with a non-zero exit code
+    Exited(ProcessExit),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_status_keeps_failures() {
+        let status = |code| ProcessExit { code, reason: None }.status();
+        assert_eq!(status(0), 0);
+        assert_eq!(status(78), 78);
+        assert_eq!(status(255), 255);
+        assert_eq!(status(256), 1);
+        assert_eq!(status(512), 1);
+        assert_eq!(status(-1), 1);
+    }
 }
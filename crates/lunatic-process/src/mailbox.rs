@@ -27,6 +27,8 @@ struct InnerMessageMailbox {
     tags: Option<Vec<i64>>,
     found: Option<Message>,
     messages: VecDeque<Message>,
+    // Number of messages the process took out of the mailbox
+    received: u64,
 }
 
 impl MessageMailbox {
@@ -59,11 +61,13 @@ impl MessageMailbox {
                 });
                 // If message matching tags is found, remove it.
                 if let Some(index) = index {
+                    mailbox.received += 1;
                     return mailbox.messages.remove(index).expect("must exist");
                 }
             } else {
                 // If not looking for a specific tags try to pop the first message available.
                 if let Some(message) = mailbox.messages.pop_front() {
+                    mailbox.received += 1;
                     return message;
                 }
             }
@@ -139,6 +143,14 @@ impl MessageMailbox {
         mailbox.messages.push_back(message);
     }
 
+    /// Returns the number of messages the process received from the mailbox
+    pub fn received(&self) -> u64 {
+        self.inner
+            .lock()
+            .expect("only accessed by one process")
+            .received
+    }
+
     /// Returns the number of messages currently available
     pub fn len(&self) -> usize {
         let mailbox = self.inner.lock().expect("only accessed by one process");
@@ -160,6 +172,7 @@ impl Future for &MessageMailbox {
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut mailbox = self.inner.lock().expect("only accessed by one process");
         if let Some(message) = mailbox.found.take() {
+            mailbox.received += 1;
             Poll::Ready(message)
         } else {
             mailbox.waker = Some(cx.waker().clone());
@@ -181,11 +194,11 @@ mod tests {
     #[tokio::test]
     async fn no_tags_signal_message() {
         let mailbox = MessageMailbox::default();
-        let message = Message::LinkDied(None);
+        let message = Message::LinkDied(None, String::new());
         mailbox.push(message);
         let result = mailbox.pop(None).await;
         match result {
-            Message::LinkDied(None) => (),
+            Message::LinkDied(None, _) => (),
             _ => panic!("Wrong message received"),
         }
     }
@@ -194,7 +207,7 @@ mod tests {
     async fn tag_signal_message() {
         let mailbox = MessageMailbox::default();
         let tag = 1337;
-        let message = Message::LinkDied(Some(tag));
+        let message = Message::LinkDied(Some(tag), String::new());
         mailbox.push(message);
         let message = mailbox.pop(None).await;
         assert_eq!(message.tag(), Some(tag));
@@ -208,11 +221,11 @@ mod tests {
         let tag3 = 3;
         let tag4 = 4;
         let tag5 = 5;
-        mailbox.push(Message::LinkDied(Some(tag1)));
-        mailbox.push(Message::LinkDied(Some(tag2)));
-        mailbox.push(Message::LinkDied(Some(tag3)));
-        mailbox.push(Message::LinkDied(Some(tag4)));
-        mailbox.push(Message::LinkDied(Some(tag5)));
+        mailbox.push(Message::LinkDied(Some(tag1), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag2), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag3), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag4), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag5), String::new()));
         let message = mailbox.pop(Some(&[tag2])).await;
         assert_eq!(message.tag(), Some(tag2));
         let message = mailbox.pop(Some(&[tag1])).await;
@@ -234,11 +247,11 @@ mod tests {
         let tag3 = 3;
         let tag4 = 4;
         let tag5 = 5;
-        mailbox.push(Message::LinkDied(Some(tag1)));
-        mailbox.push(Message::LinkDied(Some(tag2)));
-        mailbox.push(Message::LinkDied(Some(tag3)));
-        mailbox.push(Message::LinkDied(Some(tag4)));
-        mailbox.push(Message::LinkDied(Some(tag5)));
+        mailbox.push(Message::LinkDied(Some(tag1), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag2), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag3), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag4), String::new()));
+        mailbox.push(Message::LinkDied(Some(tag5), String::new()));
         let message = mailbox.pop(Some(&[tag2, tag1, tag3])).await;
         assert_eq!(message.tag(), Some(tag1));
         let message = mailbox.pop(Some(&[tag2, tag1, tag3])).await;
@@ -279,7 +292,7 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message to the mailbox will call the waker
-        mailbox.push(Message::LinkDied(tags));
+        mailbox.push(Message::LinkDied(tags, String::new()));
         assert!(*waker_ref.0.lock().unwrap());
         // Next poll will return the value
         let result = fut.as_mut().poll(&mut context);
@@ -302,15 +315,15 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message with the `None` tags should not trigger the waker
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(Message::LinkDied(None, String::new()));
         assert!(!*waker_ref.0.lock().unwrap());
         // Next poll will still not have the value with the tags 1337
         let result = fut.as_mut().poll(&mut context);
         assert!(result.is_pending());
         // Pushing another None in the meantime should not remove the waker
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(Message::LinkDied(None, String::new()));
         // Pushing a message with tags 1337 should trigger the waker
-        mailbox.push(Message::LinkDied(Some(1337)));
+        mailbox.push(Message::LinkDied(Some(1337), String::new()));
         assert!(*waker_ref.0.lock().unwrap());
         // Next poll will have the message ready
         let result = fut.as_mut().poll(&mut context);
@@ -332,7 +345,7 @@ mod tests {
         assert!(result.is_pending());
         assert!(!*waker_ref.0.lock().unwrap());
         // Pushing a message with the `None` tags should call the waker()
-        mailbox.push(Message::LinkDied(None));
+        mailbox.push(Message::LinkDied(None, String::new()));
         assert!(*waker_ref.0.lock().unwrap());
         // Dropping the future will cancel it
         drop(fut);
@@ -346,8 +359,21 @@ mod tests {
         tokio::pin!(fut);
         let result = fut.poll(&mut context);
         match result {
-            Poll::Ready(Message::LinkDied(tags)) => assert_eq!(tags, None),
+            Poll::Ready(Message::LinkDied(tags, _)) => assert_eq!(tags, None),
             _ => panic!("Unexpected message"),
         }
     }
+
+    #[tokio::test]
+    async fn received_counts_taken_messages() {
+        let mailbox = MessageMailbox::default();
+        mailbox.push(Message::LinkDied(Some(1), String::new()));
+        mailbox.push(Message::LinkDied(Some(2), String::new()));
+        // Messages waiting in the queue aren't received yet
+        assert_eq!(mailbox.received(), 0);
+        mailbox.pop(Some(&[2])).await;
+        assert_eq!(mailbox.received(), 1);
+        mailbox.pop(None).await;
+        assert_eq!(mailbox.received(), 2);
+    }
 }
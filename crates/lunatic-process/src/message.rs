@@ -11,8 +11,7 @@ use std::{
     sync::Arc,
 };
 
-use lunatic_networking_api::{TcpConnection, TlsConnection};
-use tokio::net::UdpSocket;
+use lunatic_networking_api::{TcpConnection, TlsConnection, UdpSocketResource};
 
 use crate::runtimes::wasmtime::WasmtimeCompiledModule;
 
@@ -22,30 +21,41 @@ pub type Resource = dyn Any + Send + Sync;
 ///
 /// A [`Message`] has 2 variants:
 /// * Data - Regular message containing a tag, buffer and resources.
-/// * LinkDied - A `LinkDied` signal that was turned into a message.
+/// * LinkDied - A `LinkDied` signal that was turned into a message, with the failure reason of
+///   the linked process.
 ///
 /// [0]: crate::Signal
 #[derive(Debug)]
 pub enum Message {
     Data(DataMessage),
-    LinkDied(Option<i64>),
+    LinkDied(Option<i64>, String),
     ProcessDied(u64),
+    Shutdown,
 }
 
 impl Message {
     pub fn tag(&self) -> Option<i64> {
         match self {
             Message::Data(message) => message.tag,
-            Message::LinkDied(tag) => *tag,
+            Message::LinkDied(tag, _) => *tag,
             Message::ProcessDied(_) => None,
+            Message::Shutdown => None,
         }
     }
 
     pub fn process_id(&self) -> Option<u64> {
         match self {
             Message::Data(_) => None,
-            Message::LinkDied(_) => None,
+            Message::LinkDied(..) => None,
             Message::ProcessDied(process_id) => Some(*process_id),
+            Message::Shutdown => None,
+        }
+    }
+
+    pub fn link_died_reason(&self) -> Option<&str> {
+        match self {
+            Message::LinkDied(_, reason) => Some(reason),
+            _ => None,
         }
     }
 
@@ -53,10 +63,10 @@ impl Message {
     pub fn write_metrics(&self) {
         match self {
             Message::Data(message) => message.write_metrics(),
-            Message::LinkDied(_) => {
+            Message::LinkDied(..) => {
                 metrics::increment_counter!("lunatic.process.messages.link_died.count");
             }
-            Message::ProcessDied(_) => {}
+            Message::ProcessDied(_) | Message::Shutdown => {}
         }
     }
 }
@@ -113,6 +123,14 @@ impl DataMessage {
         self.take_downcast(index)
     }
 
+    /// Takes a process configuration from the message, but preserves the indexes of all others.
+    ///
+    /// If the index is out of bound or the resource is not a configuration the function will
+    /// return None.
+    pub fn take_config<C: Send + Sync + 'static>(&mut self, index: usize) -> Option<Arc<C>> {
+        self.take_downcast(index)
+    }
+
     /// Takes a TCP stream from the message, but preserves the indexes of all others.
     ///
     /// If the index is out of bound or the resource is not a tcp stream the function will return
@@ -125,7 +143,7 @@ impl DataMessage {
     ///
     /// If the index is out of bound or the resource is not a tcp stream the function will return
     /// None.
-    pub fn take_udp_socket(&mut self, index: usize) -> Option<Arc<UdpSocket>> {
+    pub fn take_udp_socket(&mut self, index: usize) -> Option<Arc<UdpSocketResource>> {
         self.take_downcast(index)
     }
 
@@ -0,0 +1,265 @@
+//! Per-process resource usage, collected when profiling is enabled on the runtime.
+//!
+//! The [`Profiler`] keeps a [`ProcessProfile`] for every process spawned through
+//! [`spawn_wasm`](crate::wasm::spawn_wasm). A report also includes processes that are still
+//! running, with the usage up to that point. Of the finished processes only the
+//! [`MAX_FINISHED`] hottest are kept, so that long running nodes don't grow without bound.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::mailbox::MessageMailbox;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProcessProfile {
+    pub environment_id: u64,
+    pub process_id: u64,
+    /// Function the process was spawned with
+    pub function: String,
+    /// Fuel consumed, `None` if the process was killed or is still running
+    pub fuel: Option<u64>,
+    pub wall_time_ms: f64,
+    /// Messages received by the process
+    pub messages: u64,
+    /// Processes spawned by the process
+    pub spawns: u64,
+}
+
+impl ProcessProfile {
+    /// Orders profiles by fuel consumed, then by wall time.
+    pub fn cmp_hotness(&self, other: &Self) -> Ordering {
+        self.fuel
+            .cmp(&other.fuel)
+            .then(self.wall_time_ms.total_cmp(&other.wall_time_ms))
+    }
+}
+
+/// Number of finished processes kept for the report.
+pub const MAX_FINISHED: usize = 1000;
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Profiles of finished and running processes, the hottest first
+    pub processes: Vec<ProcessProfile>,
+    /// Number of finished processes left out of the report
+    pub omitted: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    running: DashMap<(u64, u64), RunningProcess>,
+    finished: Mutex<Finished>,
+}
+
+#[derive(Default)]
+struct Finished {
+    // Min-heap, the coolest kept process is the first one to be replaced
+    profiles: BinaryHeap<Reverse<ByHotness>>,
+    omitted: u64,
+}
+
+impl Finished {
+    fn push(&mut self, profile: ProcessProfile) {
+        if self.profiles.len() < MAX_FINISHED {
+            self.profiles.push(Reverse(ByHotness(profile)));
+            return;
+        }
+        self.omitted += 1;
+        if let Some(mut coolest) = self.profiles.peek_mut() {
+            if profile.cmp_hotness(&coolest.0 .0).is_gt() {
+                *coolest = Reverse(ByHotness(profile));
+            }
+        }
+    }
+}
+
+struct ByHotness(ProcessProfile);
+
+impl Ord for ByHotness {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp_hotness(&other.0)
+    }
+}
+
+impl PartialOrd for ByHotness {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ByHotness {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for ByHotness {}
+
+struct RunningProcess {
+    function: String,
+    started: Instant,
+    message_mailbox: MessageMailbox,
+    spawns: u64,
+}
+
+impl RunningProcess {
+    fn profile(
+        &self,
+        (environment_id, process_id): (u64, u64),
+        fuel: Option<u64>,
+    ) -> ProcessProfile {
+        ProcessProfile {
+            environment_id,
+            process_id,
+            function: self.function.clone(),
+            fuel,
+            wall_time_ms: self.started.elapsed().as_secs_f64() * 1000.0,
+            messages: self.message_mailbox.received(),
+            spawns: self.spawns,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Starts profiling a process. The profile is finished when the returned guard is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        environment_id: u64,
+        process_id: u64,
+        function: &str,
+        message_mailbox: MessageMailbox,
+    ) -> ProfileGuard {
+        let key = (environment_id, process_id);
+        self.running.insert(
+            key,
+            RunningProcess {
+                function: function.to_string(),
+                started: Instant::now(),
+                message_mailbox,
+                spawns: 0,
+            },
+        );
+        ProfileGuard {
+            profiler: self.clone(),
+            key,
+            fuel: None,
+        }
+    }
+
+    /// Counts a spawn towards the process `process_id`.
+    pub fn spawned_by(&self, environment_id: u64, process_id: u64) {
+        if let Some(mut parent) = self.running.get_mut(&(environment_id, process_id)) {
+            parent.spawns += 1;
+        }
+    }
+
+    /// Profiles of the running processes and the hottest finished ones.
+    pub fn report(&self) -> Report {
+        let (mut processes, omitted) = {
+            let finished = self.finished.lock().unwrap();
+            let processes: Vec<_> = finished
+                .profiles
+                .iter()
+                .map(|profile| profile.0 .0.clone())
+                .collect();
+            (processes, finished.omitted)
+        };
+        processes.extend(
+            self.running
+                .iter()
+                .map(|running| running.profile(*running.key(), None)),
+        );
+        processes.sort_by(|a, b| b.cmp_hotness(a));
+        Report { processes, omitted }
+    }
+}
+
+/// Profile of a running process, see [`Profiler::start`].
+pub struct ProfileGuard {
+    profiler: Arc<Profiler>,
+    key: (u64, u64),
+    fuel: Option<u64>,
+}
+
+impl ProfileGuard {
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+}
+
+impl Drop for ProfileGuard {
+    fn drop(&mut self) {
+        if let Some((key, running)) = self.profiler.running.remove(&self.key) {
+            let profile = running.profile(key, self.fuel);
+            self.profiler.finished.lock().unwrap().push(profile);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    #[tokio::test]
+    async fn profiles_running_and_finished_processes() {
+        let profiler = Profiler::new();
+        let parent_mailbox = MessageMailbox::default();
+        let parent = profiler.start(1, 1, "main", parent_mailbox.clone());
+
+        let child_mailbox = MessageMailbox::default();
+        let mut child = profiler.start(1, 2, "worker", child_mailbox.clone());
+        profiler.spawned_by(1, 1);
+        child_mailbox.push(Message::LinkDied(None, String::new()));
+        child_mailbox.push(Message::LinkDied(None, String::new()));
+        child_mailbox.pop(None).await;
+        child.set_fuel(Some(42));
+        drop(child);
+
+        let report = profiler.report();
+        assert_eq!(report.omitted, 0);
+        let [worker, main] = &report.processes[..] else {
+            panic!("expected 2 profiles, got {:?}", report.processes);
+        };
+        assert_eq!(worker.function, "worker");
+        assert_eq!(worker.fuel, Some(42));
+        // Only the message taken out of the mailbox is counted
+        assert_eq!(worker.messages, 1);
+        assert_eq!(main.function, "main");
+        assert_eq!(main.fuel, None);
+        assert_eq!(main.spawns, 1);
+
+        drop(parent);
+        assert_eq!(profiler.report().processes.len(), 2);
+    }
+
+    #[test]
+    fn keeps_the_hottest_finished_processes() {
+        let profiler = Profiler::new();
+        for id in 0..MAX_FINISHED as u64 + 10 {
+            // Hotter and cooler processes interleaved
+            let fuel = if id % 2 == 0 { id } else { u64::MAX - id };
+            let mut guard = profiler.start(1, id, "worker", MessageMailbox::default());
+            guard.set_fuel(Some(fuel));
+        }
+
+        let report = profiler.report();
+        assert_eq!(report.processes.len(), MAX_FINISHED);
+        assert_eq!(report.omitted, 10);
+        assert_eq!(report.processes[0].fuel, Some(u64::MAX - 1));
+        // The 10 coolest processes were left out
+        let coolest = report.processes.last().unwrap();
+        assert_eq!(coolest.fuel, Some(20));
+    }
+}
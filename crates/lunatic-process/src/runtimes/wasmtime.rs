@@ -1,12 +1,21 @@
-use std::sync::Arc;
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
 use wasmtime::ResourceLimiter;
 
 use crate::{
     config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
+    profiler::{ProfileGuard, Profiler},
+    scheduler::Scheduler,
     state::ProcessState,
-    ExecutionResult, ResultValue,
+    watchdog::Watchdog,
+    ExecutionResult, ProcessExit, ResultValue,
 };
 
 use super::RawWasm;
@@ -14,12 +23,57 @@ use super::RawWasm;
 #[derive(Clone)]
 pub struct WasmtimeRuntime {
     engine: wasmtime::Engine,
+    watchdog: Option<Arc<Watchdog>>,
+    profiler: Option<Arc<Profiler>>,
+    scheduler: Option<Arc<Scheduler>>,
+    // Directory of precompiled modules and the fingerprint of the engine settings
+    module_cache: Option<(PathBuf, Vec<u8>)>,
 }
 
 impl WasmtimeRuntime {
     pub fn new(config: &wasmtime::Config) -> Result<Self> {
         let engine = wasmtime::Engine::new(config)?;
-        Ok(Self { engine })
+        Ok(Self {
+            engine,
+            watchdog: None,
+            profiler: None,
+            scheduler: None,
+            module_cache: None,
+        })
+    }
+
+    /// Record the resource usage of all processes spawned with this runtime.
+    pub fn set_profiler(&mut self, profiler: Arc<Profiler>) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Arc<Profiler>> {
+        self.profiler.as_ref()
+    }
+
+    /// Share execution time between the environments of all processes spawned with this runtime.
+    pub fn set_scheduler(&mut self, scheduler: Arc<Scheduler>) {
+        self.scheduler = Some(scheduler);
+    }
+
+    pub fn scheduler(&self) -> Option<&Arc<Scheduler>> {
+        self.scheduler.as_ref()
+    }
+
+    /// Keep precompiled modules in `dir` and reuse them when the same module is compiled again
+    /// with [`compile_module_cached`](Self::compile_module_cached).
+    ///
+    /// The files in `dir` are loaded without validation, so only point this at a directory that
+    /// nobody else can write to.
+    pub fn set_module_cache(&mut self, dir: PathBuf) -> Result<()> {
+        let fingerprint = engine_fingerprint(&self.engine)?;
+        self.module_cache = Some((dir, fingerprint));
+        Ok(())
+    }
+
+    /// Watch host calls of all processes instantiated by this runtime.
+    pub fn set_watchdog(&mut self, watchdog: Arc<Watchdog>) {
+        self.watchdog = Some(watchdog);
     }
 
     /// Compiles a wasm module to machine code and performs type-checking on host functions.
@@ -28,6 +82,29 @@ impl WasmtimeRuntime {
         T: ProcessState,
     {
         let module = wasmtime::Module::new(&self.engine, data.as_slice())?;
+        self.link(data, module)
+    }
+
+    /// Like [`compile_module`](Self::compile_module), but reuses the precompiled module from the
+    /// module cache if one is set.
+    ///
+    /// Each distinct module adds an entry to the cache that is never removed, so this is only
+    /// meant for modules started from the command line and not for modules compiled by guests.
+    pub fn compile_module_cached<T>(&self, data: RawWasm) -> Result<WasmtimeCompiledModule<T>>
+    where
+        T: ProcessState,
+    {
+        let module = match &self.module_cache {
+            Some((dir, fingerprint)) => self.compile_cached(dir, fingerprint, data.as_slice())?,
+            None => wasmtime::Module::new(&self.engine, data.as_slice())?,
+        };
+        self.link(data, module)
+    }
+
+    fn link<T>(&self, data: RawWasm, module: wasmtime::Module) -> Result<WasmtimeCompiledModule<T>>
+    where
+        T: ProcessState,
+    {
         let mut linker = wasmtime::Linker::new(&self.engine);
         // Register host functions to linker.
         <T as ProcessState>::register(&mut linker)?;
@@ -36,6 +113,30 @@ impl WasmtimeRuntime {
         Ok(compiled_module)
     }
 
+    fn compile_cached(
+        &self,
+        dir: &Path,
+        fingerprint: &[u8],
+        data: &[u8],
+    ) -> Result<wasmtime::Module> {
+        let path = cache_entry_path(dir, fingerprint, data);
+        if path.exists() {
+            // Safety: the cache directory is trusted and entries are never modified after they
+            // are moved into place. Entries compiled by a different wasmtime version or with
+            // incompatible engine settings are rejected here and replaced below.
+            match unsafe { wasmtime::Module::deserialize_file(&self.engine, &path) } {
+                Ok(module) => return Ok(module),
+                Err(err) => log::warn!("Ignoring module cache entry {}: {err}", path.display()),
+            }
+        }
+        let module = wasmtime::Module::new(&self.engine, data)?;
+        // Failing to update the cache only costs a recompilation next time
+        if let Err(err) = write_cache_entry(&path, &module) {
+            log::warn!("Failed to cache module at {}: {err}", path.display());
+        }
+        Ok(module)
+    }
+
     pub async fn instantiate<T>(
         &self,
         compiled_module: &WasmtimeCompiledModule<T>,
@@ -44,8 +145,28 @@ impl WasmtimeRuntime {
     where
         T: ProcessState + Send + ResourceLimiter,
     {
+        // Refuse modules importing host functions the process configuration doesn't allow
+        if let Some(import) = compiled_module
+            .imports()
+            .find(|import| !state.config().can_import(import.module()))
+        {
+            return Err(anyhow!(
+                "Process configuration doesn't allow imports from '{}'",
+                import.module()
+            ));
+        }
         let max_fuel = state.config().get_max_fuel();
+        let watched = self
+            .watchdog
+            .as_ref()
+            .map(|watchdog| watchdog.watch(state.id(), state.signal_mailbox().0.clone()));
         let mut store = wasmtime::Store::new(&self.engine, state);
+        if let Some(watched) = watched {
+            store.call_hook(move |_, hook| {
+                watched.on_call_hook(hook);
+                Ok(())
+            });
+        }
         // Set limits of the store
         store.limiter(|state| state);
         // Trap if out of fuel
@@ -65,7 +186,11 @@ impl WasmtimeRuntime {
             .await?;
         // Mark state as initialized
         store.data_mut().initialize();
-        Ok(WasmtimeInstance { store, instance })
+        Ok(WasmtimeInstance {
+            store,
+            instance,
+            profile: None,
+        })
     }
 }
 
@@ -75,6 +200,7 @@ pub struct WasmtimeCompiledModule<T> {
 
 pub struct WasmtimeCompiledModuleInner<T> {
     source: RawWasm,
+    hash: [u8; 32],
     module: wasmtime::Module,
     instance_pre: wasmtime::InstancePre<T>,
 }
@@ -85,8 +211,10 @@ impl<T> WasmtimeCompiledModule<T> {
         module: wasmtime::Module,
         instance_pre: wasmtime::InstancePre<T>,
     ) -> WasmtimeCompiledModule<T> {
+        let hash = Sha256::digest(source.as_slice()).into();
         let inner = Arc::new(WasmtimeCompiledModuleInner {
             source,
+            hash,
             module,
             instance_pre,
         });
@@ -97,10 +225,19 @@ impl<T> WasmtimeCompiledModule<T> {
         self.inner.module.exports()
     }
 
+    pub fn imports(&self) -> impl ExactSizeIterator<Item = wasmtime::ImportType<'_>> {
+        self.inner.module.imports()
+    }
+
     pub fn source(&self) -> &RawWasm {
         &self.inner.source
     }
 
+    /// SHA-256 hash of the module's source bytes.
+    pub fn hash(&self) -> &[u8; 32] {
+        &self.inner.hash
+    }
+
     pub fn instantiator(&self) -> &wasmtime::InstancePre<T> {
         &self.inner.instance_pre
     }
@@ -120,12 +257,18 @@ where
 {
     store: wasmtime::Store<T>,
     instance: wasmtime::Instance,
+    profile: Option<ProfileGuard>,
 }
 
 impl<T> WasmtimeInstance<T>
 where
     T: Send,
 {
+    /// Attach a profile that gets the consumed fuel once the call finishes.
+    pub fn set_profile(&mut self, profile: ProfileGuard) {
+        self.profile = Some(profile);
+    }
+
     pub async fn call(mut self, function: &str, params: Vec<wasmtime::Val>) -> ExecutionResult<T> {
         let entry = self.instance.get_func(&mut self.store, function);
 
@@ -140,16 +283,35 @@ where
             .unwrap()
             .call_async(&mut self.store, &params, &mut [])
             .await;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.set_fuel(self.store.fuel_consumed());
+        }
 
         ExecutionResult {
             state: self.store.into_data(),
             result: match result {
                 Ok(()) => ResultValue::Ok,
                 Err(err) => {
-                    // If the trap is a result of calling `proc_exit(0)`, treat it as an no-error finish.
-                    match err.downcast_ref::<wasmtime_wasi::I32Exit>() {
-                        Some(wasmtime_wasi::I32Exit(0)) => ResultValue::Ok,
-                        _ => ResultValue::Failed(err.to_string()),
+                    // `proc_exit(0)` is a no-error finish, other codes are kept as the exit code.
+                    if let Some(wasmtime_wasi::I32Exit(code)) = err.downcast_ref() {
+                        match code {
+                            0 => ResultValue::Ok,
+                            code => ResultValue::Exited(ProcessExit {
+                                code: *code,
+                                reason: None,
+                            }),
+                        }
+                    } else if let Some(exit) = err.downcast_ref::<ProcessExit>() {
+                        match exit.code {
+                            0 => ResultValue::Ok,
+                            _ => ResultValue::Exited(exit.clone()),
+                        }
+                    // If the guest reported a panic before trapping, use it as the failure reason.
+                    } else if let Some(panic) = err.downcast_ref::<lunatic_trap_api::GuestPanic>() {
+                        ResultValue::Failed(panic.to_string())
+                    } else {
+                        // Include the causes, the trap reason is usually the root cause
+                        ResultValue::Failed(format!("{err:#}"))
                     }
                 }
             },
@@ -157,6 +319,46 @@ where
     }
 }
 
+// Entries are keyed by the module's hash, the engine settings, the lunatic version and the
+// platform.
+fn cache_entry_path(dir: &Path, fingerprint: &[u8], data: &[u8]) -> PathBuf {
+    let key = Sha256::new()
+        .chain_update(data)
+        .chain_update(fingerprint)
+        .chain_update(env!("CARGO_PKG_VERSION"))
+        .chain_update(std::env::consts::ARCH)
+        .chain_update(std::env::consts::OS)
+        .finalize();
+    dir.join(format!("{}.cwasm", to_hex(&key)))
+}
+
+// Hashes the engine settings precompiled modules depend on. Wasmtime writes all of them, in a fixed
+// order, into the header of a precompiled module, so precompiling an empty module gives a canonical
+// description of the `Config` the engine was built with.
+fn engine_fingerprint(engine: &wasmtime::Engine) -> Result<Vec<u8>> {
+    let empty = engine.precompile_module(b"\0asm\x01\0\0\0")?;
+    Ok(Sha256::digest(empty).to_vec())
+}
+
+// Writes to a temporary file first, so that readers never see a partially written entry.
+fn write_cache_entry(path: &Path, module: &wasmtime::Module) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("cache entry without a directory"))?;
+    std::fs::create_dir_all(dir)?;
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(&module.serialize()?)?;
+    tmp.persist(path)?;
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
 pub fn default_config() -> wasmtime::Config {
     let mut config = wasmtime::Config::new();
     config
@@ -175,3 +377,68 @@ pub fn default_config() -> wasmtime::Config {
         .static_memory_forced(true);
     config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE_A: &str = r#"(module (func (export "a")))"#;
+    const MODULE_B: &str = r#"(module (func (export "b")))"#;
+
+    fn exports(module: &wasmtime::Module) -> Vec<String> {
+        module.exports().map(|e| e.name().to_owned()).collect()
+    }
+
+    #[test]
+    fn cache_hit_loads_the_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let fingerprint = engine_fingerprint(&runtime.engine).unwrap();
+
+        runtime
+            .compile_cached(dir.path(), &fingerprint, MODULE_B.as_bytes())
+            .unwrap();
+        // Put module B in the place of A, if it's loaded from there A's entry is used
+        std::fs::copy(
+            cache_entry_path(dir.path(), &fingerprint, MODULE_B.as_bytes()),
+            cache_entry_path(dir.path(), &fingerprint, MODULE_A.as_bytes()),
+        )
+        .unwrap();
+
+        let module = runtime
+            .compile_cached(dir.path(), &fingerprint, MODULE_A.as_bytes())
+            .unwrap();
+        assert_eq!(exports(&module), ["b"]);
+    }
+
+    #[test]
+    fn corrupt_entry_is_recompiled() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let fingerprint = engine_fingerprint(&runtime.engine).unwrap();
+        let path = cache_entry_path(dir.path(), &fingerprint, MODULE_A.as_bytes());
+        std::fs::write(&path, b"not a module").unwrap();
+
+        let module = runtime
+            .compile_cached(dir.path(), &fingerprint, MODULE_A.as_bytes())
+            .unwrap();
+        assert_eq!(exports(&module), ["a"]);
+        // The entry is replaced with a valid one
+        let cached = unsafe { wasmtime::Module::deserialize_file(&runtime.engine, &path) };
+        assert_eq!(exports(&cached.unwrap()), ["a"]);
+    }
+
+    #[test]
+    fn engine_settings_are_part_of_the_key() {
+        let fingerprint = |config: &wasmtime::Config| {
+            engine_fingerprint(&wasmtime::Engine::new(config).unwrap()).unwrap()
+        };
+        assert_eq!(
+            fingerprint(&default_config()),
+            fingerprint(&default_config())
+        );
+        let mut without_fuel = default_config();
+        without_fuel.consume_fuel(false);
+        assert_ne!(fingerprint(&default_config()), fingerprint(&without_fuel));
+    }
+}
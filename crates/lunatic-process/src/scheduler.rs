@@ -0,0 +1,263 @@
+//! Fair sharing of execution time between environments.
+//!
+//! Processes run on the tokio executor and yield back to it after every unit of compute (see
+//! [`UNIT_OF_COMPUTE_IN_INSTRUCTIONS`](crate::config::UNIT_OF_COMPUTE_IN_INSTRUCTIONS)). The
+//! [`Scheduler`] measures how long each poll of a process takes and charges it to the process'
+//! environment. Once an environment used up its share of the current period, its processes are
+//! held back until the next period starts.
+//!
+//! Shares are only computed between environments that are running code, so an environment can use
+//! the whole node while the others are idle.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::time::Sleep;
+
+// Period of environments that never ran
+const NEVER: u64 = u64::MAX;
+
+pub struct Scheduler {
+    period: Duration,
+    // Execution time available to all environments in one period
+    capacity: Duration,
+    started: Instant,
+    environments: DashMap<u64, Arc<EnvironmentUsage>>,
+    // Number of the current period, counted from `started`
+    current_period: AtomicU64,
+    // Sum of the weights of all running environments
+    total_weight: AtomicU64,
+    // Held while the next period is set up
+    rollover: Mutex<()>,
+}
+
+struct EnvironmentUsage {
+    weight: AtomicU32,
+    // Period the environment last ran in
+    period: AtomicU64,
+    // Execution time in nanoseconds used in `period`
+    used: AtomicU64,
+}
+
+impl EnvironmentUsage {
+    // Environments that ran in this or the last period share the capacity
+    fn is_running(&self, period: u64) -> bool {
+        let last = self.period.load(Ordering::Acquire);
+        last != NEVER && last + 1 >= period
+    }
+}
+
+impl Scheduler {
+    /// Shares `capacity` of execution time per `period` between environments.
+    pub fn new(period: Duration, capacity: Duration) -> Self {
+        Self {
+            period,
+            capacity,
+            started: Instant::now(),
+            environments: DashMap::new(),
+            current_period: AtomicU64::new(0),
+            total_weight: AtomicU64::new(0),
+            rollover: Mutex::new(()),
+        }
+    }
+
+    /// Sets the weight of an environment's share, environments have a weight of 1 by default.
+    pub fn set_weight(&self, environment_id: u64, weight: u32) {
+        let usage = self.usage(environment_id);
+        let period = self.current_period();
+        let _rollover = self.rollover.lock().unwrap();
+        let old = usage.weight.swap(weight, Ordering::AcqRel);
+        if usage.is_running(period) {
+            self.total_weight.fetch_add(weight as u64, Ordering::AcqRel);
+            self.total_weight.fetch_sub(old as u64, Ordering::AcqRel);
+        }
+    }
+
+    /// Runs `fut` as part of the environment `environment_id`, holding it back while the
+    /// environment is over its share.
+    pub async fn run<F: Future>(&self, environment_id: u64, fut: F) -> F::Output {
+        let usage = self.usage(environment_id);
+        tokio::pin!(fut);
+        let mut throttled: Option<Pin<Box<Sleep>>> = None;
+        std::future::poll_fn(|cx| {
+            if let Some(sleep) = throttled.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                throttled = None;
+            }
+            if let Some(until) = self.throttled(&usage) {
+                let mut sleep = Box::pin(tokio::time::sleep_until(until.into()));
+                if sleep.as_mut().poll(cx).is_pending() {
+                    throttled = Some(sleep);
+                    return Poll::Pending;
+                }
+            }
+            let started = Instant::now();
+            let result = fut.as_mut().poll(cx);
+            self.charge_usage(&usage, started.elapsed());
+            result
+        })
+        .await
+    }
+
+    /// Returns the start of the next period if the environment used up its share of this one.
+    pub fn throttled_until(&self, environment_id: u64) -> Option<Instant> {
+        self.throttled(&self.usage(environment_id))
+    }
+
+    /// Charges `duration` of execution time to the environment.
+    pub fn charge(&self, environment_id: u64, duration: Duration) {
+        self.charge_usage(&self.usage(environment_id), duration);
+    }
+
+    fn usage(&self, environment_id: u64) -> Arc<EnvironmentUsage> {
+        self.environments
+            .entry(environment_id)
+            .or_insert_with(|| {
+                Arc::new(EnvironmentUsage {
+                    weight: AtomicU32::new(1),
+                    period: AtomicU64::new(NEVER),
+                    used: AtomicU64::new(0),
+                })
+            })
+            .clone()
+    }
+
+    fn throttled(&self, usage: &EnvironmentUsage) -> Option<Instant> {
+        let period = self.current_period();
+        let used = if usage.period.load(Ordering::Acquire) == period {
+            usage.used.load(Ordering::Acquire)
+        } else {
+            0
+        };
+        let weight = usage.weight.load(Ordering::Acquire) as u64;
+        let mut total_weight = self.total_weight.load(Ordering::Acquire);
+        if !usage.is_running(period) {
+            // The environment is about to run and takes a share
+            total_weight += weight;
+        }
+        let share = self
+            .capacity
+            .mul_f64(weight as f64 / total_weight.max(1) as f64);
+        let next_period = self.period.as_nanos() * (period as u128 + 1);
+        (used >= share.as_nanos() as u64)
+            .then(|| self.started + Duration::from_nanos(next_period as u64))
+    }
+
+    fn charge_usage(&self, usage: &EnvironmentUsage, duration: Duration) {
+        let period = self.current_period();
+        let last = usage.period.swap(period, Ordering::AcqRel);
+        if last != period {
+            usage.used.store(0, Ordering::Release);
+        }
+        usage
+            .used
+            .fetch_add(duration.as_nanos() as u64, Ordering::AcqRel);
+        // Only the first charge after the environment was idle sees the old period
+        if last == NEVER || last + 1 < period {
+            let weight = usage.weight.load(Ordering::Acquire);
+            self.total_weight.fetch_add(weight as u64, Ordering::AcqRel);
+        }
+    }
+
+    // Returns the number of the current period, recounting the running environments when a new
+    // one started.
+    fn current_period(&self) -> u64 {
+        let period = (self.started.elapsed().as_nanos() / self.period.as_nanos()) as u64;
+        if period > self.current_period.load(Ordering::Acquire) {
+            let _rollover = self.rollover.lock().unwrap();
+            if period > self.current_period.load(Ordering::Acquire) {
+                let total_weight = self
+                    .environments
+                    .iter()
+                    .filter(|usage| usage.is_running(period))
+                    .map(|usage| usage.weight.load(Ordering::Acquire) as u64)
+                    .sum();
+                self.total_weight.store(total_weight, Ordering::Release);
+                self.current_period.store(period, Ordering::Release);
+            }
+        }
+        period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn shares_by_weight() {
+        let scheduler = Scheduler::new(Duration::from_secs(60), 100 * MS);
+        scheduler.set_weight(2, 3);
+        scheduler.charge(1, 20 * MS);
+        scheduler.charge(2, 20 * MS);
+        assert!(scheduler.throttled_until(1).is_none());
+        assert!(scheduler.throttled_until(2).is_none());
+
+        // Environment 1 gets a quarter of the capacity, environment 2 the rest
+        scheduler.charge(1, 5 * MS);
+        assert!(scheduler.throttled_until(1).is_some());
+        scheduler.charge(2, 50 * MS);
+        assert!(scheduler.throttled_until(2).is_none());
+        scheduler.charge(2, 5 * MS);
+        assert!(scheduler.throttled_until(2).is_some());
+    }
+
+    #[test]
+    fn idle_environments_dont_take_a_share() {
+        let scheduler = Scheduler::new(Duration::from_secs(60), 100 * MS);
+        scheduler.charge(1, 90 * MS);
+        assert!(scheduler.throttled_until(1).is_none());
+        // Another environment starting to run takes half of the capacity
+        scheduler.charge(2, MS);
+        assert!(scheduler.throttled_until(1).is_some());
+        assert!(scheduler.throttled_until(2).is_none());
+    }
+
+    #[test]
+    fn environments_idle_for_a_period_give_up_their_share() {
+        let scheduler = Scheduler::new(100 * MS, 100 * MS);
+        scheduler.charge(1, MS);
+        scheduler.charge(2, MS);
+        std::thread::sleep(250 * MS);
+        // Only environment 1 runs now, so it gets the whole capacity
+        scheduler.charge(1, 90 * MS);
+        assert!(scheduler.throttled_until(1).is_none());
+        scheduler.charge(1, 10 * MS);
+        assert!(scheduler.throttled_until(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn run_charges_the_environment() {
+        let scheduler = Scheduler::new(Duration::from_secs(60), 10 * MS);
+        scheduler
+            .run(1, async { std::thread::sleep(10 * MS) })
+            .await;
+        assert!(scheduler.throttled_until(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn throttled_environments_wait_for_the_next_period() {
+        let scheduler = Scheduler::new(50 * MS, 10 * MS);
+        scheduler.charge(1, 10 * MS);
+        let until = scheduler.throttled_until(1).unwrap();
+
+        scheduler.run(1, async {}).await;
+        assert!(Instant::now() >= until);
+        assert!(scheduler.throttled_until(1).is_none());
+        // Other environments were never held back
+        assert!(scheduler.throttled_until(2).is_none());
+    }
+}
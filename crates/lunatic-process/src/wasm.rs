@@ -36,13 +36,28 @@ where
     let signal_mailbox = state.signal_mailbox().clone();
     let message_mailbox = state.message_mailbox().clone();
 
-    let instance = runtime.instantiate(module, state).await?;
-    let function = function.to_string();
-    let fut = async move { instance.call(&function, params).await };
-    let child_process = crate::new(fut, id, env.clone(), signal_mailbox.1, message_mailbox);
+    let mut instance = runtime.instantiate(module, state).await?;
     let child_process_handle = Arc::new(WasmProcess::new(id, signal_mailbox.0.clone()));
+    // Takes a slot of the environment's process limit
+    env.add_process(id, child_process_handle.clone())?;
 
-    env.add_process(id, child_process_handle.clone());
+    if let Some(profiler) = runtime.profiler() {
+        instance.set_profile(profiler.start(env.id(), id, function, message_mailbox.clone()));
+        if let Some(parent_id) = crate::current_process_id() {
+            profiler.spawned_by(env.id(), parent_id);
+        }
+    }
+    let function = function.to_string();
+    let scheduler = runtime.scheduler().cloned();
+    let environment_id = env.id();
+    let fut = async move {
+        let call = instance.call(&function, params);
+        match scheduler {
+            Some(scheduler) => scheduler.run(environment_id, call).await,
+            None => call.await,
+        }
+    };
+    let child_process = crate::new(fut, id, env.clone(), signal_mailbox.1, message_mailbox);
 
     // **Child link guarantees**:
     // The link signal is going to be put inside of the child's mailbox and is going to be
@@ -81,6 +96,6 @@ where
 
     // Spawn a background process
     trace!("Process size: {}", std::mem::size_of_val(&child_process));
-    let join = tokio::task::spawn(child_process);
+    let join = tokio::task::spawn(crate::CURRENT_PROCESS_ID.scope(id, child_process));
     Ok((join, child_process_handle))
 }
@@ -0,0 +1,262 @@
+//! Detection of slow host calls and executor stalls.
+//!
+//! A single misbehaving host function (e.g. a blocking call into an external service) can hold
+//! onto a process for a long time, and a blocking host function can stall a whole executor
+//! thread. The [`Watchdog`] periodically checks all watched processes and reports the ones that
+//! are stuck inside of a host call for longer than the configured duration, together with the
+//! name of the host function. It also reports if a heartbeat task on the monitored runtime falls
+//! behind, which is a sign of an executor stall.
+//!
+//! The checks run on a dedicated thread, so that they keep working while the executor is stalled.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use log::warn;
+use lunatic_common_api::host_call::{self, HostCallName};
+use wasmtime::CallHook;
+
+use crate::{state::SignalSender, Signal};
+
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    /// Host calls taking longer than this are reported.
+    pub slow_host_call: Duration,
+    /// If the heartbeat on the executor is delayed by more than this, an executor stall is
+    /// reported.
+    pub executor_stall: Duration,
+    /// How often the watchdog checks for slow host calls.
+    pub check_interval: Duration,
+    /// Kill processes that are stuck in a host call for longer than `slow_host_call`.
+    pub kill_slow_processes: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            slow_host_call: Duration::from_secs(5),
+            executor_stall: Duration::from_millis(500),
+            check_interval: Duration::from_millis(100),
+            kill_slow_processes: false,
+        }
+    }
+}
+
+pub struct Watchdog {
+    config: WatchdogConfig,
+    // All durations are stored as nanoseconds since `epoch`
+    epoch: Instant,
+    next_id: AtomicU64,
+    watched: DashMap<u64, Weak<WatchedProcess>>,
+    // Last time the heartbeat task ran on the monitored runtime
+    heartbeat: AtomicU64,
+}
+
+/// A process that is stuck inside of a host call.
+#[derive(Clone, Debug)]
+pub struct SlowHostCall {
+    pub process_id: u64,
+    /// Name of the host function, e.g. `lunatic::process::sleep_ms`. `None` for host functions
+    /// that don't record their name, like the WASI ones.
+    pub function: Option<Arc<str>>,
+    pub duration: Duration,
+}
+
+/// Host call tracking of a single process, updated from the store's call hook.
+pub struct WatchedProcess {
+    process_id: u64,
+    signal_mailbox: SignalSender,
+    epoch: Instant,
+    // 0 if the process is not inside a host call, otherwise the time the call started
+    host_call_started: AtomicU64,
+    host_call_name: Arc<HostCallName>,
+    // Set once the current host call was reported, to avoid reporting it on every tick
+    reported: AtomicBool,
+}
+
+impl WatchedProcess {
+    /// Updates the host call state, should be called from [`wasmtime::Store::call_hook`].
+    pub fn on_call_hook(&self, hook: CallHook) {
+        if hook.entering_host() {
+            // Never store 0, it marks that the process is not inside a host call.
+            let now = (self.epoch.elapsed().as_nanos() as u64).max(1);
+            self.host_call_started.store(now, Ordering::Relaxed);
+            host_call::entering_host(&self.host_call_name);
+        } else if hook.exiting_host() {
+            self.host_call_started.store(0, Ordering::Relaxed);
+            self.reported.store(false, Ordering::Relaxed);
+            host_call::exiting_host();
+        }
+    }
+}
+
+impl WatchedProcess {
+    // Time spent in the current host call, if it's longer than `limit` nanoseconds.
+    fn slow_host_call(&self, now: u64, limit: u64) -> Option<Duration> {
+        let started = self.host_call_started.load(Ordering::Relaxed);
+        if started == 0 || now.saturating_sub(started) < limit {
+            return None;
+        }
+        Some(Duration::from_nanos(now - started))
+    }
+}
+
+impl Watchdog {
+    /// Creates a new watchdog and spawns the thread checking on processes, together with a
+    /// heartbeat task on the current tokio runtime.
+    ///
+    /// Both stop once the last reference to the watchdog is dropped.
+    pub fn spawn(config: WatchdogConfig) -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            config,
+            epoch: Instant::now(),
+            next_id: AtomicU64::new(0),
+            watched: DashMap::new(),
+            heartbeat: AtomicU64::new(0),
+        });
+        let check_interval = watchdog.config.check_interval;
+
+        let weak = Arc::downgrade(&watchdog);
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(check_interval).await;
+                match weak.upgrade() {
+                    Some(watchdog) => watchdog.beat(),
+                    None => break,
+                }
+            }
+        });
+
+        let weak = Arc::downgrade(&watchdog);
+        std::thread::Builder::new()
+            .name("lunatic-watchdog".into())
+            .spawn(move || {
+                let mut stalled = false;
+                loop {
+                    std::thread::sleep(check_interval);
+                    let watchdog = match weak.upgrade() {
+                        Some(watchdog) => watchdog,
+                        None => break,
+                    };
+                    let delay = watchdog.since_heartbeat().saturating_sub(check_interval);
+                    // Report a stall once, not on every check while it lasts
+                    match (delay > watchdog.config.executor_stall, stalled) {
+                        (true, false) => {
+                            warn!("Executor stall detected, heartbeat was delayed by {delay:?}");
+                            stalled = true;
+                        }
+                        (false, true) => stalled = false,
+                        _ => {}
+                    }
+                    watchdog.check();
+                }
+            })
+            .expect("Failed to spawn watchdog thread");
+        watchdog
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    fn beat(&self) {
+        self.heartbeat.store(self.now(), Ordering::Relaxed);
+    }
+
+    fn since_heartbeat(&self) -> Duration {
+        // The heartbeat task can store a newer value after `now` was taken
+        Duration::from_nanos(
+            self.now()
+                .saturating_sub(self.heartbeat.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Starts watching the process with `process_id`.
+    ///
+    /// The process is watched as long as the returned [`WatchedProcess`] is alive.
+    pub fn watch(&self, process_id: u64, signal_mailbox: SignalSender) -> Arc<WatchedProcess> {
+        let watched = Arc::new(WatchedProcess {
+            process_id,
+            signal_mailbox,
+            epoch: self.epoch,
+            host_call_started: AtomicU64::new(0),
+            host_call_name: Default::default(),
+            reported: AtomicBool::new(false),
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.watched.insert(id, Arc::downgrade(&watched));
+        watched
+    }
+
+    /// Returns the processes that are stuck in a host call for longer than `slow_host_call`.
+    pub fn slow_host_calls(&self) -> Vec<SlowHostCall> {
+        let now = self.now();
+        let slow_host_call = self.config.slow_host_call.as_nanos() as u64;
+        self.watched
+            .iter()
+            .filter_map(|watched| {
+                let watched = watched.upgrade()?;
+                let duration = watched.slow_host_call(now, slow_host_call)?;
+                Some(SlowHostCall {
+                    process_id: watched.process_id,
+                    function: watched.host_call_name.get(),
+                    duration,
+                })
+            })
+            .collect()
+    }
+
+    fn check(&self) {
+        let now = self.now();
+        let slow_host_call = self.config.slow_host_call.as_nanos() as u64;
+        self.watched.retain(|_, watched| {
+            let watched = match watched.upgrade() {
+                Some(watched) => watched,
+                // The process finished
+                None => return false,
+            };
+            let duration = match watched.slow_host_call(now, slow_host_call) {
+                Some(duration) => duration,
+                None => return true,
+            };
+            if !watched.reported.swap(true, Ordering::Relaxed) {
+                let function = watched
+                    .host_call_name
+                    .get()
+                    .unwrap_or_else(|| "an unnamed host function".into());
+                if self.config.kill_slow_processes {
+                    warn!(
+                        "Process {} is stuck in {} for {:?}, killing it",
+                        watched.process_id, function, duration
+                    );
+                    let _ = watched.signal_mailbox.send(Signal::Kill);
+                } else {
+                    warn!(
+                        "Process {} is stuck in {} for {:?}",
+                        watched.process_id, function, duration
+                    );
+                }
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn heartbeat_after_now_is_no_delay() {
+        let watchdog = Watchdog::spawn(WatchdogConfig::default());
+        let later = watchdog.now() + Duration::from_secs(1).as_nanos() as u64;
+        watchdog.heartbeat.store(later, Ordering::Relaxed);
+        assert_eq!(watchdog.since_heartbeat(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
+use lunatic_error_api::ErrorCtx;
+use tokio::sync::Mutex;
+use wasmtime::{Caller, Linker};
+
+// How many values are reserved on disk at once. A crash can leave a gap of at most this many
+// values in a sequence, but values are never handed out twice.
+const RESERVE_BATCH: u64 = 1024;
+
+pub trait SeqCtx {
+    fn sequences(&self) -> &Arc<Sequences>;
+}
+
+/// Named, monotonic counters shared by all processes on a node.
+///
+/// If a directory is given, each sequence persists the upper bound of the values it handed out
+/// (reserved in batches of [`RESERVE_BATCH`]). After a restart the sequence continues from the
+/// persisted bound, so values stay unique and monotonic across crashes. Without a directory
+/// the sequences only live as long as the node.
+///
+/// Reading and persisting the bounds runs on the blocking thread pool, so a slow disk only
+/// delays the processes using sequences and never stalls the executor.
+#[derive(Debug, Default)]
+pub struct Sequences {
+    dir: Option<PathBuf>,
+    sequences: Mutex<HashMap<String, Sequence>>,
+}
+
+#[derive(Debug, Default)]
+struct Sequence {
+    // Next value that is going to be returned.
+    next: u64,
+    // Values below this bound are persisted as used.
+    reserved: u64,
+}
+
+impl Sequences {
+    /// Creates sequences persisted in `dir`, creating the directory if necessary.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: Some(dir),
+            sequences: Mutex::default(),
+        })
+    }
+
+    /// Creates sequences that are not persisted.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to the sequence `name` and returns the previous value.
+    ///
+    /// A guest can reserve a batch of ids with one call by using a `delta` bigger than 1.
+    pub async fn fetch_add(&self, name: &str, delta: u64) -> Result<u64> {
+        let mut sequences = self.sequences.lock().await;
+        let sequence = self.load(&mut sequences, name).await?;
+        let previous = sequence.next;
+        let next = previous
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("sequence '{name}' overflowed"))?;
+        if next > sequence.reserved {
+            let reserved = next.saturating_add(RESERVE_BATCH);
+            if let Some(dir) = self.dir.as_ref() {
+                let path = file_path(dir, name);
+                blocking(move || persist(&path, reserved)).await?;
+            }
+            sequence.reserved = reserved;
+        }
+        sequence.next = next;
+        Ok(previous)
+    }
+
+    /// Returns the next value of the sequence `name` without changing it.
+    pub async fn get(&self, name: &str) -> Result<u64> {
+        let mut sequences = self.sequences.lock().await;
+        Ok(self.load(&mut sequences, name).await?.next)
+    }
+
+    async fn load<'a>(
+        &self,
+        sequences: &'a mut HashMap<String, Sequence>,
+        name: &str,
+    ) -> Result<&'a mut Sequence> {
+        if !sequences.contains_key(name) {
+            let reserved = match self.dir.as_ref() {
+                Some(dir) => {
+                    let path = file_path(dir, name);
+                    blocking(move || read_persisted(&path)).await?
+                }
+                None => 0,
+            };
+            // Everything below the persisted bound could have been handed out before a crash.
+            let sequence = Sequence {
+                next: reserved,
+                reserved,
+            };
+            sequences.insert(name.to_owned(), sequence);
+        }
+        Ok(sequences.get_mut(name).expect("inserted above"))
+    }
+}
+
+// Runs file system I/O on the blocking thread pool.
+async fn blocking<R, F>(f: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+// Sequence names are arbitrary strings, hex encode them to get a valid file name.
+fn file_path(dir: &Path, name: &str) -> PathBuf {
+    let file_name: String = name.bytes().map(|b| format!("{b:02x}")).collect();
+    dir.join(format!("{file_name}.seq"))
+}
+
+fn read_persisted(path: &Path) -> Result<u64> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let bytes: [u8; 8] = bytes
+                .try_into()
+                .map_err(|_| anyhow!("corrupted sequence file {}", path.display()))?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Write to a temporary file first and rename it, so that a crash never leaves a partially
+// written value behind.
+fn persist(path: &Path, value: u64) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&value.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Register the sequence APIs to the linker
+pub fn register<T>(linker: &mut Linker<T>) -> Result<()>
+where
+    T: SeqCtx + ErrorCtx + Send + 'static,
+{
+    linker.func_wrap4_async_named("lunatic::seq", "fetch_add", fetch_add)?;
+    linker.func_wrap3_async_named("lunatic::seq", "get", get)?;
+    Ok(())
+}
+
+// Adds `delta` to the sequence under `name` and returns the previous value. Sequences start
+// at 0 and are created on first use.
+//
+// Returns:
+// * 0 on success - The previous value is written to **value_ptr**
+// * 1 on error   - The error ID is written to **value_ptr**
+//
+// Traps:
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn fetch_add<T: SeqCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    delta: u64,
+    value_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let name = memory
+            .data(&caller)
+            .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+            .or_trap("lunatic::seq::fetch_add")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::seq::fetch_add")?
+            .to_owned();
+
+        let sequences = caller.data().sequences().clone();
+        let (value_or_error_id, result) = match sequences.fetch_add(&name, delta).await {
+            Ok(previous) => (previous, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                value_ptr as usize,
+                &value_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::seq::fetch_add")?;
+        Ok(result)
+    })
+}
+
+// Returns the next value of the sequence under `name` without changing it.
+//
+// Returns:
+// * 0 on success - The value is written to **value_ptr**
+// * 1 on error   - The error ID is written to **value_ptr**
+//
+// Traps:
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn get<T: SeqCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    value_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let name = memory
+            .data(&caller)
+            .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
+            .or_trap("lunatic::seq::get")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::seq::get")?
+            .to_owned();
+
+        let sequences = caller.data().sequences().clone();
+        let (value_or_error_id, result) = match sequences.get(&name).await {
+            Ok(value) => (value, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+        };
+        memory
+            .write(
+                &mut caller,
+                value_ptr as usize,
+                &value_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::seq::get")?;
+        Ok(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_sequence() {
+        let sequences = Sequences::in_memory();
+        assert_eq!(sequences.fetch_add("ids", 1).await.unwrap(), 0);
+        assert_eq!(sequences.fetch_add("ids", 10).await.unwrap(), 1);
+        assert_eq!(sequences.get("ids").await.unwrap(), 11);
+        assert_eq!(sequences.get("other").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn persisted_sequence_never_repeats() {
+        let dir = std::env::temp_dir().join(format!("lunatic-seq-test-{}", std::process::id()));
+        let sequences = Sequences::open(&dir).unwrap();
+        assert_eq!(sequences.fetch_add("ids", 5).await.unwrap(), 0);
+        drop(sequences);
+
+        // Simulate a restart, previously reserved values are skipped
+        let sequences = Sequences::open(&dir).unwrap();
+        assert_eq!(
+            sequences.fetch_add("ids", 1).await.unwrap(),
+            5 + RESERVE_BATCH
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overflow_is_an_error() {
+        let sequences = Sequences::in_memory();
+        sequences.fetch_add("ids", u64::MAX).await.unwrap();
+        assert!(sequences.fetch_add("ids", 1).await.is_err());
+    }
+}
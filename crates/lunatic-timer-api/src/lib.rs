@@ -7,7 +7,7 @@ use std::{
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
-use lunatic_common_api::IntoTrap;
+use lunatic_common_api::{IntoTrap, NamedHostFunctions};
 use lunatic_process::{state::ProcessState, Signal};
 use lunatic_process_api::ProcessCtx;
 use tokio::task::JoinHandle;
@@ -21,7 +21,7 @@ struct HeapValue {
 
 impl PartialOrd for HeapValue {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.instant.cmp(&other.instant).reverse())
+        Some(self.cmp(other))
     }
 }
 
@@ -87,8 +87,8 @@ pub trait TimerCtx {
 pub fn register<T: ProcessState + ProcessCtx<T> + TimerCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
-    linker.func_wrap("lunatic::timer", "send_after", send_after)?;
-    linker.func_wrap1_async("lunatic::timer", "cancel_timer", cancel_timer)?;
+    linker.func_wrap_named("lunatic::timer", "send_after", send_after)?;
+    linker.func_wrap1_async_named("lunatic::timer", "cancel_timer", cancel_timer)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
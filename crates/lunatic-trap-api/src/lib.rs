@@ -1,15 +1,37 @@
-use std::future::Future;
+use std::{fmt::Display, future::Future};
 
 use anyhow::Result;
-use lunatic_common_api::IntoTrap;
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use wasmtime::{Caller, Linker, Val};
 
 // Register the trap APIs to the linker
 pub fn register<T: Send + 'static>(linker: &mut Linker<T>) -> Result<()> {
-    linker.func_wrap2_async("lunatic::trap", "catch", catch_trap::<T>)?;
+    linker.func_wrap2_async_named("lunatic::trap", "catch", catch_trap::<T>)?;
+    linker.func_wrap_named("lunatic::trap", "panic", panic::<T>)?;
     Ok(())
 }
 
+/// A panic reported by the guest through `lunatic::trap::panic` right before it traps.
+///
+/// The runtime looks for this error when a process fails, so that the exit reason contains the
+/// actual panic message and location instead of a generic `unreachable` trap.
+#[derive(Debug, Clone)]
+pub struct GuestPanic {
+    pub message: String,
+    pub location: Option<String>,
+}
+
+impl Display for GuestPanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "panicked at '{}', {}", self.message, location),
+            None => write!(f, "panicked at '{}'", self.message),
+        }
+    }
+}
+
+impl std::error::Error for GuestPanic {}
+
 // Can be used as a trampoline to catch traps inside of guest by jumping
 // through the host.
 //
@@ -44,8 +66,41 @@ fn catch_trap<T: Send>(
             .call_async(caller, &params, &mut result)
             .await;
         match execution_result {
-            Ok(()) => Ok(result.get(0).unwrap().i32().unwrap()),
+            Ok(()) => Ok(result.first().unwrap().i32().unwrap()),
             Err(_) => Ok(0),
         }
     })
 }
+
+// Reports a guest panic and traps.
+//
+// Meant to be called from the guest's panic hook with the UTF-8 encoded panic message and
+// location (e.g. `src/main.rs:10:5`). If `location_len` is 0, no location is recorded. This
+// function never returns, it always traps with a [`GuestPanic`] error that becomes the exit
+// reason of the process.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn panic<T>(
+    mut caller: Caller<T>,
+    message_ptr: u32,
+    message_len: u32,
+    location_ptr: u32,
+    location_len: u32,
+) -> Result<()> {
+    let memory = get_memory(&mut caller)?;
+    let read_string = |ptr: u32, len: u32| -> Result<String> {
+        let bytes = memory
+            .data(&caller)
+            .get(ptr as usize..(ptr as usize + len as usize))
+            .or_trap("lunatic::trap::panic")?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    };
+    let message = read_string(message_ptr, message_len)?;
+    let location = if location_len > 0 {
+        Some(read_string(location_ptr, location_len)?)
+    } else {
+        None
+    };
+    Err(GuestPanic { message, location }.into())
+}
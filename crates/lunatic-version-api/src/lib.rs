@@ -1,10 +1,23 @@
-use wasmtime::Linker;
+use anyhow::Result;
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
+use wasmtime::{Caller, Linker};
+
+pub trait VersionCtx {
+    /// SHA-256 hash of the module the process was spawned from.
+    fn module_hash(&self) -> [u8; 32];
+    /// Optional features the runtime was built with.
+    fn build_features(&self) -> &[&'static str];
+}
 
 /// Links the `version` APIs.
-pub fn register<T>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+pub fn register<T: VersionCtx + 'static>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    // Without access to the caller these can't be named, they don't block anyway
     linker.func_wrap("lunatic::version", "major", major)?;
     linker.func_wrap("lunatic::version", "minor", minor)?;
     linker.func_wrap("lunatic::version", "patch", patch)?;
+    linker.func_wrap_named("lunatic::version", "module_hash", module_hash)?;
+    linker.func_wrap_named("lunatic::version", "features_size", features_size)?;
+    linker.func_wrap_named("lunatic::version", "features", features)?;
     Ok(())
 }
 
@@ -19,3 +32,36 @@ fn minor() -> u32 {
 fn patch() -> u32 {
     env!("CARGO_PKG_VERSION_PATCH").parse::<u32>().unwrap()
 }
+
+// Writes the 32 byte SHA-256 hash of the module that the current process was spawned from to
+// `hash_ptr`.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn module_hash<T: VersionCtx>(mut caller: Caller<T>, hash_ptr: u32) -> Result<()> {
+    let hash = caller.data().module_hash();
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, hash_ptr as usize, &hash)
+        .or_trap("lunatic::version::module_hash")?;
+    Ok(())
+}
+
+// Returns the size of the comma separated list of features the runtime was built with.
+fn features_size<T: VersionCtx>(caller: Caller<T>) -> u32 {
+    caller.data().build_features().join(",").len() as u32
+}
+
+// Writes the comma separated list of features the runtime was built with to `features_ptr`.
+// `lunatic::version::features_size` can be used to get the list size.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn features<T: VersionCtx>(mut caller: Caller<T>, features_ptr: u32) -> Result<()> {
+    let features = caller.data().build_features().join(",");
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, features_ptr as usize, features.as_bytes())
+        .or_trap("lunatic::version::features")?;
+    Ok(())
+}
@@ -1,5 +1,5 @@
-use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
+use anyhow::{anyhow, Result};
+use lunatic_common_api::{get_memory, IntoTrap, NamedHostFunctions};
 use lunatic_process::state::ProcessState;
 use lunatic_stdout_capture::StdoutCapture;
 use wasmtime::{Caller, Linker};
@@ -29,6 +29,8 @@ pub trait LunaticWasiConfigCtx {
     fn add_environment_variable(&mut self, key: String, value: String);
     fn add_command_line_argument(&mut self, argument: String);
     fn preopen_dir(&mut self, dir: String);
+    /// Checks if a process with this config can grant new configs access to the directory.
+    fn can_preopen_dir(&self, dir: &str) -> Result<(), String>;
 }
 
 pub trait LunaticWasiCtx {
@@ -53,17 +55,17 @@ where
     )?;
 
     // Register host functions to configure wasi
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::wasi",
         "config_add_environment_variable",
         add_environment_variable,
     )?;
-    linker.func_wrap(
+    linker.func_wrap_named(
         "lunatic::wasi",
         "config_add_command_line_argument",
         add_command_line_argument,
     )?;
-    linker.func_wrap("lunatic::wasi", "config_preopen_dir", preopen_dir)?;
+    linker.func_wrap_named("lunatic::wasi", "config_preopen_dir", preopen_dir)?;
 
     Ok(())
 }
@@ -148,6 +150,7 @@ where
 // Mark a directory as preopened in the configuration.
 //
 // Traps:
+// * If the directory is outside of the directories the calling process can access.
 // * If the config ID doesn't exist.
 // * If the directory string is not a valid utf8 string.
 // * If any of the memory slices falls outside the memory.
@@ -164,6 +167,9 @@ where
     let dir = std::str::from_utf8(dir_str)
         .or_trap("lunatic::wasi::preopen_dir")?
         .to_string();
+    if let Err(e) = caller.data().config().can_preopen_dir(&dir) {
+        return Err(anyhow!("lunatic::wasi::preopen_dir: {e}"));
+    }
 
     caller
         .data_mut()
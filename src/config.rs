@@ -1,8 +1,10 @@
 use std::{
     fmt::Debug,
     path::{Component, Path, PathBuf},
+    time::SystemTime,
 };
 
+use lunatic_networking_api::{DnsOverrides, NetworkingConfigCtx, VirtualNetwork};
 use lunatic_process::config::ProcessConfig;
 use lunatic_process_api::ProcessConfigCtx;
 use lunatic_wasi_api::LunaticWasiConfigCtx;
@@ -20,10 +22,22 @@ pub struct DefaultProcessConfig {
     can_create_configs: bool,
     // Can this process spawn sub-processes
     can_spawn_processes: bool,
+    // Time at which the permissions above and access to the preopened dirs are revoked
+    #[serde(default)]
+    expires_at: Option<SystemTime>,
     // WASI configs
     preopened_dirs: Vec<String>,
     command_line_arguments: Vec<String>,
     environment_variables: Vec<(String, String)>,
+    // Host function namespaces processes can't import from
+    #[serde(default)]
+    denied_namespaces: Vec<String>,
+    // Custom name resolution
+    #[serde(default)]
+    dns_overrides: DnsOverrides,
+    // In-memory network for sockets, it only exists on this node
+    #[serde(skip)]
+    virtual_network: Option<VirtualNetwork>,
 }
 
 impl Debug for DefaultProcessConfig {
@@ -31,9 +45,13 @@ impl Debug for DefaultProcessConfig {
         f.debug_struct("EnvConfig")
             .field("max_memory", &self.max_memory)
             .field("max_fuel", &self.max_fuel)
+            .field("expires_at", &self.expires_at)
             .field("preopened_dirs", &self.preopened_dirs)
             .field("args", &self.command_line_arguments)
             .field("envs", &self.environment_variables)
+            .field("denied_namespaces", &self.denied_namespaces)
+            .field("dns_overrides", &self.dns_overrides)
+            .field("virtual_network", &self.virtual_network)
             .finish()
     }
 }
@@ -54,6 +72,21 @@ impl ProcessConfig for DefaultProcessConfig {
     fn get_max_memory(&self) -> usize {
         self.max_memory
     }
+
+    fn can_import(&self, namespace: &str) -> bool {
+        // Denying `lunatic::networking` also denies nested namespaces like `lunatic::networking::tls`
+        !self.denied_namespaces.iter().any(|denied| {
+            matches!(
+                namespace.strip_prefix(denied.as_str()),
+                Some(rest) if rest.is_empty() || rest.starts_with("::")
+            )
+        })
+    }
+
+    fn can_spawn_remotely(&self) -> bool {
+        // The virtual network only exists on this node and isn't serialized
+        self.virtual_network.is_none()
+    }
 }
 
 impl LunaticWasiConfigCtx for DefaultProcessConfig {
@@ -68,6 +101,28 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
     fn preopen_dir(&mut self, dir: String) {
         self.preopened_dirs.push(dir);
     }
+
+    fn can_preopen_dir(&self, dir: &str) -> Result<(), String> {
+        self.can_access_fs_location(Path::new(dir))
+    }
+}
+
+impl NetworkingConfigCtx for DefaultProcessConfig {
+    fn dns_overrides(&self) -> &DnsOverrides {
+        &self.dns_overrides
+    }
+
+    fn set_dns_overrides(&mut self, dns_overrides: DnsOverrides) {
+        self.dns_overrides = dns_overrides;
+    }
+
+    fn virtual_network(&self) -> Option<&VirtualNetwork> {
+        self.virtual_network.as_ref()
+    }
+
+    fn set_virtual_network(&mut self, virtual_network: Option<VirtualNetwork>) {
+        self.virtual_network = virtual_network;
+    }
 }
 
 impl DefaultProcessConfig {
@@ -95,11 +150,15 @@ impl DefaultProcessConfig {
     pub fn environment_variables(&self) -> &Vec<(String, String)> {
         &self.environment_variables
     }
+
+    fn expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if SystemTime::now() >= expires_at)
+    }
 }
 
 impl ProcessConfigCtx for DefaultProcessConfig {
     fn can_compile_modules(&self) -> bool {
-        self.can_compile_modules
+        self.can_compile_modules && !self.expired()
     }
 
     fn set_can_compile_modules(&mut self, can: bool) {
@@ -107,7 +166,7 @@ impl ProcessConfigCtx for DefaultProcessConfig {
     }
 
     fn can_create_configs(&self) -> bool {
-        self.can_create_configs
+        self.can_create_configs && !self.expired()
     }
 
     fn set_can_create_configs(&mut self, can: bool) {
@@ -115,14 +174,33 @@ impl ProcessConfigCtx for DefaultProcessConfig {
     }
 
     fn can_spawn_processes(&self) -> bool {
-        self.can_spawn_processes
+        self.can_spawn_processes && !self.expired()
     }
 
     fn set_can_spawn_processes(&mut self, can: bool) {
         self.can_spawn_processes = can
     }
 
+    fn deny_namespace(&mut self, namespace: String) {
+        self.denied_namespaces.push(namespace);
+    }
+
+    fn denied_namespaces(&self) -> &[String] {
+        &self.denied_namespaces
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    fn set_expires_at(&mut self, expires_at: Option<SystemTime>) {
+        self.expires_at = expires_at;
+    }
+
     fn can_access_fs_location(&self, path: &std::path::Path) -> Result<(), String> {
+        if self.expired() {
+            return Err(format!("Permission to '{path:?}' expired"));
+        }
         let (file_path, parent_dir) = match strip_file(path) {
             Ok(p) => p,
             Err(e) => {
@@ -132,10 +210,7 @@ impl ProcessConfigCtx for DefaultProcessConfig {
         let has_access = self
             .preopened_dirs()
             .iter()
-            .filter_map(|dir| match get_absolute_path(Path::new(dir)) {
-                Ok(d) => Some(d),
-                _ => None,
-            })
+            .filter_map(|dir| get_absolute_path(Path::new(dir)).ok())
             .any(|dir| dir.exists() && path_is_ancestor(&dir, &parent_dir));
 
         match has_access {
@@ -217,6 +292,25 @@ fn normalize_path(path: &Path) -> PathBuf {
     ret
 }
 
+impl Default for DefaultProcessConfig {
+    fn default() -> Self {
+        Self {
+            max_memory: u32::MAX as usize, // = 4 GB
+            max_fuel: None,
+            can_compile_modules: false,
+            can_create_configs: false,
+            can_spawn_processes: false,
+            expires_at: None,
+            preopened_dirs: vec![],
+            command_line_arguments: vec![],
+            environment_variables: vec![],
+            denied_namespaces: vec![],
+            dns_overrides: DnsOverrides::default(),
+            virtual_network: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
@@ -225,6 +319,32 @@ mod tests {
 
     use super::normalize_path;
 
+    #[test]
+    fn denied_namespaces() {
+        use super::DefaultProcessConfig;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = DefaultProcessConfig::default();
+        config.deny_namespace("lunatic::networking".to_string());
+        assert!(!config.can_import("lunatic::networking"));
+        assert!(!config.can_import("lunatic::networking::tls"));
+        assert!(config.can_import("lunatic::networking_extra"));
+        assert!(config.can_import("lunatic::process"));
+    }
+
+    #[test]
+    fn virtual_network_stays_local() {
+        use super::DefaultProcessConfig;
+        use lunatic_networking_api::{NetworkingConfigCtx, VirtualNetwork};
+        use lunatic_process::config::ProcessConfig;
+
+        let mut config = DefaultProcessConfig::default();
+        assert!(config.can_spawn_remotely());
+        config.set_virtual_network(Some(VirtualNetwork::default()));
+        assert!(!config.can_spawn_remotely());
+    }
+
     #[test]
     fn test_accessible_paths() {
         let crates = get_absolute_path(Path::new("crates")).unwrap();
@@ -272,18 +392,3 @@ mod tests {
         assert_eq!(crates, normalize_path(&sneaky_path));
     }
 }
-
-impl Default for DefaultProcessConfig {
-    fn default() -> Self {
-        Self {
-            max_memory: u32::MAX as usize, // = 4 GB
-            max_fuel: None,
-            can_compile_modules: false,
-            can_create_configs: false,
-            can_spawn_processes: false,
-            preopened_dirs: vec![],
-            command_line_arguments: vec![],
-            environment_variables: vec![],
-        }
-    }
-}
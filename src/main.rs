@@ -3,33 +3,60 @@ mod mode;
 use mode::{cargo_test, execution};
 
 use anyhow::Result;
+use lunatic_process::ProcessExit;
 use regex::Regex;
-use std::collections::VecDeque;
 use std::{env, path::PathBuf};
 
-// Lunatic versions under 0.13 implied run
-// This checks whether the 0.12 behaviour is wanted with a regex
-fn is_run_implied() -> bool {
-    if std::env::args().count() < 2 {
-        return false;
+// Flags accepted before the subcommand, with whether they take a value
+const TOP_LEVEL_FLAGS: &[(&str, bool)] = &[
+    ("--config", true),
+    ("--config-profile", true),
+    ("--log-format", true),
+    ("--prometheus", false),
+    ("--prometheus-http", true),
+];
+
+// Returns the index of the first argument that isn't a top-level flag or its value, where the
+// subcommand is expected.
+fn command_position(args: &[String]) -> usize {
+    let mut position = 1;
+    while let Some(arg) = args.get(position) {
+        let flag = arg.split_once('=').map_or(arg.as_str(), |(flag, _)| flag);
+        match TOP_LEVEL_FLAGS.iter().find(|(name, _)| *name == flag) {
+            // `--flag value`
+            Some((_, true)) if !arg.contains('=') => position += 2,
+            // `--flag=value` or `--flag`
+            Some(_) => position += 1,
+            None => break,
+        }
     }
+    position
+}
 
+// Lunatic versions under 0.13 implied run
+// This checks whether the 0.12 behaviour is wanted with a regex
+fn is_run_implied(args: &[String]) -> bool {
     // lunatic <foo.wasm> -> Implied run
     // lunatic run <foo.wasm> -> Explicit run
     // lunatic fdskl <foo.wasm> -> Not implied run
-    let test_re = Regex::new(r"^(--bench|--dir|.+\.wasm)")
+    let test_re = Regex::new(r"^(--bench|--dir|--watch|.+\.wasm)")
         .expect("BUG: Regex error with lunatic::mode::execution::is_run_implied()");
 
-    test_re.is_match(&std::env::args().nth(1).unwrap())
+    match args.get(command_position(args)) {
+        Some(arg) => test_re.is_match(arg),
+        None => false,
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let run_implied = is_run_implied(&args);
     // Run is implied from lunatic 0.12
-    let augmented_args = if is_run_implied() {
-        let mut augmented_args: VecDeque<String> = std::env::args().collect();
-        augmented_args.insert(1, "run".to_owned());
-        Some(augmented_args.into())
+    let augmented_args = if run_implied {
+        let mut augmented_args = args.clone();
+        augmented_args.insert(command_position(&args), "run".to_owned());
+        Some(augmented_args)
     } else {
         None
     };
@@ -55,17 +82,17 @@ async fn main() -> Result<()> {
             let test_regex = format!("{separator}{test_path_matcher}{separator}.*\\.wasm$");
             let test_regex = regex::Regex::new(&test_regex).unwrap();
 
-            let skip_positions = match is_run_implied() {
-                true => 1,
-                false => 2,
+            let skip_positions = match run_implied {
+                true => command_position(&args),
+                false => command_position(&args) + 1,
             };
 
-            // Check if the 3rd argument is a rust wasm build in the `deps` directory
-            // && none of the other arguments indicate a benchmark
-            let mut arguments = env::args().skip(skip_positions);
+            // Check if the argument after the subcommand is a rust wasm build in the `deps`
+            // directory && none of the other arguments indicate a benchmark
+            let mut arguments = args.iter().skip(skip_positions);
             match arguments.next() {
                 Some(wasm_file) => {
-                    test_regex.is_match(&wasm_file) && !arguments.any(|arg| arg == "--bench")
+                    test_regex.is_match(wasm_file) && !arguments.any(|arg| arg == "--bench")
                 }
 
                 None => false,
@@ -74,9 +101,117 @@ async fn main() -> Result<()> {
         Err(_) => false,
     };
 
-    if cargo_test {
+    let result = if cargo_test {
         cargo_test::test(augmented_args).await
     } else {
         execution::execute(augmented_args).await
+    };
+
+    if let Err(error) = &result {
+        match error.downcast_ref::<ProcessExit>() {
+            // A plain exit code is an expected outcome, only explain exits that carry a reason
+            Some(exit) if exit.reason.is_none() => {}
+            Some(exit) => log::error!("{exit}"),
+            None => eprintln!("Error: {error:?}"),
+        }
+    }
+    std::process::exit(exit_status(&result))
+}
+
+// The entry process decides on the exit code when it exits explicitly, other failures like traps
+// exit with 1.
+fn exit_status(result: &Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(error) => error
+            .downcast_ref::<ProcessExit>()
+            .map_or(1, ProcessExit::status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn run_is_implied_after_top_level_flags() {
+        assert!(is_run_implied(&args("lunatic app.wasm")));
+        assert!(is_run_implied(&args("lunatic --dir . app.wasm")));
+        assert!(is_run_implied(&args("lunatic --log-format json app.wasm")));
+        assert!(is_run_implied(&args(
+            "lunatic --config=lunatic.toml --prometheus app.wasm"
+        )));
+        assert_eq!(
+            command_position(&args("lunatic --config f.toml app.wasm")),
+            3
+        );
+
+        assert!(!is_run_implied(&args("lunatic")));
+        assert!(!is_run_implied(&args("lunatic run app.wasm")));
+        assert!(!is_run_implied(&args("lunatic --log-format json ps")));
+        assert!(!is_run_implied(&args(
+            "lunatic --config f.toml node --help"
+        )));
+    }
+
+    // Runs `_start` of the module as the entry process and returns the exit status of the CLI.
+    async fn exit_status_of(name: &str, wat: &str) -> i32 {
+        use std::sync::Arc;
+
+        use lunatic_process::env::{Environments, LunaticEnvironments};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_seq_api::Sequences;
+
+        use crate::mode::common::{run_wasm, RunWasm};
+
+        let dir = std::env::temp_dir().join(format!("lunatic-exit-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{name}.wasm"));
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+
+        let result = run_wasm(RunWasm {
+            path: path.clone(),
+            wasm_args: Vec::new(),
+            dir: Vec::new(),
+            env_vars: Vec::new(),
+            max_memory: None,
+            max_fuel: None,
+            dns_overrides: Default::default(),
+            runtime: WasmtimeRuntime::new(&default_config()).unwrap(),
+            env: LunaticEnvironments::default().create(1).await,
+            distributed: None,
+            sequences: Arc::new(Sequences::in_memory()),
+        })
+        .await;
+        std::fs::remove_file(path).unwrap();
+        exit_status(&result)
+    }
+
+    #[tokio::test]
+    async fn proc_exit_sets_the_exit_status() {
+        let status = exit_status_of(
+            "proc_exit",
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "_start") (call $proc_exit (i32.const 42))))"#,
+        )
+        .await;
+        assert_eq!(status, 42);
+
+        let status = exit_status_of("return", r#"(module (func (export "_start")))"#).await;
+        assert_eq!(status, 0);
+    }
+
+    #[tokio::test]
+    async fn trap_exits_with_1() {
+        let status =
+            exit_status_of("trap", r#"(module (func (export "_start") unreachable))"#).await;
+        assert_eq!(status, 1);
     }
 }
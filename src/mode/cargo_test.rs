@@ -1,7 +1,14 @@
-use std::{collections::HashMap, env, fs, path::Path, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
+use lunatic_networking_api::{NetworkConditions, NetworkingConfigCtx, VirtualNetwork};
 use lunatic_process::{
     env::{Environment, LunaticEnvironment},
     runtimes,
@@ -57,14 +64,34 @@ struct Args {
     #[arg(long)]
     exact: bool,
 
+    /// Give every test its own in-memory network for TCP and UDP, instead of the host network
+    #[arg(long)]
+    virtual_network: bool,
+
+    /// Latency of the virtual network in milliseconds
+    #[arg(long, value_name = "MILLISECONDS", requires = "virtual_network")]
+    network_latency_ms: Option<u64>,
+
+    /// Share of UDP datagrams the virtual network drops, from 0.0 to 1.0
+    #[arg(long, value_name = "SHARE", value_parser = parse_loss, requires = "virtual_network")]
+    network_loss: Option<f64>,
+
     /// Arguments passed to the guest
     #[arg()]
     wasm_args: Vec<String>,
 }
 
+fn parse_loss(s: &str) -> Result<f64> {
+    let loss: f64 = s.parse()?;
+    if !(0.0..=1.0).contains(&loss) {
+        return Err(anyhow!("expected a share from 0.0 to 1.0, got `{s}`"));
+    }
+    Ok(loss)
+}
+
 pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
     // Set logger level to "error" to avoid printing process failures warnings during tests.
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error")).init();
+    super::logging::init(super::logging::LogFormat::Text, "error");
     // Measure test duration
     let now = Instant::now();
 
@@ -206,8 +233,21 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
     println!("\nrunning {} {}", n, if n == 1 { "test" } else { "tests" });
 
     let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    // Find panic output
+    // Modes:
+    // * m: ^ and $ match begin/end of line (not string)
+    // * s: allow . to match \n
+    let panic_regex = regex::Regex::new("(?ms)^thread '.*' panicked at '(.*)', ").unwrap();
 
     let config = Arc::new(config);
+    let network_conditions = if args.virtual_network {
+        Some(NetworkConditions {
+            latency: Duration::from_millis(args.network_latency_ms.unwrap_or_default()),
+            loss: args.network_loss.unwrap_or_default(),
+        })
+    } else {
+        None
+    };
 
     for test_function in test_functions {
         // Skip over filtered out functions
@@ -229,13 +269,22 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
 
         let env = Arc::new(LunaticEnvironment::new(0));
         let registry = Arc::new(RwLock::new(HashMap::new()));
+        let config = match network_conditions {
+            Some(conditions) => {
+                let mut config = DefaultProcessConfig::clone(&config);
+                config.set_virtual_network(Some(VirtualNetwork::new(conditions)));
+                Arc::new(config)
+            }
+            None => config.clone(),
+        };
         let mut state = DefaultProcessState::new(
             env.clone(),
             None,
             runtime.clone(),
             module.clone(),
-            config.clone(),
+            config,
             registry,
+            Default::default(),
         )
         .unwrap();
 
@@ -264,6 +313,7 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
 
         let sender = sender.clone();
         let nocapture = args.nocapture;
+        let panic_regex = panic_regex.clone();
 
         tokio::task::spawn(async move {
             let result = match task.await.unwrap() {
@@ -286,33 +336,25 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
                     }
                 }
                 Err(_err) => {
-                    // Find panic output
-                    let panic_regex =
-                    // Modes:
-                    // * m: ^ and $ match begin/end of line (not string)
-                    // * s: allow . to match \n
-                    regex::Regex::new("(?ms)^thread '.*' panicked at '(.*)', ").unwrap();
-
                     let content = stdout.content();
                     let panic_detected = panic_regex.captures(&content);
 
-                    // If we didn't expect a panic, but got one or were killed by a signal
-                    if test_function.panic.is_none() {
-                        // In case of --nocapture the regex will never match (content is empty).
-                        // At this point we can't be certain if there was a panic.
-                        if panic_detected.is_none() && !nocapture {
-                            stdout.push_str("note: Process trapped or received kill signal\n");
-                        }
-                        TestResult {
-                            name: test_function.function_name,
-                            status: TestStatus::Failed,
-                            stdout,
+                    match test_function.panic {
+                        // If we didn't expect a panic, but got one or were killed by a signal
+                        None => {
+                            // In case of --nocapture the regex will never match (content is empty).
+                            // At this point we can't be certain if there was a panic.
+                            if panic_detected.is_none() && !nocapture {
+                                stdout.push_str("note: Process trapped or received kill signal\n");
+                            }
+                            TestResult {
+                                name: test_function.function_name,
+                                status: TestStatus::Failed,
+                                stdout,
+                            }
                         }
-                    } else {
-                        match panic_detected {
+                        Some(expected_panic) => match panic_detected {
                             Some(panic) => {
-                                // `test_function.panic` is always `Some` in this branch.
-                                let expected_panic = test_function.panic.unwrap();
                                 let panic_message = panic.get(1).map_or("", |m| m.as_str());
                                 if panic_message.contains(&expected_panic) {
                                     TestResult {
@@ -338,20 +380,20 @@ pub(crate) async fn test(augmented_args: Option<Vec<String>>) -> Result<()> {
                                 name: test_function.function_name,
                                 // This is only considered a success if the `expected` panic string
                                 // didn't contain anything.
-                                status: if test_function.panic.as_ref().unwrap() == "" {
+                                status: if expected_panic.is_empty() {
                                     TestStatus::PanicOk
                                 } else {
                                     stdout.push_str(
                                         &format!(
                                             "note: Process received kill signal, but expected a panic that contains `{}`\n",
-                                            test_function.panic.unwrap()
+                                            expected_panic
                                         )
                                     );
                                     TestStatus::PanicFailed
                                 },
                                 stdout,
                             },
-                        }
+                        },
                     }
                 }
             };
@@ -1,16 +1,29 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use clap::Args;
+use clap::{Args, ValueEnum};
 
 use lunatic_distributed::DistributedProcessState;
+use lunatic_networking_api::{DnsOverrides, NetworkingConfigCtx};
+use lunatic_process::config::ProcessConfig;
 use lunatic_process::{
-    env::{Environment, LunaticEnvironment, LunaticEnvironments},
+    env::{Environment, LunaticEnvironment},
+    profiler::Profiler,
     runtimes::{wasmtime::WasmtimeRuntime, RawWasm},
+    scheduler::Scheduler,
     wasm::spawn_wasm,
+    watchdog::{Watchdog, WatchdogConfig},
+    ProcessExit,
 };
 use lunatic_process_api::ProcessConfigCtx;
 use lunatic_runtime::{DefaultProcessConfig, DefaultProcessState};
+use lunatic_seq_api::Sequences;
 
 #[derive(Args, Debug)]
 pub struct WasmArgs {}
@@ -19,39 +32,71 @@ pub struct RunWasm {
     pub path: PathBuf,
     pub wasm_args: Vec<String>,
     pub dir: Vec<PathBuf>,
+    pub env_vars: Vec<(String, String)>,
+    pub max_memory: Option<usize>,
+    pub max_fuel: Option<u64>,
+    pub dns_overrides: DnsOverrides,
 
     pub runtime: WasmtimeRuntime,
-    pub envs: Arc<LunaticEnvironments>,
     pub env: Arc<LunaticEnvironment>,
     pub distributed: Option<DistributedProcessState>,
+    pub sequences: Arc<Sequences>,
 }
 
-pub async fn run_wasm(args: RunWasm) -> Result<()> {
+/// Configuration of an entry process, it's allowed to compile modules, create configurations and
+/// spawn sub-processes.
+pub fn entry_config(
+    path: &Path,
+    wasm_args: Vec<String>,
+    dirs: Vec<PathBuf>,
+    env_vars: Vec<(String, String)>,
+    max_memory: Option<usize>,
+    max_fuel: Option<u64>,
+    dns_overrides: DnsOverrides,
+) -> DefaultProcessConfig {
     let mut config = DefaultProcessConfig::default();
     // Allow initial process to compile modules, create configurations and spawn sub-processes
     config.set_can_compile_modules(true);
     config.set_can_create_configs(true);
     config.set_can_spawn_processes(true);
-
-    // Path to wasm file
-    let path = args.path;
+    // Limits apply to the initial process and are inherited by all sub-processes
+    if let Some(max_memory) = max_memory {
+        config.set_max_memory(max_memory);
+    }
+    config.set_max_fuel(max_fuel);
 
     // Set correct command line arguments for the guest
     let filename = path.file_name().unwrap().to_string_lossy().to_string();
     let mut wasi_args = vec![filename];
-    wasi_args.extend(args.wasm_args);
+    wasi_args.extend(wasm_args);
     config.set_command_line_arguments(wasi_args);
 
-    // Inherit environment variables
-    config.set_environment_variables(std::env::vars().collect());
+    config.set_environment_variables(env_vars);
+    config.set_dns_overrides(dns_overrides);
 
-    // Always preopen the current dir
-    config.preopen_dir(".");
-    for dir in args.dir {
+    for dir in dirs {
         if let Some(s) = dir.as_os_str().to_str() {
             config.preopen_dir(s);
         }
     }
+    config
+}
+
+pub async fn run_wasm(args: RunWasm) -> Result<()> {
+    // Path to wasm file
+    let path = args.path;
+    // Modules started from the command line can always access the current dir
+    let mut dirs = vec![PathBuf::from(".")];
+    dirs.extend(args.dir);
+    let config = entry_config(
+        &path,
+        args.wasm_args,
+        dirs,
+        args.env_vars,
+        args.max_memory,
+        args.max_fuel,
+        args.dns_overrides,
+    );
 
     // Spawn main process
     let module = std::fs::read(&path).map_err(|err| match err.kind() {
@@ -64,14 +109,18 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
         module.into()
     };
 
-    let module = Arc::new(args.runtime.compile_module::<DefaultProcessState>(module)?);
+    let module = Arc::new(
+        args.runtime
+            .compile_module_cached::<DefaultProcessState>(module)?,
+    );
     let state = DefaultProcessState::new(
         args.env.clone(),
         args.distributed,
         args.runtime.clone(),
         module.clone(),
         Arc::new(config),
-        Default::default(),
+        args.env.registry().clone(),
+        args.sequences,
     )
     .unwrap();
 
@@ -92,7 +141,329 @@ pub async fn run_wasm(args: RunWasm) -> Result<()> {
     ))?;
 
     // Wait on the main process to finish
-    task.await.map(|_| ()).map_err(|e| anyhow!(e.to_string()))
+    match task.await.map_err(|e| anyhow!(e.to_string()))? {
+        Ok(_) => Ok(()),
+        // An explicit exit sets the exit code of the CLI
+        Err(error) if error.is::<ProcessExit>() => Err(error),
+        Err(error) => Err(error.context(format!(
+            "Process {}::_start() failed",
+            path.to_string_lossy()
+        ))),
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct WatchdogArgs {
+    /// Report processes that spend more than the given number of milliseconds in a host call
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub slow_host_call_ms: Option<u64>,
+
+    /// Report executor stalls longer than the given number of milliseconds
+    #[arg(long, value_name = "MILLISECONDS", requires = "slow_host_call_ms")]
+    pub executor_stall_ms: Option<u64>,
+
+    /// Kill processes that spend too much time in a host call, instead of only reporting them
+    #[arg(long, requires = "slow_host_call_ms")]
+    pub kill_slow_host_calls: bool,
+}
+
+impl WatchdogArgs {
+    /// Attaches a watchdog to the runtime if slow host call detection is enabled.
+    pub fn apply(&self, runtime: &mut WasmtimeRuntime) {
+        if let Some(slow_host_call_ms) = self.slow_host_call_ms {
+            let mut config = WatchdogConfig {
+                slow_host_call: Duration::from_millis(slow_host_call_ms),
+                kill_slow_processes: self.kill_slow_host_calls,
+                ..Default::default()
+            };
+            if let Some(executor_stall_ms) = self.executor_stall_ms {
+                config.executor_stall = Duration::from_millis(executor_stall_ms);
+            }
+            runtime.set_watchdog(Watchdog::spawn(config));
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SchedulerArgs {
+    /// Share execution time fairly between environments, in periods of the given number of
+    /// milliseconds
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub fair_share_period_ms: Option<u64>,
+
+    /// Weight of an environment's share of execution time, e.g. `--env-weight 2=3`. Environments
+    /// have a weight of 1 by default
+    #[arg(
+        long,
+        value_name = "ENV_ID=WEIGHT",
+        value_parser = parse_env_weight,
+        requires = "fair_share_period_ms"
+    )]
+    pub env_weight: Vec<(u64, u32)>,
+}
+
+impl SchedulerArgs {
+    /// Attaches a scheduler to the runtime if fair sharing is enabled.
+    pub fn apply(&self, runtime: &mut WasmtimeRuntime) {
+        if let Some(period_ms) = self.fair_share_period_ms {
+            let period = Duration::from_millis(period_ms);
+            // Processes run on one executor thread per core
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let scheduler = Scheduler::new(period, period * threads as u32);
+            for (environment_id, weight) in &self.env_weight {
+                scheduler.set_weight(*environment_id, *weight);
+            }
+            runtime.set_scheduler(Arc::new(scheduler));
+        }
+    }
+}
+
+fn parse_env_weight(s: &str) -> Result<(u64, u32)> {
+    let (environment_id, weight) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected ENV_ID=WEIGHT, got `{s}`"))?;
+    let weight = weight.parse()?;
+    if weight == 0 {
+        return Err(anyhow!(
+            "weight of environment {environment_id} must be positive"
+        ));
+    }
+    Ok((environment_id.parse()?, weight))
+}
+
+#[derive(Args, Debug)]
+pub struct EnvArgs {
+    /// Set an environment variable for the guest, e.g. `--env RUST_LOG=info`
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub env: Vec<(String, String)>,
+
+    /// Pass host environment variables to the guest, by name or by prefix ending in `*`
+    #[arg(long, value_name = "NAME|PREFIX*")]
+    pub env_inherit: Vec<String>,
+}
+
+impl EnvArgs {
+    /// Adds the variables from the config file that weren't set on the command line.
+    pub fn merge(&mut self, env: HashMap<String, String>, env_inherit: Vec<String>) {
+        let mut env: Vec<_> = env
+            .into_iter()
+            .filter(|(key, _)| !self.env.iter().any(|(k, _)| k == key))
+            .collect();
+        env.sort();
+        self.env.append(&mut env);
+        self.env_inherit.extend(env_inherit);
+    }
+
+    /// The guest's environment variables. Host variables are only passed on if they match one of
+    /// the `--env-inherit` patterns, and explicitly set variables take precedence over them.
+    pub fn vars(&self) -> Vec<(String, String)> {
+        let mut vars: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| {
+                self.env_inherit
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => key.starts_with(prefix),
+                        None => key == pattern,
+                    })
+            })
+            .filter(|(key, _)| !self.env.iter().any(|(k, _)| k == key))
+            .collect();
+        vars.extend(self.env.iter().cloned());
+        vars
+    }
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE, got `{s}`"))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[derive(Args, Debug)]
+pub struct LimitArgs {
+    /// Memory limit of each process, in bytes or with a K, M or G suffix
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_size)]
+    pub max_memory: Option<usize>,
+
+    /// Fuel limit of each process, one unit of fuel is around 100k instructions
+    #[arg(long, value_name = "FUEL")]
+    pub max_fuel: Option<u64>,
+
+    /// Maximum number of processes running at the same time
+    #[arg(long, value_name = "COUNT")]
+    pub max_processes: Option<usize>,
+}
+
+impl LimitArgs {
+    /// Fills in the limits from the config file that weren't passed on the command line.
+    pub fn merge(&mut self, limits: super::config_file::LimitsSection) {
+        self.max_memory = self.max_memory.or(limits.max_memory);
+        self.max_fuel = self.max_fuel.or(limits.max_fuel);
+        self.max_processes = self.max_processes.or(limits.max_processes);
+    }
+}
+
+/// Parses a number of bytes, optionally followed by a K, M or G suffix.
+pub fn parse_memory_size(s: &str) -> Result<usize> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| anyhow!("invalid memory size `{s}`"))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ProfileFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct ProfileArgs {
+    /// Record fuel, wall time, messages and spawns of every process and report them on exit, or
+    /// after every run with `--watch`
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Format of the profile report
+    #[arg(long, value_enum, default_value_t, requires = "profile")]
+    pub profile_format: ProfileFormat,
+
+    /// Write the profile report to a file instead of stderr
+    #[arg(long, value_name = "FILE", requires = "profile")]
+    pub profile_output: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    /// Attaches a profiler to the runtime if profiling is enabled.
+    pub fn apply(&self, runtime: &mut WasmtimeRuntime) {
+        if self.profile {
+            runtime.set_profiler(Profiler::new());
+        }
+    }
+
+    /// Writes the report of the runtime's profiler, the hottest processes first.
+    pub fn report(&self, runtime: &WasmtimeRuntime) -> Result<()> {
+        let Some(profiler) = runtime.profiler() else {
+            return Ok(());
+        };
+        let report = profiler.report();
+        let mut out: Box<dyn std::io::Write> = match &self.profile_output {
+            Some(path) => Box::new(
+                std::fs::File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?,
+            ),
+            None => Box::new(std::io::stderr()),
+        };
+        match self.profile_format {
+            ProfileFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, &report)?;
+                writeln!(out)?;
+            }
+            ProfileFormat::Table => {
+                writeln!(
+                    out,
+                    "{:>5} {:>8} {:>14} {:>12} {:>9} {:>7}  FUNCTION",
+                    "ENV", "PROCESS", "FUEL", "WALL MS", "MESSAGES", "SPAWNS"
+                )?;
+                for profile in report.processes {
+                    let fuel = profile
+                        .fuel
+                        .map_or_else(|| "-".to_string(), |f| f.to_string());
+                    writeln!(
+                        out,
+                        "{:>5} {:>8} {:>14} {:>12.1} {:>9} {:>7}  {}",
+                        profile.environment_id,
+                        profile.process_id,
+                        fuel,
+                        profile.wall_time_ms,
+                        profile.messages,
+                        profile.spawns,
+                        profile.function
+                    )?;
+                }
+                if report.omitted > 0 {
+                    writeln!(out, "... and {} cooler finished processes", report.omitted)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CacheArgs {
+    /// Always compile modules, instead of reusing precompiled ones from ~/.cache/lunatic
+    #[arg(long)]
+    pub no_cache: bool,
+}
+
+impl CacheArgs {
+    /// Enables the module cache, unless disabled or there is no home directory to put it in.
+    pub fn apply(&self, runtime: &mut WasmtimeRuntime) {
+        if self.no_cache {
+            return;
+        }
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")));
+        if let Some(cache_home) = cache_home {
+            let dir = cache_home.join("lunatic").join("modules");
+            // Modules are compiled every time without the cache, which is slower but works
+            if let Err(e) = runtime.set_module_cache(dir) {
+                log::warn!("Module cache disabled: {e:?}");
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DnsArgs {
+    /// Resolve a host name to a fixed IP address, e.g. `--dns-host db=10.0.0.5`
+    #[arg(long, value_name = "NAME=IP", value_parser = parse_dns_host)]
+    pub dns_host: Vec<(String, std::net::IpAddr)>,
+
+    /// Search domain appended to host names without a dot during resolution
+    #[arg(long, value_name = "DOMAIN")]
+    pub dns_search: Vec<String>,
+}
+
+impl DnsArgs {
+    pub fn overrides(&self) -> DnsOverrides {
+        let mut overrides = DnsOverrides::default();
+        for (name, ip) in self.dns_host.iter() {
+            overrides.add_host(name, *ip);
+        }
+        for domain in self.dns_search.iter() {
+            overrides.add_search_domain(domain.clone());
+        }
+        overrides
+    }
+}
+
+fn parse_dns_host(s: &str) -> Result<(String, std::net::IpAddr)> {
+    let (name, ip) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected NAME=IP, got `{s}`"))?;
+    Ok((name.to_string(), ip.parse()?))
+}
+
+/// Opens the node-level sequences, persisted under `data_dir` if one is given.
+pub fn open_sequences(data_dir: Option<&Path>) -> Result<Arc<Sequences>> {
+    let sequences = match data_dir {
+        Some(data_dir) => Sequences::open(data_dir.join("sequences"))
+            .with_context(|| format!("Failed to open data directory {}", data_dir.display()))?,
+        None => Sequences::in_memory(),
+    };
+    Ok(Arc::new(sequences))
 }
 
 #[cfg(feature = "prometheus")]
@@ -107,6 +478,14 @@ pub struct PrometheusArgs {
     pub prometheus_http: Option<std::net::SocketAddr>,
 }
 
+#[cfg(feature = "prometheus")]
+impl PrometheusArgs {
+    pub fn merge(&mut self, metrics: super::config_file::MetricsSection) {
+        self.prometheus |= metrics.prometheus;
+        self.prometheus_http = self.prometheus_http.or(metrics.prometheus_http);
+    }
+}
+
 #[cfg(feature = "prometheus")]
 pub fn prometheus(http_socket: Option<std::net::SocketAddr>, node_id: Option<u64>) -> Result<()> {
     metrics_exporter_prometheus::PrometheusBuilder::new()
@@ -115,3 +494,23 @@ pub fn prometheus(http_socket: Option<std::net::SocketAddr>, node_id: Option<u64
         .install()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_memory_size;
+
+    #[test]
+    fn memory_sizes() {
+        assert_eq!(parse_memory_size("1024").unwrap(), 1024);
+        assert_eq!(parse_memory_size("4K").unwrap(), 4 << 10);
+        assert_eq!(parse_memory_size("64m").unwrap(), 64 << 20);
+        assert_eq!(parse_memory_size("2G").unwrap(), 2 << 30);
+
+        assert!(parse_memory_size("").is_err());
+        assert!(parse_memory_size("M").is_err());
+        assert!(parse_memory_size("-1M").is_err());
+        assert!(parse_memory_size("1.5G").is_err());
+        assert!(parse_memory_size("10T").is_err());
+        assert!(parse_memory_size(&format!("{}G", usize::MAX)).is_err());
+    }
+}
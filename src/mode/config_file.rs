@@ -0,0 +1,355 @@
+//! Settings loaded from a `lunatic.toml` file.
+//!
+//! Every setting in the file mirrors a command line flag. Flags passed on the command line take
+//! precedence over the values from the file. Relative paths in the file are relative to the
+//! directory the file is in.
+//!
+//! String values can reference environment variables with `${env:NAME}` and secrets with
+//! `${secret:NAME}`. A secret is the content of the file `NAME` in the secrets directory, without
+//! the trailing newline. The secrets directory is `$LUNATIC_SECRETS_DIR`, or `/run/secrets` where
+//! Docker and Kubernetes mount secrets.
+//!
+//! Settings for a specific deployment target go into `[profile.<name>]` tables, which are laid
+//! over the rest of the file when the profile is selected. References in profiles that aren't
+//! selected are never resolved:
+//!
+//! ```toml
+//! [node]
+//! control = "http://127.0.0.1:3030/"
+//!
+//! [profile.prod.node]
+//! control = "${env:CONTROL_URL}"
+//! ```
+
+use std::{collections::HashMap, net::SocketAddr, path::Path, path::PathBuf};
+
+const DEFAULT_SECRETS_DIR: &str = "/run/secrets";
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use toml::{value::Table, Value};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Host directories the entry process gets access to
+    pub dirs: Vec<PathBuf>,
+    /// Directory used to persist node data
+    pub data_dir: Option<PathBuf>,
+    /// Environment variables set for the entry process
+    pub env: HashMap<String, String>,
+    /// Host environment variables passed to the entry process, by name or by prefix ending in `*`
+    pub env_inherit: Vec<String>,
+    pub node: NodeSection,
+    pub control: ControlSection,
+    pub limits: LimitsSection,
+    pub metrics: MetricsSection,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSection {
+    /// Control server register URL
+    pub control: Option<String>,
+    pub bind_socket: Option<SocketAddr>,
+    pub wasm: Option<PathBuf>,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlSection {
+    pub bind_socket: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    /// Memory limit of each process, in bytes or a string like "512M"
+    #[serde(deserialize_with = "memory_size")]
+    pub max_memory: Option<usize>,
+    pub max_fuel: Option<u64>,
+    pub max_processes: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub prometheus: bool,
+    pub prometheus_http: Option<SocketAddr>,
+}
+
+impl ConfigFile {
+    /// Loads the config file at `path`, with the `[profile.<name>]` overlay of `profile` applied.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let secrets = std::env::var_os("LUNATIC_SECRETS_DIR")
+            .map_or_else(|| PathBuf::from(DEFAULT_SECRETS_DIR), PathBuf::from);
+        let mut file = Self::parse(&content, profile, &secrets)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            file.resolve_paths(dir);
+        }
+        Ok(file)
+    }
+
+    // Makes relative paths relative to `dir`, the directory of the config file, instead of the
+    // working directory.
+    fn resolve_paths(&mut self, dir: &Path) {
+        let paths = self
+            .dirs
+            .iter_mut()
+            .chain(self.data_dir.as_mut())
+            .chain(self.node.wasm.as_mut());
+        for path in paths {
+            *path = dir.join(&*path);
+        }
+    }
+
+    fn parse(content: &str, profile: Option<&str>, secrets: &Path) -> Result<Self> {
+        let mut table: Table = toml::from_str(content)?;
+        let profiles = table.remove("profile");
+        if let Some(name) = profile {
+            let profile = match profiles.as_ref().and_then(|profiles| profiles.get(name)) {
+                Some(Value::Table(profile)) => profile.clone(),
+                Some(_) => bail!("Profile `{name}` must be a table"),
+                None => bail!("Profile `{name}` is not defined"),
+            };
+            overlay(&mut table, profile);
+        }
+        let mut value = Value::Table(table);
+        interpolate_value(&mut value, secrets)?;
+        Ok(value.try_into()?)
+    }
+}
+
+// Lays the values of `profile` over `base`, merging tables present in both.
+fn overlay(base: &mut Table, profile: Table) {
+    for (key, value) in profile {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(table)) => overlay(base_table, table),
+            (Some(slot), value) => *slot = value,
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn interpolate_value(value: &mut Value, secrets: &Path) -> Result<()> {
+    match value {
+        Value::String(text) => *text = interpolate(text, secrets)?,
+        Value::Array(values) => values
+            .iter_mut()
+            .try_for_each(|value| interpolate_value(value, secrets))?,
+        Value::Table(table) => table
+            .iter_mut()
+            .try_for_each(|(_, value)| interpolate_value(value, secrets))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+// Replaces the `${env:NAME}` references in `text` with the values of the environment variables,
+// and the `${secret:NAME}` references with the files in the `secrets` directory.
+fn interpolate(text: &str, secrets: &Path) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let (reference, after) = rest[start + 2..]
+            .split_once('}')
+            .ok_or_else(|| anyhow!("Unclosed reference in `{text}`"))?;
+        match reference.split_once(':') {
+            Some(("env", name)) => {
+                let value = std::env::var(name)
+                    .with_context(|| format!("Environment variable `{name}` is not set"))?;
+                result.push_str(&value);
+            }
+            Some(("secret", name)) => result.push_str(&secret(secrets, name)?),
+            _ => bail!(
+                "Unsupported reference `${{{reference}}}`, expected `${{env:NAME}}` or \
+                 `${{secret:NAME}}`"
+            ),
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// Reads the secret `name` from the `secrets` directory.
+fn secret(secrets: &Path, name: &str) -> Result<String> {
+    // Names can't point outside of the secrets directory
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        bail!("Invalid secret name `{name}`");
+    }
+    let path = secrets.join(name);
+    let value = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read secret `{name}` from {}", path.display()))?;
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    Ok(value.strip_suffix('\r').unwrap_or(value).to_string())
+}
+
+fn memory_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MemorySize {
+        Bytes(usize),
+        Text(String),
+    }
+
+    match MemorySize::deserialize(deserializer)? {
+        MemorySize::Bytes(bytes) => Ok(Some(bytes)),
+        MemorySize::Text(text) => super::common::parse_memory_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            dirs = ["/tmp"]
+
+            [env]
+            RUST_LOG = "info"
+
+            [node]
+            control = "http://10.0.0.1:3030/"
+            tags = { region = "eu" }
+
+            [limits]
+            max_memory = "64M"
+            max_processes = 100
+
+            [metrics]
+            prometheus = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.dirs, vec![PathBuf::from("/tmp")]);
+        assert_eq!(file.env["RUST_LOG"], "info");
+        assert_eq!(file.node.control.as_deref(), Some("http://10.0.0.1:3030/"));
+        assert_eq!(file.node.tags["region"], "eu");
+        assert_eq!(file.limits.max_memory, Some(64 << 20));
+        assert_eq!(file.limits.max_processes, Some(100));
+        assert!(file.limits.max_fuel.is_none());
+        assert!(file.metrics.prometheus);
+        assert!(file.control.bind_socket.is_none());
+
+        assert!(toml::from_str::<ConfigFile>("unknown = 1").is_err());
+    }
+
+    const PROFILES: &str = r#"
+        dirs = ["/tmp"]
+
+        [node]
+        control = "http://127.0.0.1:3030/"
+        tags = { region = "eu" }
+
+        [profile.prod]
+        dirs = ["/srv"]
+
+        [profile.prod.node]
+        control = "http://${env:LUNATIC_CONFIG_TEST_HOST}:3030/"
+
+        [profile.staging.node]
+        control = "${env:LUNATIC_CONFIG_TEST_UNSET}"
+    "#;
+
+    fn no_secrets() -> &'static Path {
+        Path::new("/nonexistent")
+    }
+
+    #[test]
+    fn profile_overlay() {
+        std::env::set_var("LUNATIC_CONFIG_TEST_HOST", "10.0.0.1");
+
+        // The staging profile references an unset variable, but it's not selected
+        let file = ConfigFile::parse(PROFILES, None, no_secrets()).unwrap();
+        assert_eq!(file.dirs, vec![PathBuf::from("/tmp")]);
+        assert_eq!(file.node.control.as_deref(), Some("http://127.0.0.1:3030/"));
+
+        let file = ConfigFile::parse(PROFILES, Some("prod"), no_secrets()).unwrap();
+        assert_eq!(file.dirs, vec![PathBuf::from("/srv")]);
+        assert_eq!(file.node.control.as_deref(), Some("http://10.0.0.1:3030/"));
+        // Tables are merged with the overlay
+        assert_eq!(file.node.tags["region"], "eu");
+
+        assert!(ConfigFile::parse(PROFILES, Some("dev"), no_secrets()).is_err());
+        // Selecting it resolves the reference
+        assert!(ConfigFile::parse(PROFILES, Some("staging"), no_secrets()).is_err());
+    }
+
+    #[test]
+    fn env_interpolation() {
+        std::env::set_var("LUNATIC_CONFIG_TEST_LEVEL", "debug");
+        assert_eq!(
+            interpolate(
+                "${env:LUNATIC_CONFIG_TEST_LEVEL},hyper=${env:LUNATIC_CONFIG_TEST_LEVEL}",
+                no_secrets()
+            )
+            .unwrap(),
+            "debug,hyper=debug"
+        );
+        assert_eq!(
+            interpolate("no references", no_secrets()).unwrap(),
+            "no references"
+        );
+
+        assert!(interpolate("${env:LUNATIC_CONFIG_TEST_UNSET}", no_secrets()).is_err());
+        assert!(interpolate("${vault:db_url}", no_secrets()).is_err());
+        assert!(interpolate("${env:LUNATIC_CONFIG_TEST_LEVEL", no_secrets()).is_err());
+    }
+
+    #[test]
+    fn secret_interpolation() {
+        let dir = std::env::temp_dir().join(format!("lunatic-secrets-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("db_url"), "postgres://db:5432\n").unwrap();
+
+        let url = interpolate("${secret:db_url}/app", &dir);
+        let missing = interpolate("${secret:api_key}", &dir);
+        let outside = interpolate("${secret:../db_url}", &dir.join("nested"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(url.unwrap(), "postgres://db:5432/app");
+        assert!(missing.is_err());
+        assert!(outside.is_err());
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_against_the_config_file() {
+        let dir = std::env::temp_dir().join(format!("lunatic-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lunatic.toml");
+        std::fs::write(
+            &path,
+            r#"
+            dirs = ["static", "/tmp"]
+            data_dir = "data"
+
+            [node]
+            wasm = "../app.wasm"
+            "#,
+        )
+        .unwrap();
+
+        let file = ConfigFile::load(&path, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(file.dirs, vec![dir.join("static"), PathBuf::from("/tmp")]);
+        assert_eq!(file.data_dir, Some(dir.join("data")));
+        assert_eq!(file.node.wasm, Some(dir.join("../app.wasm")));
+    }
+}
@@ -3,12 +3,21 @@ use std::net::{SocketAddr, TcpListener};
 use anyhow::{anyhow, Result};
 use clap::Parser;
 
+use super::config_file::ConfigFile;
+
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
     #[arg(long, value_name = "CONTROL_SERVER_SOCKET")]
     bind_socket: Option<SocketAddr>,
 }
 
+impl Args {
+    /// Fills in the settings from the config file that weren't passed on the command line.
+    pub(crate) fn merge(&mut self, file: ConfigFile) {
+        self.bind_socket = self.bind_socket.or(file.control.bind_socket);
+    }
+}
+
 pub(crate) async fn start(args: Args) -> Result<()> {
     if let Some(socket) = args.bind_socket {
         log::info!("Register URL: http://{}/", socket);
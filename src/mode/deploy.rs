@@ -0,0 +1,189 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use lunatic_control::api::Registration;
+use lunatic_distributed::{
+    control,
+    distributed::{self, message::Spawn, server::gen_node_cert},
+    quic,
+};
+use lunatic_runtime::DefaultProcessConfig;
+use uuid::Uuid;
+
+use super::common::{entry_config, DnsArgs, EnvArgs, LimitArgs};
+
+#[derive(Parser, Debug)]
+pub(crate) struct Args {
+    /// .wasm file to deploy
+    #[arg(index = 1)]
+    path: PathBuf,
+
+    /// Arguments passed to the guest
+    #[arg(index = 2)]
+    wasm_args: Vec<String>,
+
+    /// Control server register URL
+    #[arg(
+        long,
+        value_name = "CONTROL_URL",
+        default_value = "http://127.0.0.1:3030/"
+    )]
+    control: String,
+
+    /// Node to spawn the process on, by default the first node known to the control server
+    #[arg(long, value_name = "NODE_ID")]
+    node: Option<u64>,
+
+    /// Environment on the node to spawn the process in
+    #[arg(long, value_name = "ENVIRONMENT_ID", default_value_t = 1)]
+    environment_id: u64,
+
+    /// Function the process starts with
+    #[arg(long, default_value = "_start")]
+    function: String,
+
+    /// Grant access to the given directories on the node, relative to its working directory.
+    /// Without it the process can't access any files on the node
+    #[arg(long, value_name = "DIRECTORY")]
+    dir: Vec<PathBuf>,
+
+    #[command(flatten)]
+    env: EnvArgs,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+
+    #[command(flatten)]
+    dns: DnsArgs,
+}
+
+pub(crate) async fn start(args: Args) -> Result<()> {
+    if args.limits.max_processes.is_some() {
+        return Err(anyhow!(
+            "--max-processes is a node setting and can't be set per deployment"
+        ));
+    }
+    let module = std::fs::read(&args.path)
+        .with_context(|| format!("Failed to read module {}", args.path.display()))?;
+
+    // Nodes only accept spawn requests from peers with a certificate signed by the control
+    // server, so register like a node would for the duration of the deployment
+    let http_client = reqwest::Client::new();
+    let name = Uuid::new_v4();
+    let cert = gen_node_cert(&name.as_hyphenated().to_string())
+        .with_context(|| "Failed to generate CSR and PK")?;
+    let reg = control::Client::register(
+        &http_client,
+        args.control
+            .parse()
+            .with_context(|| "Parsing control URL")?,
+        name,
+        cert.serialize_request_pem()?,
+    )
+    .await?;
+    // Nobody connects to the deploying client, so it doesn't start a node
+    let control_client = control::Client::without_node(http_client, reg.clone()).await?;
+
+    let key = cert.serialize_private_key_pem();
+    let result = deploy(&args, &control_client, &reg, &key, module).await;
+    control_client.deregister().await.ok();
+    let (node_id, process_id) = result?;
+    println!(
+        "Spawned process {process_id} in environment {} on node {node_id}",
+        args.environment_id
+    );
+    Ok(())
+}
+
+// Uploads the module to the control server and spawns it on the target node. Returns the node
+// and process ID.
+async fn deploy(
+    args: &Args,
+    control_client: &control::Client,
+    reg: &Registration,
+    key: &str,
+    module: Vec<u8>,
+) -> Result<(u64, u64)> {
+    let node_id = match args.node {
+        Some(node_id) => node_id,
+        None => control_client
+            .node_ids()
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No nodes are registered with the control server"))?,
+    };
+
+    let quic_client = quic::new_quic_client(
+        &reg.root_cert,
+        reg.cert_pem_chain
+            .first()
+            .ok_or_else(|| anyhow!("No certificate available for QUIC client"))?,
+        key,
+    )
+    .with_context(|| "Failed to create mTLS QUIC client")?;
+    let distributed_client = distributed::Client::new(
+        control_client.node_id(),
+        control_client.clone(),
+        quic_client,
+    )
+    .await?;
+
+    let module = control_client.add_module(module).await?;
+    let module_id = module
+        .id
+        .ok_or_else(|| anyhow!("Control server didn't return a module ID"))?;
+    let config = deploy_config(args);
+    let process_id = distributed_client
+        .spawn(
+            node_id,
+            Spawn {
+                environment_id: args.environment_id,
+                module_id,
+                function: args.function.clone(),
+                params: vec![],
+                config: rmp_serde::to_vec(&config)?,
+            },
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to spawn process on node {node_id}: {e:?}"))?;
+    Ok((node_id, process_id))
+}
+
+// Configuration of the deployed process.
+fn deploy_config(args: &Args) -> DefaultProcessConfig {
+    entry_config(
+        &args.path,
+        args.wasm_args.clone(),
+        args.dir.clone(),
+        args.env.vars(),
+        args.limits.max_memory,
+        args.limits.max_fuel,
+        args.dns.overrides(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_directories_are_only_accessible_when_granted() {
+        let args = Args::try_parse_from(["deploy", "app.wasm"]).unwrap();
+        assert!(deploy_config(&args).preopened_dirs().is_empty());
+
+        let args =
+            Args::try_parse_from(["deploy", "app.wasm", "--dir", ".", "--dir", "data"]).unwrap();
+        assert_eq!(deploy_config(&args).preopened_dirs(), [".", "data"]);
+    }
+
+    #[test]
+    fn node_settings_are_rejected() {
+        let args = Args::try_parse_from(["deploy", "app.wasm", "--max-processes", "10"]).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let error = runtime.block_on(start(args)).unwrap_err();
+        assert!(error.to_string().contains("--max-processes"));
+    }
+}
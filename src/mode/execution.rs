@@ -1,12 +1,31 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
+use super::{
+    config_file::ConfigFile,
+    logging::{self, LogFormat},
+};
+
 #[derive(Parser, Debug)]
 #[command(version)]
 pub struct Args {
     #[command(subcommand)]
     command: Commands,
 
+    /// Load settings from a `lunatic.toml` file, command line flags take precedence
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Apply the `[profile.<NAME>]` settings of the config file
+    #[arg(long, global = true, value_name = "NAME", requires = "config")]
+    config_profile: Option<String>,
+
+    /// Format of the log output
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
+
     #[cfg(feature = "prometheus")]
     #[command(flatten)]
     prometheus: super::common::PrometheusArgs,
@@ -27,20 +46,39 @@ enum Commands {
     Control(super::control::Args),
     /// Starts a node
     Node(super::node::Args),
+    /// Uploads a .wasm file to the control server and spawns it on a node
+    Deploy(super::deploy::Args),
+    /// Lists the nodes registered with the control server and their processes
+    Ps(super::ps::Args),
 }
 
 pub(crate) async fn execute(augmented_args: Option<Vec<String>>) -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
-
     let args = match augmented_args {
         Some(a) => Args::parse_from(a),
         None => Args::parse(),
     };
+    logging::init(args.log_format, "warn");
+
+    let file = match args.config {
+        Some(path) => ConfigFile::load(&path, args.config_profile.as_deref())?,
+        None => ConfigFile::default(),
+    };
 
     match args.command {
         Commands::Init => super::init::start(),
-        Commands::Run(a) => super::run::start(a).await,
-        Commands::Control(a) => super::control::start(a).await,
-        Commands::Node(a) => super::node::start(a).await,
+        Commands::Run(mut a) => {
+            a.merge(file);
+            super::run::start(a).await
+        }
+        Commands::Control(mut a) => {
+            a.merge(file);
+            super::control::start(a).await
+        }
+        Commands::Node(mut a) => {
+            a.merge(file);
+            super::node::start(a).await
+        }
+        Commands::Deploy(a) => super::deploy::start(a).await,
+        Commands::Ps(a) => super::ps::start(a).await,
     }
 }
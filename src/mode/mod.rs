@@ -5,9 +5,14 @@
 pub(crate) mod cargo_test;
 // Default mode, if no other mode could be detected.
 pub(crate) mod execution;
+// Arguments and helpers shared between the modes, like running the entry module.
+pub(crate) mod common;
 
-mod common;
+mod config_file;
 mod control;
+mod deploy;
 mod init;
+mod logging;
 mod node;
+mod ps;
 mod run;
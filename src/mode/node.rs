@@ -5,12 +5,13 @@ use std::{
 
 use clap::Parser;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Context, Result};
+use lunatic_control::api::NodeStats;
 use lunatic_distributed::{
     control::{self},
-    distributed::{self, server::ServerCtx},
+    distributed::{self, server::ServerCtx, Maintenance},
     quic,
 };
 use lunatic_process::{
@@ -20,17 +21,21 @@ use lunatic_process::{
 use lunatic_runtime::DefaultProcessState;
 use uuid::Uuid;
 
-use crate::mode::common::{run_wasm, RunWasm};
+use crate::mode::{
+    common::{
+        open_sequences, run_wasm, CacheArgs, DnsArgs, EnvArgs, LimitArgs, ProfileArgs, RunWasm,
+        SchedulerArgs, WatchdogArgs,
+    },
+    config_file::ConfigFile,
+};
+
+const DEFAULT_CONTROL_URL: &str = "http://127.0.0.1:3030/";
 
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
-    /// Control server register URL
-    #[arg(
-        index = 1,
-        value_name = "CONTROL_URL",
-        default_value = "http://127.0.0.1:3030/"
-    )]
-    control: String,
+    /// Control server register URL [default: http://127.0.0.1:3030/]
+    #[arg(index = 1, value_name = "CONTROL_URL")]
+    control: Option<String>,
 
     #[arg(long, value_name = "NODE_SOCKET")]
     bind_socket: Option<SocketAddr>,
@@ -38,15 +43,78 @@ pub(crate) struct Args {
     #[arg(long, value_name = "WASM_MODULE")]
     wasm: Option<PathBuf>,
 
+    /// Directory used to persist node data, like sequences and the node identity
+    #[arg(long, value_name = "DIRECTORY")]
+    data_dir: Option<PathBuf>,
+
     /// Define key=value variable to store as node information
     #[arg(long, value_parser = parse_key_val, action = clap::ArgAction::Append)]
     tag: Vec<(String, String)>,
 
+    /// How long processes get to finish after the shutdown request on SIGINT or SIGTERM, before
+    /// they are killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    shutdown_grace_secs: u64,
+
+    /// Longest time the node stays in maintenance mode, entered with SIGUSR1 and left with SIGUSR2
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    max_maintenance_secs: u64,
+
+    /// Host directories the entry module gets access to, from the config file
+    #[arg(skip)]
+    dir: Vec<PathBuf>,
+
+    #[command(flatten)]
+    env: EnvArgs,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+
+    #[command(flatten)]
+    watchdog: WatchdogArgs,
+
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    #[command(flatten)]
+    profile: ProfileArgs,
+
+    #[command(flatten)]
+    scheduler: SchedulerArgs,
+
+    #[command(flatten)]
+    dns: DnsArgs,
+
     #[cfg(feature = "prometheus")]
     #[command(flatten)]
     prometheus: super::common::PrometheusArgs,
 }
 
+impl Args {
+    /// Fills in the settings from the config file that weren't passed on the command line.
+    pub(crate) fn merge(&mut self, file: ConfigFile) {
+        self.control = self.control.take().or(file.node.control);
+        self.bind_socket = self.bind_socket.or(file.node.bind_socket);
+        self.wasm = self.wasm.take().or(file.node.wasm);
+        self.data_dir = self.data_dir.take().or(file.data_dir);
+        // Tags from the command line override tags with the same key from the file
+        let mut tags: Vec<_> = file
+            .node
+            .tags
+            .into_iter()
+            .filter(|(key, _)| !self.tag.iter().any(|(k, _)| k == key))
+            .collect();
+        tags.sort();
+        tags.append(&mut self.tag);
+        self.tag = tags;
+        self.dir = file.dirs;
+        self.env.merge(file.env, file.env_inherit);
+        self.limits.merge(file.limits);
+        #[cfg(feature = "prometheus")]
+        self.prometheus.merge(file.metrics);
+    }
+}
+
 pub(crate) async fn start(args: Args) -> Result<()> {
     #[cfg(feature = "prometheus")]
     if args.prometheus.prometheus {
@@ -59,17 +127,30 @@ pub(crate) async fn start(args: Args) -> Result<()> {
         .ok_or_else(|| anyhow!("No available localhost UDP port"))?;
     let http_client = reqwest::Client::new();
 
-    // TODO unwrap, better message
-    let node_name = Uuid::new_v4();
+    // A node with a data directory keeps its name and key across restarts
+    let (node_name, node_cert) = match args.data_dir.as_deref() {
+        Some(data_dir) => lunatic_distributed::distributed::server::load_or_gen_node_identity(
+            &data_dir.join("node"),
+        )
+        .with_context(|| "Failed to load node identity")?,
+        None => {
+            let node_name = Uuid::new_v4();
+            let node_cert = lunatic_distributed::distributed::server::gen_node_cert(
+                &node_name.as_hyphenated().to_string(),
+            )
+            .with_context(|| "Failed to generate node CSR and PK")?;
+            (node_name, node_cert)
+        }
+    };
     let node_name_str = node_name.as_hyphenated().to_string();
-    let node_attributes: HashMap<String, String> = Default::default(); //args.tag.into_iter().collect(); TODO
-    let node_cert = lunatic_distributed::distributed::server::gen_node_cert(&node_name_str)
-        .with_context(|| "Failed to generate node CSR and PK")?;
+    let node_attributes: HashMap<String, String> = args.tag.iter().cloned().collect();
     log::info!("Generate CSR for node name {node_name_str}");
 
     let reg = control::Client::register(
         &http_client,
         args.control
+            .as_deref()
+            .unwrap_or(DEFAULT_CONTROL_URL)
             .parse()
             .with_context(|| "Parsing control URL")?,
         node_name,
@@ -83,11 +164,12 @@ pub(crate) async fn start(args: Args) -> Result<()> {
     let node_id = control_client.node_id();
 
     log::info!("Registration successful, node id {}", node_id);
+    crate::mode::logging::set_node_id(node_id);
 
     let quic_client = quic::new_quic_client(
         &reg.root_cert,
         reg.cert_pem_chain
-            .get(0)
+            .first()
             .ok_or_else(|| anyhow!("No certificate available for QUIC client"))?,
         &node_cert.serialize_private_key_pem(),
     )
@@ -96,16 +178,35 @@ pub(crate) async fn start(args: Args) -> Result<()> {
     let distributed_client =
         distributed::Client::new(node_id, control_client.clone(), quic_client.clone()).await?;
 
+    let sequences = open_sequences(args.data_dir.as_deref())?;
     let dist = lunatic_distributed::DistributedProcessState::new(
         node_id,
         control_client.clone(),
         distributed_client,
+        sequences.clone(),
     )
     .await?;
 
     let wasmtime_config = runtimes::wasmtime::default_config();
-    let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
-    let envs = Arc::new(LunaticEnvironments::default());
+    let mut runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
+    args.watchdog.apply(&mut runtime);
+    args.cache.apply(&mut runtime);
+    args.profile.apply(&mut runtime);
+    args.scheduler.apply(&mut runtime);
+    let mut envs = LunaticEnvironments::default();
+    envs.set_max_processes(args.limits.max_processes);
+    let envs = Arc::new(envs);
+
+    let maintenance = Maintenance::default();
+    let shutdown_maintenance = maintenance.clone();
+    let grace_period = Duration::from_secs(args.shutdown_grace_secs);
+    let profile = args.profile;
+    let profile_runtime = runtime.clone();
+    #[cfg(unix)]
+    tokio::task::spawn(maintenance_signals(
+        maintenance.clone(),
+        Duration::from_secs(args.max_maintenance_secs),
+    ));
 
     let node = tokio::task::spawn(lunatic_distributed::distributed::server::node_server(
         ServerCtx {
@@ -113,6 +214,7 @@ pub(crate) async fn start(args: Args) -> Result<()> {
             modules: Modules::<DefaultProcessState>::default(),
             distributed: dist.clone(),
             runtime: runtime.clone(),
+            maintenance,
         },
         socket,
         reg.root_cert,
@@ -120,31 +222,42 @@ pub(crate) async fn start(args: Args) -> Result<()> {
         node_cert.serialize_private_key_pem(),
     ));
 
-    if args.wasm.is_some() {
+    if let Some(path) = args.wasm {
         let env = envs.create(1).await;
+        let run = RunWasm {
+            path,
+            wasm_args: vec![],
+            dir: args.dir,
+            env_vars: args.env.vars(),
+            max_memory: args.limits.max_memory,
+            max_fuel: args.limits.max_fuel,
+            dns_overrides: args.dns.overrides(),
+            runtime,
+            env,
+            distributed: Some(dist),
+            sequences,
+        };
         tokio::task::spawn(async {
-            if let Err(e) = run_wasm(RunWasm {
-                path: args.wasm.unwrap(),
-                wasm_args: vec![],
-                dir: vec![],
-                runtime,
-                envs,
-                env,
-                distributed: Some(dist),
-            })
-            .await
-            {
+            if let Err(e) = run_wasm(run).await {
                 log::error!("Error running wasm: {e:?}");
             }
         });
     }
 
+    tokio::task::spawn(report_stats(control_client.clone(), envs.clone()));
+
     let ctrl = control_client.clone();
     tokio::task::spawn(async move {
-        async_ctrlc::CtrlC::new().unwrap().await;
+        shutdown_signal().await;
         log::info!("Shutting down node");
+        // Reject spawns and hold back messages from other nodes while processes stop
+        shutdown_maintenance.enter(grace_period);
+        let stopped = stop_processes(&envs, grace_period).await;
+        if let Err(e) = profile.report(&profile_runtime) {
+            log::error!("Failed to write profile report: {e:?}");
+        }
         ctrl.notify_node_stopped().await.ok();
-        std::process::exit(0);
+        std::process::exit(if stopped { 0 } else { 1 });
     });
 
     node.await.ok();
@@ -154,6 +267,67 @@ pub(crate) async fn start(args: Args) -> Result<()> {
     Ok(())
 }
 
+// Periodically reports the running processes to the control server, shown by `lunatic ps`.
+async fn report_stats(control_client: control::Client, envs: Arc<LunaticEnvironments>) {
+    loop {
+        let stats = NodeStats {
+            processes: envs.process_counts(),
+            process_names: envs.process_names(control_client.node_id()).await,
+        };
+        if let Err(e) = control_client.report_stats(stats).await {
+            log::debug!("Failed to report node stats: {e:?}");
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+// Resolves on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                log::warn!("Can't listen for SIGTERM: {e}");
+                tokio::signal::ctrl_c().await.ok();
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
+// Asks all processes to shut down and kills the ones that didn't stop within `grace_period`.
+// Returns `false` if some processes had to be killed.
+async fn stop_processes(envs: &LunaticEnvironments, grace_period: Duration) -> bool {
+    let killed = envs.shutdown(grace_period).await;
+    if killed > 0 {
+        log::warn!("Killed {killed} processes that didn't stop within {grace_period:?}");
+    }
+    killed == 0
+}
+
+// Enters maintenance mode on SIGUSR1 and leaves it on SIGUSR2.
+#[cfg(unix)]
+async fn maintenance_signals(maintenance: Maintenance, max_duration: Duration) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut enter = signal(SignalKind::user_defined1())?;
+    let mut exit = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            Some(_) = enter.recv() => maintenance.enter(max_duration),
+            Some(_) = exit.recv() => maintenance.exit(),
+            else => return Ok(()),
+        }
+    }
+}
+
 fn get_available_localhost() -> Option<SocketAddr> {
     for port in 1025..65535u16 {
         let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use lunatic_control::NodeInfo;
+use lunatic_distributed::control;
+
+#[derive(Parser, Debug)]
+pub(crate) struct Args {
+    /// Control server URL
+    #[arg(
+        long,
+        value_name = "CONTROL_URL",
+        default_value = "http://127.0.0.1:3030/"
+    )]
+    control: String,
+
+    /// Print the nodes as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+pub(crate) async fn start(args: Args) -> Result<()> {
+    let http_client = reqwest::Client::new();
+    let control_url = args
+        .control
+        .parse()
+        .with_context(|| "Parsing control URL")?;
+    let mut nodes = control::Client::cluster(&http_client, control_url).await?;
+    nodes.sort_by_key(|node| node.id);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&nodes)?);
+    } else {
+        print_table(&nodes);
+    }
+    Ok(())
+}
+
+fn print_table(nodes: &[NodeInfo]) {
+    println!(
+        "{:<6} {:<22} {:<36} {:<10} {:<20} ATTRIBUTES",
+        "NODE", "ADDRESS", "NAME", "PROCESSES", "ENVIRONMENTS"
+    );
+    for node in nodes {
+        // Sorted for a stable output
+        let environments: BTreeMap<_, _> = node.processes.iter().collect();
+        let attributes: BTreeMap<_, _> = node.attributes.iter().collect();
+        println!(
+            "{:<6} {:<22} {:<36} {:<10} {:<20} {}",
+            node.id,
+            node.address.to_string(),
+            node.name,
+            node.processes.values().sum::<usize>(),
+            environments
+                .iter()
+                .map(|(id, count)| format!("{id}:{count}"))
+                .collect::<Vec<_>>()
+                .join(","),
+            attributes
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        // Named processes are listed below their node
+        let names: BTreeMap<_, _> = node.process_names.iter().collect();
+        for (id, names) in names {
+            println!("{:<6} {id}: {}", "", names.join(", "));
+        }
+    }
+}
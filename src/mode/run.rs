@@ -1,13 +1,24 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::Result;
 use clap::Parser;
 use lunatic_process::{
-    env::{Environments, LunaticEnvironments},
-    runtimes::{self},
+    env::{Environments, LunaticEnvironment, LunaticEnvironments},
+    runtimes::{self, wasmtime::WasmtimeRuntime},
 };
+use lunatic_seq_api::Sequences;
 
-use super::common::{run_wasm, RunWasm};
+use super::{
+    common::{
+        open_sequences, run_wasm, CacheArgs, DnsArgs, EnvArgs, LimitArgs, ProfileArgs, RunWasm,
+        WatchdogArgs,
+    },
+    config_file::ConfigFile,
+};
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -16,10 +27,23 @@ pub struct Args {
     #[arg(long, value_name = "DIRECTORY")]
     pub dir: Vec<PathBuf>,
 
+    /// Directory used to persist node data, like sequences
+    #[arg(long, value_name = "DIRECTORY")]
+    pub data_dir: Option<PathBuf>,
+
     /// Indicate that a benchmark is running
     #[arg(long)]
     pub bench: bool,
 
+    /// Restart the entry process whenever the .wasm file changes
+    #[arg(long)]
+    pub watch: bool,
+
+    /// How long processes get to finish after the shutdown request when the .wasm file changes,
+    /// before they are killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10, requires = "watch")]
+    pub shutdown_grace_secs: u64,
+
     /// Entry .wasm file
     #[arg(index = 1)]
     pub path: PathBuf,
@@ -28,11 +52,43 @@ pub struct Args {
     #[arg(index = 2)]
     pub wasm_args: Vec<String>,
 
+    #[command(flatten)]
+    env: EnvArgs,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+
+    #[command(flatten)]
+    watchdog: WatchdogArgs,
+
+    #[command(flatten)]
+    cache: CacheArgs,
+
+    #[command(flatten)]
+    profile: ProfileArgs,
+
+    #[command(flatten)]
+    dns: DnsArgs,
+
     #[cfg(feature = "prometheus")]
     #[command(flatten)]
     prometheus: super::common::PrometheusArgs,
 }
 
+impl Args {
+    /// Fills in the settings from the config file that weren't passed on the command line.
+    pub(crate) fn merge(&mut self, file: ConfigFile) {
+        if self.dir.is_empty() {
+            self.dir = file.dirs;
+        }
+        self.data_dir = self.data_dir.take().or(file.data_dir);
+        self.env.merge(file.env, file.env_inherit);
+        self.limits.merge(file.limits);
+        #[cfg(feature = "prometheus")]
+        self.prometheus.merge(file.metrics);
+    }
+}
+
 pub(crate) async fn start(mut args: Args) -> Result<()> {
     #[cfg(feature = "prometheus")]
     if args.prometheus.prometheus {
@@ -41,21 +97,185 @@ pub(crate) async fn start(mut args: Args) -> Result<()> {
 
     // Create wasmtime runtime
     let wasmtime_config = runtimes::wasmtime::default_config();
-    let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
-    let envs = Arc::new(LunaticEnvironments::default());
+    let mut runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
+    args.watchdog.apply(&mut runtime);
+    args.cache.apply(&mut runtime);
+    args.profile.apply(&mut runtime);
+    let mut envs = LunaticEnvironments::default();
+    envs.set_max_processes(args.limits.max_processes);
+    let envs = Arc::new(envs);
+    let sequences = open_sequences(args.data_dir.as_deref())?;
 
-    let env = envs.create(1).await;
     if args.bench {
         args.wasm_args.push("--bench".to_owned());
     }
-    run_wasm(RunWasm {
+    if args.watch {
+        return watch(args, runtime, envs, sequences).await;
+    }
+
+    let env = envs.create(1).await;
+    let result = run_wasm(RunWasm {
         path: args.path,
         wasm_args: args.wasm_args,
         dir: args.dir,
-        runtime,
-        envs,
+        env_vars: args.env.vars(),
+        max_memory: args.limits.max_memory,
+        max_fuel: args.limits.max_fuel,
+        dns_overrides: args.dns.overrides(),
+        runtime: runtime.clone(),
         env,
         distributed: None,
+        sequences,
     })
-    .await
+    .await;
+    if let Err(e) = args.profile.report(&runtime) {
+        log::error!("Failed to write profile report: {e:?}");
+    }
+    result
+}
+
+// Runs the entry module and starts it over each time the .wasm file changes. Runs that fail are
+// reported instead of stopping the watch loop.
+async fn watch(
+    args: Args,
+    runtime: WasmtimeRuntime,
+    envs: Arc<LunaticEnvironments>,
+    sequences: Arc<Sequences>,
+) -> Result<()> {
+    let grace_period = Duration::from_secs(args.shutdown_grace_secs);
+    let mut environment_id = 0;
+    loop {
+        let modified = modified(&args.path);
+        // Every run gets a fresh environment, so no process or registry name outlives a reload
+        environment_id += 1;
+        let env = envs.create(environment_id).await;
+        let run = run_wasm(RunWasm {
+            path: args.path.clone(),
+            wasm_args: args.wasm_args.clone(),
+            dir: args.dir.clone(),
+            env_vars: args.env.vars(),
+            max_memory: args.limits.max_memory,
+            max_fuel: args.limits.max_fuel,
+            dns_overrides: args.dns.overrides(),
+            runtime: runtime.clone(),
+            env: env.clone(),
+            distributed: None,
+            sequences: sequences.clone(),
+        });
+        tokio::pin!(run);
+
+        tokio::select! {
+            result = &mut run => {
+                if let Err(e) = result {
+                    log::error!("{e:?}");
+                }
+                // Processes spawned by the module can outlive it
+                stop(&env, grace_period).await;
+                if let Err(e) = args.profile.report(&runtime) {
+                    log::error!("Failed to write profile report: {e:?}");
+                }
+                log::info!("Waiting for changes to {}", args.path.display());
+                changed(&args.path, modified).await;
+            }
+            _ = changed(&args.path, modified) => {
+                stop(&env, grace_period).await;
+                run.await.ok();
+                if let Err(e) = args.profile.report(&runtime) {
+                    log::error!("Failed to write profile report: {e:?}");
+                }
+            }
+        }
+        envs.remove(environment_id);
+        log::info!("{} changed, restarting", args.path.display());
+    }
+}
+
+// Stops all processes of a run, the next run only starts once they are gone.
+async fn stop(env: &LunaticEnvironment, grace_period: Duration) {
+    let killed = env.shutdown(grace_period).await;
+    if killed > 0 {
+        log::warn!("Killed {killed} processes that didn't stop within {grace_period:?}");
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Resolves once the file at `path` has a modification time different from `since`.
+async fn changed(path: &Path, since: Option<SystemTime>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        interval.tick().await;
+        let modified = modified(path);
+        if modified.is_some() && modified != since {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // Waits for messages tagged with 1, that never arrive
+    const STUBBORN: &str = r#"
+        (module
+            (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\01\00\00\00\00\00\00\00")
+            (func (export "_start")
+                (drop (call $receive (i32.const 0) (i32.const 1) (i64.const -1)))))"#;
+
+    // Waits until the environments run `expected` processes. Two runs never overlap.
+    async fn wait_for(envs: &LunaticEnvironments, expected: HashMap<u64, usize>) {
+        for _ in 0..1000 {
+            let counts = envs.process_counts();
+            assert!(counts.values().filter(|&&count| count > 0).count() <= 1);
+            if counts == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Environments never ran {:?}", expected);
+    }
+
+    #[tokio::test]
+    async fn watch_restarts_in_a_new_environment() {
+        let dir = std::env::temp_dir().join(format!("lunatic-watch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.wasm");
+        std::fs::write(&path, wat::parse_str(STUBBORN).unwrap()).unwrap();
+
+        let args = Args::try_parse_from([
+            "run",
+            "--watch",
+            "--shutdown-grace-secs",
+            "0",
+            path.to_str().unwrap(),
+        ])
+        .unwrap();
+        let runtime = WasmtimeRuntime::new(&runtimes::wasmtime::default_config()).unwrap();
+        let envs = Arc::new(LunaticEnvironments::default());
+        let sequences = open_sequences(None).unwrap();
+        let watching = tokio::spawn(watch(args, runtime, envs.clone(), sequences));
+        wait_for(&envs, HashMap::from([(1, 1)])).await;
+
+        // The stubborn process is killed and the old environment removed before the restart
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        wait_for(&envs, HashMap::from([(2, 1)])).await;
+
+        watching.abort();
+        envs.kill_all();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shutdown_grace_period_requires_watch() {
+        assert!(Args::try_parse_from(["run", "--shutdown-grace-secs", "1", "app.wasm"]).is_err());
+    }
 }
@@ -7,7 +7,9 @@ use hash_map_id::HashMapId;
 use lunatic_distributed::{DistributedCtx, DistributedProcessState};
 use lunatic_error_api::{ErrorCtx, ErrorResource};
 use lunatic_networking_api::{DnsIterator, TlsConnection, TlsListener};
-use lunatic_networking_api::{NetworkingCtx, TcpConnection};
+use lunatic_networking_api::{
+    NetworkingCtx, TcpConnection, TcpListenerResource, UdpSocketResource,
+};
 use lunatic_process::env::{Environment, LunaticEnvironment};
 use lunatic_process::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 use lunatic_process::state::{ConfigResources, ProcessState};
@@ -17,11 +19,12 @@ use lunatic_process::{
 };
 use lunatic_process::{mailbox::MessageMailbox, message::Message};
 use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
+use lunatic_seq_api::{SeqCtx, Sequences};
 use lunatic_sqlite_api::{SQLiteConnections, SQLiteCtx, SQLiteGuestAllocators, SQLiteStatements};
 use lunatic_stdout_capture::StdoutCapture;
 use lunatic_timer_api::{TimerCtx, TimerResources};
+use lunatic_version_api::VersionCtx;
 use lunatic_wasi_api::{build_wasi, LunaticWasiCtx};
-use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::{Mutex, RwLock};
 use wasmtime::{Linker, ResourceLimiter};
@@ -71,6 +74,8 @@ pub struct DefaultProcessState {
     // database resources
     db_resources: DbResources,
     registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+    // Node-level sequences
+    sequences: Arc<Sequences>,
 }
 
 impl DefaultProcessState {
@@ -81,6 +86,7 @@ impl DefaultProcessState {
         module: Arc<WasmtimeCompiledModule<Self>>,
         config: Arc<DefaultProcessConfig>,
         registry: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+        sequences: Arc<Sequences>,
     ) -> Result<Self> {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
@@ -105,6 +111,7 @@ impl DefaultProcessState {
             wasi_stderr: None,
             initialized: false,
             registry,
+            sequences,
             db_resources: DbResources::default(),
         };
         Ok(state)
@@ -142,6 +149,7 @@ impl ProcessState for DefaultProcessState {
             wasi_stderr: None,
             initialized: false,
             registry: self.registry.clone(),
+            sequences: self.sequences.clone(),
             db_resources: DbResources::default(),
         };
         Ok(state)
@@ -156,6 +164,7 @@ impl ProcessState for DefaultProcessState {
         lunatic_version_api::register(linker)?;
         lunatic_wasi_api::register(linker)?;
         lunatic_registry_api::register(linker)?;
+        lunatic_seq_api::register(linker)?;
         lunatic_distributed_api::register(linker)?;
         lunatic_sqlite_api::register(linker)?;
         #[cfg(feature = "metrics")]
@@ -327,6 +336,38 @@ impl NetworkingCtx for DefaultProcessState {
     fn dns_resources_mut(&mut self) -> &mut lunatic_networking_api::DnsResources {
         &mut self.resources.dns_iterators
     }
+
+    fn dns_overrides(&self) -> &lunatic_networking_api::DnsOverrides {
+        lunatic_networking_api::NetworkingConfigCtx::dns_overrides(self.config.as_ref())
+    }
+
+    fn virtual_network(&self) -> Option<&lunatic_networking_api::VirtualNetwork> {
+        lunatic_networking_api::NetworkingConfigCtx::virtual_network(self.config.as_ref())
+    }
+}
+
+impl SeqCtx for DefaultProcessState {
+    fn sequences(&self) -> &Arc<Sequences> {
+        &self.sequences
+    }
+}
+
+// Optional features this runtime was built with, as reported to guests.
+const BUILD_FEATURES: &[&str] = &[
+    #[cfg(feature = "metrics")]
+    "metrics",
+    #[cfg(feature = "prometheus")]
+    "prometheus",
+];
+
+impl VersionCtx for DefaultProcessState {
+    fn module_hash(&self) -> [u8; 32] {
+        *self.module().hash()
+    }
+
+    fn build_features(&self) -> &[&'static str] {
+        BUILD_FEATURES
+    }
 }
 
 impl TimerCtx for DefaultProcessState {
@@ -400,11 +441,11 @@ pub(crate) struct Resources {
     pub(crate) modules: HashMapId<Arc<WasmtimeCompiledModule<DefaultProcessState>>>,
     pub(crate) timers: TimerResources,
     pub(crate) dns_iterators: HashMapId<DnsIterator>,
-    pub(crate) tcp_listeners: HashMapId<TcpListener>,
+    pub(crate) tcp_listeners: HashMapId<TcpListenerResource>,
     pub(crate) tcp_streams: HashMapId<Arc<TcpConnection>>,
     pub(crate) tls_listeners: HashMapId<TlsListener>,
     pub(crate) tls_streams: HashMapId<Arc<TlsConnection>>,
-    pub(crate) udp_sockets: HashMapId<Arc<UdpSocket>>,
+    pub(crate) udp_sockets: HashMapId<Arc<UdpSocketResource>>,
     pub(crate) errors: HashMapId<anyhow::Error>,
 }
 
@@ -448,6 +489,8 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
         let message_mailbox = MessageMailbox::default();
+        let sequences = distributed.sequences().clone();
+        let registry = environment.registry().clone();
         let state = Self {
             id: environment.get_next_process_id(),
             environment,
@@ -467,13 +510,15 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             wasi_stdout: None,
             wasi_stderr: None,
             initialized: false,
-            registry: Default::default(), // TODO move registry into env?
+            registry,
+            sequences,
             db_resources: DbResources::default(),
         };
         Ok(state)
     }
 }
 
+#[cfg(test)]
 mod tests {
 
     #[tokio::test]
@@ -507,6 +552,7 @@ mod tests {
             module.clone(),
             Arc::new(config),
             registry,
+            Default::default(),
         )
         .unwrap();
 
@@ -516,4 +562,792 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn guest_panic_is_exit_reason() {
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::trap" "panic" (func $panic (param i32 i32 i32 i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "boom")
+                        (data (i32.const 16) "src/lib.rs:1:1")
+                        (func (export "hello")
+                            (call $panic (i32.const 0) (i32.const 4) (i32.const 16) (i32.const 14))
+                            unreachable)
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let process = node
+            .spawn(&module, "hello", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let failure = process.join().await.unwrap_err();
+        assert_eq!(failure.to_string(), "panicked at 'boom', src/lib.rs:1:1");
+    }
+
+    #[tokio::test]
+    async fn process_exit_keeps_exit_code() {
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::process" "exit" (func $exit (param i32 i32 i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "bad config")
+                        (func (export "hello")
+                            (call $exit (i32.const 78) (i32.const 0) (i32.const 10))
+                            unreachable)
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let process = node
+            .spawn(&module, "hello", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let failure = process.join().await.unwrap_err();
+        let exit = failure
+            .downcast_ref::<lunatic_process::ProcessExit>()
+            .unwrap();
+        assert_eq!(exit.code, 78);
+        assert_eq!(exit.reason.as_deref(), Some("bad config"));
+    }
+
+    // Sleeps for a minute inside of a host call
+    const SLOW_HOST_CALL: &str = r#"
+        (module
+            (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+            (func (export "hello") (call $sleep_ms (i64.const 60000)))
+        )"#;
+
+    #[tokio::test]
+    async fn watchdog_kills_slow_host_call() {
+        use std::time::Duration;
+
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::watchdog::{Watchdog, WatchdogConfig};
+
+        use crate::testing::TestNode;
+
+        let mut runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        runtime.set_watchdog(Watchdog::spawn(WatchdogConfig {
+            slow_host_call: Duration::from_millis(50),
+            check_interval: Duration::from_millis(10),
+            kill_slow_processes: true,
+            ..Default::default()
+        }));
+        let node = TestNode::with_runtime(runtime).await.unwrap();
+        let module = node
+            .compile(wat::parse_str(SLOW_HOST_CALL).unwrap())
+            .unwrap();
+
+        let process = node
+            .spawn(&module, "hello", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(10), process.join())
+            .await
+            .expect("process should be killed by the watchdog");
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn watchdog_reports_the_slow_host_function() {
+        use std::time::Duration;
+
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::watchdog::{Watchdog, WatchdogConfig};
+
+        use crate::testing::TestNode;
+
+        let watchdog = Watchdog::spawn(WatchdogConfig {
+            slow_host_call: Duration::from_millis(50),
+            check_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let mut runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        runtime.set_watchdog(watchdog.clone());
+        let node = TestNode::with_runtime(runtime).await.unwrap();
+        let module = node
+            .compile(wat::parse_str(SLOW_HOST_CALL).unwrap())
+            .unwrap();
+
+        let process = node
+            .spawn(&module, "hello", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let slow = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(slow) = watchdog.slow_host_calls().pop() {
+                    break slow;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(slow.process_id, process.id());
+        assert_eq!(slow.function.as_deref(), Some("lunatic::process::sleep_ms"));
+        assert!(slow.duration >= Duration::from_millis(50));
+        // Without `kill_slow_processes` the process keeps running
+        process.kill();
+        assert!(process.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scheduler_holds_back_environments_over_their_share() {
+        use std::{sync::Arc, time::Duration};
+
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::scheduler::Scheduler;
+
+        use crate::testing::TestNode;
+
+        let scheduler = Arc::new(Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_millis(10),
+        ));
+        let mut runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        runtime.set_scheduler(scheduler.clone());
+        let node = TestNode::with_runtime(runtime).await.unwrap();
+        let module = node
+            .compile(wat::parse_str(r#"(module (func (export "hello")))"#).unwrap())
+            .unwrap();
+
+        // The environment used up its share, so its processes wait for the next period
+        scheduler.charge(node.environment().id(), Duration::from_millis(10));
+        let process = node
+            .spawn(&module, "hello", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let id = process.id();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(node.environment().get_process(id).is_some());
+        process.kill();
+        assert!(process.join().await.is_err());
+    }
+
+    // Calls the config setters with values derived from the calling process' own config. The
+    // caller has 64 MiB of memory, 1000 units of fuel, can create configs and has access to
+    // `./wat`. Environment variables and arguments are data, so any of them can be passed on.
+    const DELEGATE_CONFIG: &str = r#"
+        (module
+            (import "lunatic::process" "create_config" (func $create (result i64)))
+            (import "lunatic::process" "config_set_max_memory" (func $set_memory (param i64 i64)))
+            (import "lunatic::process" "config_get_max_memory" (func $get_memory (param i64) (result i64)))
+            (import "lunatic::process" "config_set_max_fuel" (func $set_fuel (param i64 i64)))
+            (import "lunatic::process" "config_get_max_fuel" (func $get_fuel (param i64) (result i64)))
+            (import "lunatic::process" "config_set_can_compile_modules" (func $can_compile (param i64 i32)))
+            (import "lunatic::process" "config_set_can_create_configs" (func $can_create (param i64 i32)))
+            (import "lunatic::process" "config_set_can_spawn_processes" (func $can_spawn (param i64 i32)))
+            (import "lunatic::wasi" "config_preopen_dir" (func $preopen (param i64 i32 i32)))
+            (import "lunatic::wasi" "config_add_environment_variable" (func $env (param i64 i32 i32 i32 i32)))
+            (import "lunatic::wasi" "config_add_command_line_argument" (func $arg (param i64 i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "/")
+            (data (i32.const 8) "wat")
+            (data (i32.const 16) "wat/..")
+            (data (i32.const 24) "SECRET")
+            (data (i32.const 32) "x")
+            (data (i32.const 40) "--flag")
+
+            (func (export "grant_compile") (call $can_compile (call $create) (i32.const 1)))
+            (func (export "grant_spawn") (call $can_spawn (call $create) (i32.const 1)))
+            (func (export "raise_memory") (call $set_memory (call $create) (i64.const 0x8000000)))
+            (func (export "raise_fuel") (call $set_fuel (call $create) (i64.const 2000)))
+            (func (export "remove_fuel") (call $set_fuel (call $create) (i64.const 0)))
+            (func (export "preopen_root") (call $preopen (call $create) (i32.const 0) (i32.const 1)))
+            (func (export "preopen_parent") (call $preopen (call $create) (i32.const 16) (i32.const 6)))
+
+            (func (export "delegate")
+                (local $config i64)
+                (local.set $config (call $create))
+                ;; A new config starts with the caller's limits
+                (if (i64.ne (call $get_memory (local.get $config)) (i64.const 0x4000000))
+                    (then unreachable))
+                (if (i64.ne (call $get_fuel (local.get $config)) (i64.const 1000))
+                    (then unreachable))
+                (call $set_memory (local.get $config) (i64.const 0x1000000))
+                (call $set_fuel (local.get $config) (i64.const 500))
+                (call $can_create (local.get $config) (i32.const 1))
+                (call $can_compile (local.get $config) (i32.const 0))
+                (call $preopen (local.get $config) (i32.const 8) (i32.const 3))
+                (call $env (local.get $config) (i32.const 24) (i32.const 6) (i32.const 32) (i32.const 1))
+                (call $arg (local.get $config) (i32.const 40) (i32.const 6)))
+        )"#;
+
+    fn delegating_config() -> crate::DefaultProcessConfig {
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_memory(64 * 1024 * 1024);
+        config.set_max_fuel(Some(1000));
+        config.set_can_create_configs(true);
+        config.preopen_dir("wat");
+        config
+    }
+
+    #[tokio::test]
+    async fn config_escalations_are_refused() {
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(wat::parse_str(DELEGATE_CONFIG).unwrap())
+            .unwrap();
+        for (function, reason) in [
+            ("grant_compile", "can't grant a permission"),
+            ("grant_spawn", "can't grant a permission"),
+            ("raise_memory", "max_memory exceeds the process' own limit"),
+            ("raise_fuel", "max_fuel exceeds the process' own limit"),
+            ("remove_fuel", "max_fuel exceeds the process' own limit"),
+            ("preopen_root", "preopen_dir: Permission"),
+            ("preopen_parent", "preopen_dir: Permission"),
+        ] {
+            let process = node
+                .spawn(&module, function, Vec::new(), delegating_config())
+                .await
+                .unwrap();
+            let error = process.join().await.unwrap_err();
+            assert!(
+                format!("{:?}", error).contains(reason),
+                "{} should trap with `{}`, but got: {:?}",
+                function,
+                reason,
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn config_delegates_own_capabilities() {
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(wat::parse_str(DELEGATE_CONFIG).unwrap())
+            .unwrap();
+        let process = node
+            .spawn(&module, "delegate", Vec::new(), delegating_config())
+            .await
+            .unwrap();
+        process.join().await.unwrap();
+    }
+
+    // Configs with time-limited permissions, and configs sent to other processes
+    const DELEGATE_TEMPORARILY: &str = r#"
+        (module
+            (import "lunatic::process" "create_config" (func $create (result i64)))
+            (import "lunatic::process" "config_set_expires_after" (func $expires_after (param i64 i64)))
+            (import "lunatic::process" "config_can_create_configs" (func $can_create (param i64) (result i32)))
+            (import "lunatic::process" "config_set_can_create_configs" (func $set_can_create (param i64 i32)))
+            (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+            (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+            (import "lunatic::message" "push_config" (func $push_config (param i64) (result i64)))
+            (import "lunatic::message" "take_config" (func $take_config (param i64) (result i64)))
+            (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+            (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+            (memory (export "memory") 1)
+
+            (func (export "expire")
+                (if (i64.lt_s (call $create) (i64.const 0)) (then unreachable))
+                (call $sleep_ms (i64.const 200))
+                ;; The permission to create configs expired
+                (if (i64.ge_s (call $create) (i64.const 0)) (then unreachable)))
+
+            (func (export "outlive")
+                (call $expires_after (call $create) (i64.const 7200000)))
+
+            (func (export "send_config") (param $to i64)
+                (local $config i64)
+                (local.set $config (call $create))
+                (call $set_can_create (local.get $config) (i32.const 1))
+                (call $expires_after (local.get $config) (i64.const 60000))
+                (call $create_data (i64.const 0) (i64.const 0))
+                (drop (call $push_config (local.get $config)))
+                (drop (call $send (local.get $to))))
+
+            (func (export "receive_config")
+                (local $config i64)
+                (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                (local.set $config (call $take_config (i64.const 0)))
+                (if (i32.eqz (call $can_create (local.get $config))) (then unreachable)))
+        )"#;
+
+    fn expiring_config(after: std::time::Duration) -> crate::DefaultProcessConfig {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        config.set_expires_at(Some(std::time::SystemTime::now() + after));
+        config
+    }
+
+    #[tokio::test]
+    async fn delegated_permissions_expire() {
+        use std::time::Duration;
+
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(wat::parse_str(DELEGATE_TEMPORARILY).unwrap())
+            .unwrap();
+        let process = node
+            .spawn(
+                &module,
+                "expire",
+                Vec::new(),
+                expiring_config(Duration::from_millis(100)),
+            )
+            .await
+            .unwrap();
+        process.join().await.unwrap();
+
+        // Configs created by the process can't outlive its own permissions
+        let process = node
+            .spawn(
+                &module,
+                "outlive",
+                Vec::new(),
+                expiring_config(Duration::from_secs(3600)),
+            )
+            .await
+            .unwrap();
+        let error = process.join().await.unwrap_err();
+        assert!(format!("{:?}", error).contains("can't outlive"));
+    }
+
+    #[tokio::test]
+    async fn configs_are_delegated_through_messages() {
+        use std::time::Duration;
+
+        use wasmtime::Val;
+
+        use crate::{testing::TestNode, DefaultProcessConfig};
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(wat::parse_str(DELEGATE_TEMPORARILY).unwrap())
+            .unwrap();
+        // The receiver can't create configs itself, but gets a config that can
+        let receiver = node
+            .spawn(
+                &module,
+                "receive_config",
+                Vec::new(),
+                DefaultProcessConfig::default(),
+            )
+            .await
+            .unwrap();
+        let sender = node
+            .spawn(
+                &module,
+                "send_config",
+                vec![Val::I64(receiver.id() as i64)],
+                expiring_config(Duration::from_secs(3600)),
+            )
+            .await
+            .unwrap();
+        sender.join().await.unwrap();
+        receiver.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_is_requested_before_kill() {
+        use std::time::Duration;
+
+        use wasmtime::Val;
+
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                        (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                        (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                        (import "lunatic::process" "notify_shutdown" (func $notify_shutdown (param i32)))
+                        (memory (export "memory") 1)
+                        ;; Asks for the shutdown request, tells `ready` and finishes once it arrives
+                        (func (export "graceful") (param $ready i64)
+                            (call $notify_shutdown (i32.const 1))
+                            (call $create_data (i64.const 0) (i64.const 0))
+                            (drop (call $send (local.get $ready)))
+                            (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const -1)) (i32.const 3))
+                                (then unreachable)))
+                        ;; Isn't notified, so it waits until it's killed
+                        (func (export "stubborn")
+                            (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1))))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let ready = node.mailbox();
+        let graceful = node
+            .spawn(
+                &module,
+                "graceful",
+                vec![Val::I64(ready.id() as i64)],
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        ready.receive(None).await;
+        drop(ready);
+        let killed = node.environments().shutdown(Duration::from_secs(10)).await;
+        assert_eq!(killed, 0);
+        graceful.join().await.unwrap();
+
+        let stubborn = node
+            .spawn(&module, "stubborn", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let killed = node
+            .environments()
+            .shutdown(Duration::from_millis(50))
+            .await;
+        assert_eq!(killed, 1);
+        assert!(stubborn.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dns_overrides_come_from_the_config() {
+        use lunatic_networking_api::{DnsOverrides, NetworkingConfigCtx};
+
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::networking" "resolve" (func $resolve (param i32 i32 i64 i32) (result i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "db.invalid:80")
+                        (func (export "resolve")
+                            (if (call $resolve (i32.const 0) (i32.const 13) (i64.const 1000) (i32.const 16))
+                                (then unreachable)))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mut dns_overrides = DnsOverrides::default();
+        dns_overrides.add_host("db.invalid", [127, 0, 0, 1].into());
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_dns_overrides(dns_overrides);
+        let process = node
+            .spawn(&module, "resolve", Vec::new(), config)
+            .await
+            .unwrap();
+        process.join().await.unwrap();
+
+        // Another process in the same environment isn't affected
+        let process = node
+            .spawn(&module, "resolve", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        assert!(process.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn registered_names_of_running_processes_are_listed() {
+        use std::time::Duration;
+
+        use lunatic_process::env::Environment;
+
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::registry" "put" (func $put (param i32 i32 i64 i64)))
+                        (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                        (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "\01\00\00\00\00\00\00\00")
+                        (data (i32.const 8) "worker")
+                        ;; Registers itself and waits for a message that never arrives
+                        (func (export "named")
+                            (call $put (i32.const 8) (i32.const 6) (i64.const 0) (call $process_id))
+                            (drop (call $receive (i32.const 0) (i32.const 1) (i64.const -1))))
+                        (func (export "anonymous")
+                            (drop (call $receive (i32.const 0) (i32.const 1) (i64.const -1))))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let named = node
+            .spawn(&module, "named", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let anonymous = node
+            .spawn(&module, "anonymous", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let env_id = node.environment().id();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while node.environments().process_names(0).await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let names = node.environments().process_names(0).await;
+        assert_eq!(names[&env_id], ["worker"]);
+        // Names registered on other nodes aren't listed
+        assert!(node.environments().process_names(1).await.is_empty());
+
+        named.kill();
+        assert!(named.join().await.is_err());
+        assert!(node.environments().process_names(0).await.is_empty());
+        anonymous.kill();
+        assert!(anonymous.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn panic_is_delivered_to_links() {
+        use wasmtime::Val;
+
+        use crate::testing::TestNode;
+
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::trap" "panic" (func $panic (param i32 i32 i32 i32)))
+                        (import "lunatic::process" "die_when_link_dies" (func $die_when_link_dies (param i32)))
+                        (import "lunatic::process" "link" (func $link (param i64 i64)))
+                        (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                        (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                        (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                        (import "lunatic::message" "link_died_reason_size" (func $reason_size (result i32)))
+                        (import "lunatic::message" "link_died_reason" (func $reason (param i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "boom")
+                        ;; Panics once it receives a message
+                        (func (export "child")
+                            (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                            (call $panic (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 0))
+                            unreachable)
+                        ;; Links to the child, makes it panic and panics with the received reason
+                        (func (export "parent") (param $child i64) (local $size i32)
+                            (call $die_when_link_dies (i32.const 0))
+                            (call $link (i64.const 7) (local.get $child))
+                            (call $create_data (i64.const 0) (i64.const 0))
+                            (drop (call $send (local.get $child)))
+                            (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const -1)) (i32.const 1))
+                                (then unreachable))
+                            (local.set $size (call $reason_size))
+                            (call $reason (i32.const 64))
+                            (call $panic (i32.const 64) (local.get $size) (i32.const 0) (i32.const 0))
+                            unreachable)
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let child = node
+            .spawn(&module, "child", Vec::new(), Default::default())
+            .await
+            .unwrap();
+        let parent = node
+            .spawn(
+                &module,
+                "parent",
+                vec![Val::I64(child.id() as i64)],
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let failure = parent.join().await.unwrap_err();
+        assert_eq!(failure.to_string(), "panicked at 'panicked at 'boom''");
+        assert!(child.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn virtual_network_serves_guest_sockets() {
+        use lunatic_networking_api::{NetworkingConfigCtx, VirtualNetwork};
+
+        use crate::{testing::TestNode, DefaultProcessConfig};
+
+        let node = TestNode::new().await.unwrap();
+        // Binds 10.0.0.1:80, connects to it and then to the unbound port 81. Traps unless the
+        // first connect succeeds and the second one is refused.
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::networking" "tcp_bind"
+                            (func $tcp_bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                        (import "lunatic::networking" "tcp_connect"
+                            (func $tcp_connect (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+                        (memory (export "memory") 1)
+                        (data (i32.const 0) "\0a\00\00\01")
+                        (func (export "hello")
+                            (if (call $tcp_bind (i32.const 4) (i32.const 0) (i32.const 80)
+                                    (i32.const 0) (i32.const 0) (i32.const 8))
+                                (then unreachable))
+                            (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const 80)
+                                    (i32.const 0) (i32.const 0) (i64.const 1000) (i32.const 16))
+                                (then unreachable))
+                            (if (i32.ne
+                                    (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const 81)
+                                        (i32.const 0) (i32.const 0) (i64.const 1000) (i32.const 16))
+                                    (i32.const 1))
+                                (then unreachable)))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mut config = DefaultProcessConfig::default();
+        config.set_virtual_network(Some(VirtualNetwork::default()));
+        let process = node
+            .spawn(&module, "hello", Vec::new(), config)
+            .await
+            .unwrap();
+        process.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn maintenance_rejects_spawns_and_holds_back_messages() {
+        use std::time::Duration;
+
+        use lunatic_distributed::distributed::message::{ClientError, Spawn};
+
+        use crate::testing::TestControl;
+
+        let control = TestControl::new().await.unwrap();
+        let node = control.node().await.unwrap();
+        // Joins last, so it knows about the other node right away
+        let remote = control.node().await.unwrap();
+        let node_id = node.node_id().unwrap();
+        let client = remote.distributed().unwrap().node_client.clone();
+        let mailbox = node.mailbox();
+
+        node.maintenance().enter(Duration::from_secs(60));
+        let spawn = Spawn {
+            environment_id: 1,
+            module_id: 1,
+            function: "hello".to_string(),
+            params: Vec::new(),
+            config: Vec::new(),
+        };
+        let error = client.spawn(node_id, spawn).await.unwrap_err();
+        assert!(matches!(error, ClientError::NodeInMaintenance));
+
+        let mailbox_id = mailbox.id();
+        let send = tokio::spawn(async move {
+            client
+                .message_process(node_id, 1, mailbox_id, Some(7), Vec::new())
+                .await
+        });
+        let held_back = tokio::time::timeout(Duration::from_millis(200), mailbox.receive(None));
+        assert!(held_back.await.is_err());
+        assert!(!send.is_finished());
+
+        node.maintenance().exit();
+        assert_eq!(mailbox.receive(None).await.tag(), Some(7));
+        send.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn version_reports_module_hash_and_features() {
+        use std::io::Read;
+
+        use lunatic_process::message::Message;
+        use sha2::{Digest, Sha256};
+        use wasmtime::Val;
+
+        use crate::{testing::TestNode, DefaultProcessConfig};
+
+        let node = TestNode::new().await.unwrap();
+        // Sends the module hash followed by the features to `$to`
+        let wasm = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::version" "module_hash" (func $module_hash (param i32)))
+                (import "lunatic::version" "features_size" (func $features_size (result i32)))
+                (import "lunatic::version" "features" (func $features (param i32)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello") (param $to i64)
+                    (call $module_hash (i32.const 0))
+                    (call $features (i32.const 32))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0)
+                        (i32.add (i32.const 32) (call $features_size))))
+                    (drop (call $send (local.get $to))))
+            )"#,
+        )
+        .unwrap();
+        let module = node.compile(wasm.clone()).unwrap();
+
+        let mailbox = node.mailbox();
+        let process = node
+            .spawn(
+                &module,
+                "hello",
+                vec![Val::I64(mailbox.id() as i64)],
+                DefaultProcessConfig::default(),
+            )
+            .await
+            .unwrap();
+
+        let mut data = Vec::new();
+        match mailbox.receive(None).await {
+            Message::Data(mut message) => message.read_to_end(&mut data).unwrap(),
+            _ => panic!("expected a data message"),
+        };
+        let (hash, features) = data.split_at(32);
+        assert_eq!(hash, Sha256::digest(&wasm).as_slice());
+        let expected: &[u8] = if cfg!(feature = "prometheus") {
+            b"metrics,prometheus"
+        } else if cfg!(feature = "metrics") {
+            // Default features
+            b"metrics"
+        } else {
+            b""
+        };
+        assert_eq!(features, expected);
+        process.join().await.unwrap();
+    }
 }
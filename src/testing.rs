@@ -0,0 +1,446 @@
+//! Helpers for host-side integration tests.
+//!
+//! A [`TestNode`] runs a lunatic node inside the test process. It compiles wasm fixtures, spawns
+//! processes from them and exchanges messages with them through a [`TestMailbox`], without going
+//! through the `lunatic` binary.
+//!
+//! Distributed tests start a [`TestControl`] server and join several nodes to it with
+//! [`TestControl::node`]. Nodes only know about the nodes that joined before them, until they
+//! refresh the node list.
+//!
+//! All processes of a node are killed when it's dropped.
+
+use std::{collections::HashMap, net::UdpSocket, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use lunatic_distributed::{
+    control,
+    distributed::{
+        self,
+        server::{gen_node_cert, ServerCtx},
+        Maintenance,
+    },
+    quic, DistributedProcessState,
+};
+use lunatic_process::{
+    env::{Environment, Environments, LunaticEnvironment, LunaticEnvironments},
+    mailbox::MessageMailbox,
+    message::{DataMessage, Message},
+    runtimes::{
+        wasmtime::{default_config, WasmtimeCompiledModule, WasmtimeRuntime},
+        Modules, RawWasm,
+    },
+    wasm::spawn_wasm,
+};
+use lunatic_seq_api::Sequences;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use wasmtime::Val;
+
+use crate::{DefaultProcessConfig, DefaultProcessState, Process, Signal};
+
+/// A control server running inside the current process, on a free localhost port.
+pub struct TestControl {
+    url: reqwest::Url,
+    server: JoinHandle<Result<()>>,
+}
+
+impl TestControl {
+    pub async fn new() -> Result<Self> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/", listener.local_addr()?).parse()?;
+        let server = tokio::spawn(lunatic_control_axum::server::control_server_from_tcp(
+            listener,
+        ));
+        Ok(Self { url, server })
+    }
+
+    /// Boots a node with the default runtime configuration and registers it with this control
+    /// server.
+    pub async fn node(&self) -> Result<TestNode> {
+        self.node_with_runtime(WasmtimeRuntime::new(&default_config())?)
+            .await
+    }
+
+    /// Boots a node on top of an existing runtime and registers it with this control server.
+    pub async fn node_with_runtime(&self, runtime: WasmtimeRuntime) -> Result<TestNode> {
+        let http_client = reqwest::Client::new();
+        let node_name = Uuid::new_v4();
+        let node_cert = gen_node_cert(&node_name.as_hyphenated().to_string())?;
+        let reg = control::Client::register(
+            &http_client,
+            self.url.clone(),
+            node_name,
+            node_cert.serialize_request_pem()?,
+        )
+        .await?;
+        let node_key = node_cert.serialize_private_key_pem();
+
+        // Bind the node server before announcing its address to the other nodes
+        let socket = UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+        let mut quic_server = quic::new_quic_server(
+            socket,
+            reg.cert_pem_chain.clone(),
+            &node_key,
+            &reg.root_cert,
+        )?;
+        let control_client =
+            control::Client::new(http_client, reg.clone(), socket, HashMap::new()).await?;
+        let node_id = control_client.node_id();
+        let quic_client = quic::new_quic_client(
+            &reg.root_cert,
+            reg.cert_pem_chain
+                .first()
+                .ok_or_else(|| anyhow!("No certificate available for QUIC client"))?,
+            &node_key,
+        )?;
+        let node_client =
+            distributed::Client::new(node_id, control_client.clone(), quic_client).await?;
+        let sequences = Arc::new(Sequences::in_memory());
+        let distributed =
+            DistributedProcessState::new(node_id, control_client, node_client, sequences.clone())
+                .await?;
+
+        let mut node = TestNode::build(runtime, Some(distributed.clone()), sequences).await;
+        let ctx = ServerCtx {
+            envs: Arc::new(node.envs.clone()),
+            modules: Modules::<DefaultProcessState>::default(),
+            distributed,
+            runtime: node.runtime.clone(),
+            maintenance: node.maintenance.clone(),
+        };
+        node.server = Some(tokio::spawn(async move {
+            quic::handle_node_server(&mut quic_server, ctx).await
+        }));
+        Ok(node)
+    }
+}
+
+impl Drop for TestControl {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A node running inside the current process, with a single environment.
+pub struct TestNode {
+    runtime: WasmtimeRuntime,
+    envs: LunaticEnvironments,
+    env: Arc<LunaticEnvironment>,
+    sequences: Arc<Sequences>,
+    distributed: Option<DistributedProcessState>,
+    maintenance: Maintenance,
+    // Serves requests from other nodes
+    server: Option<JoinHandle<Result<()>>>,
+}
+
+impl TestNode {
+    /// Boots a node with the default runtime configuration.
+    pub async fn new() -> Result<Self> {
+        Self::with_runtime(WasmtimeRuntime::new(&default_config())?).await
+    }
+
+    /// Boots a node on top of an existing runtime, e.g. one with a watchdog attached.
+    pub async fn with_runtime(runtime: WasmtimeRuntime) -> Result<Self> {
+        Ok(Self::build(runtime, None, Arc::new(Sequences::in_memory())).await)
+    }
+
+    async fn build(
+        runtime: WasmtimeRuntime,
+        distributed: Option<DistributedProcessState>,
+        sequences: Arc<Sequences>,
+    ) -> Self {
+        let envs = LunaticEnvironments::default();
+        let env = envs.create(1).await;
+        Self {
+            runtime,
+            envs,
+            env,
+            sequences,
+            distributed,
+            maintenance: Maintenance::default(),
+            server: None,
+        }
+    }
+
+    /// The id the control server assigned to this node, `None` if it didn't join one.
+    pub fn node_id(&self) -> Option<u64> {
+        self.distributed
+            .as_ref()
+            .map(|distributed| distributed.node_id())
+    }
+
+    pub fn distributed(&self) -> Option<&DistributedProcessState> {
+        self.distributed.as_ref()
+    }
+
+    /// Maintenance mode of the node's server, it only affects requests from other nodes.
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    pub fn environment(&self) -> &Arc<LunaticEnvironment> {
+        &self.env
+    }
+
+    pub fn environments(&self) -> &LunaticEnvironments {
+        &self.envs
+    }
+
+    /// Compiles a wasm fixture.
+    pub fn compile<W: Into<RawWasm>>(
+        &self,
+        wasm: W,
+    ) -> Result<Arc<WasmtimeCompiledModule<DefaultProcessState>>> {
+        Ok(Arc::new(self.runtime.compile_module(wasm.into())?))
+    }
+
+    /// Spawns a process that starts by calling `function` with `params`.
+    pub async fn spawn(
+        &self,
+        module: &Arc<WasmtimeCompiledModule<DefaultProcessState>>,
+        function: &str,
+        params: Vec<Val>,
+        config: DefaultProcessConfig,
+    ) -> Result<TestProcess> {
+        let state = DefaultProcessState::new(
+            self.env.clone(),
+            self.distributed.clone(),
+            self.runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            self.env.registry().clone(),
+            self.sequences.clone(),
+        )?;
+        self.env.can_spawn_next_process().await?;
+        let (task, process) = spawn_wasm(
+            self.env.clone(),
+            self.runtime.clone(),
+            module,
+            state,
+            function,
+            params,
+            None,
+        )
+        .await?;
+        Ok(TestProcess { process, task })
+    }
+
+    /// Creates a mailbox that guest processes can send messages to.
+    pub fn mailbox(&self) -> TestMailbox {
+        let id = self.env.get_next_process_id();
+        let messages = MessageMailbox::default();
+        self.env
+            .add_process(
+                id,
+                Arc::new(MailboxProcess {
+                    id,
+                    messages: messages.clone(),
+                }),
+            )
+            .expect("test node has no process limit");
+        TestMailbox {
+            id,
+            messages,
+            env: self.env.clone(),
+        }
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        self.envs.kill_all();
+        if let Some(server) = self.server.take() {
+            server.abort();
+        }
+    }
+}
+
+/// A process spawned by a [`TestNode`].
+pub struct TestProcess {
+    process: Arc<dyn Process>,
+    task: JoinHandle<Result<DefaultProcessState>>,
+}
+
+impl TestProcess {
+    pub fn id(&self) -> u64 {
+        self.process.id()
+    }
+
+    /// Sends a data message to the process.
+    pub fn send(&self, tag: Option<i64>, data: Vec<u8>) {
+        self.process
+            .send(Signal::Message(Message::Data(DataMessage::new_from_vec(
+                tag, data,
+            ))));
+    }
+
+    pub fn kill(&self) {
+        self.process.send(Signal::Kill);
+    }
+
+    /// Waits for the process to finish and returns its failure, if any.
+    pub async fn join(self) -> Result<()> {
+        self.task
+            .await
+            .map_err(|e| anyhow!(e.to_string()))?
+            .map(|_| ())
+    }
+}
+
+/// A mailbox registered as a process in the node's environment.
+///
+/// It's removed from the environment when dropped.
+pub struct TestMailbox {
+    id: u64,
+    messages: MessageMailbox,
+    env: Arc<LunaticEnvironment>,
+}
+
+impl TestMailbox {
+    /// The process ID guests can send messages to.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits for the next message matching one of the `tags`, or any message if `None`.
+    pub async fn receive(&self, tags: Option<&[i64]>) -> Message {
+        self.messages.pop(tags).await
+    }
+}
+
+impl Drop for TestMailbox {
+    fn drop(&mut self) {
+        self.env.remove_process(self.id);
+    }
+}
+
+struct MailboxProcess {
+    id: u64,
+    messages: MessageMailbox,
+}
+
+impl Process for MailboxProcess {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn send(&self, signal: Signal) {
+        // Only messages are observable by the test, other signals are dropped
+        if let Signal::Message(message) = signal {
+            self.messages.push(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn echo_message_tag() {
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                        (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                        (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                        (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                        (memory (export "memory") 1)
+                        (func (export "echo") (param $to i64)
+                            (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                            (call $create_data (call $get_tag) (i64.const 0))
+                            (drop (call $send (local.get $to))))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mailbox = node.mailbox();
+        let process = node
+            .spawn(
+                &module,
+                "echo",
+                vec![Val::I64(mailbox.id() as i64)],
+                DefaultProcessConfig::default(),
+            )
+            .await
+            .unwrap();
+        process.send(Some(42), Vec::new());
+
+        assert_eq!(mailbox.receive(None).await.tag(), Some(42));
+        process.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropping_the_node_kills_its_processes() {
+        let node = TestNode::new().await.unwrap();
+        let module = node
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                        (memory (export "memory") 1)
+                        (func (export "wait")
+                            (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1))))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let process = node
+            .spawn(&module, "wait", Vec::new(), DefaultProcessConfig::default())
+            .await
+            .unwrap();
+
+        drop(node);
+        assert!(process.join().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn message_between_nodes() {
+        let control = TestControl::new().await.unwrap();
+        let receiver = control.node().await.unwrap();
+        // The sender joins last, so it knows about the receiver right away
+        let sender = control.node().await.unwrap();
+        let module = sender
+            .compile(
+                wat::parse_str(
+                    r#"
+                    (module
+                        (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                        (import "lunatic::distributed" "send" (func $send (param i64 i64) (result i32)))
+                        (memory (export "memory") 1)
+                        (func (export "ping") (param $node i64) (param $to i64)
+                            (call $create_data (i64.const 7) (i64.const 0))
+                            (if (i32.ne (call $send (local.get $node) (local.get $to)) (i32.const 0))
+                                (then unreachable)))
+                    )"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mailbox = receiver.mailbox();
+        let process = sender
+            .spawn(
+                &module,
+                "ping",
+                vec![
+                    Val::I64(receiver.node_id().unwrap() as i64),
+                    Val::I64(mailbox.id() as i64),
+                ],
+                DefaultProcessConfig::default(),
+            )
+            .await
+            .unwrap();
+
+        process.join().await.unwrap();
+        assert_eq!(mailbox.receive(None).await.tag(), Some(7));
+    }
+}
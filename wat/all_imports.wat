@@ -10,9 +10,13 @@
     (import "lunatic::message" "read_data" (func (param i32 i32) (result i32)))
     (import "lunatic::message" "seek_data" (func (param i64)))
     (import "lunatic::message" "get_tag" (func (result i64)))
+    (import "lunatic::message" "link_died_reason_size" (func (result i32)))
+    (import "lunatic::message" "link_died_reason" (func (param i32)))
     (import "lunatic::message" "data_size" (func (result i64)))
     (import "lunatic::message" "push_tcp_stream" (func (param i64) (result i64)))
     (import "lunatic::message" "take_tcp_stream" (func (param i64) (result i64)))
+    (import "lunatic::message" "push_config" (func (param i64) (result i64)))
+    (import "lunatic::message" "take_config" (func (param i64) (result i64)))
     (import "lunatic::message" "push_udp_socket" (func (param i64) (result i64)))
     (import "lunatic::message" "take_udp_socket" (func (param i64) (result i64)))
     (import "lunatic::message" "send" (func (param i64) (result i32)))
@@ -74,6 +78,7 @@
     (import "lunatic::process" "compile_module" (func (param i32 i32 i32) (result i32)))
     (import "lunatic::process" "drop_module" (func (param i64)))
     (import "lunatic::process" "create_config" (func (result i64)))
+    (import "lunatic::process" "derive_config" (func (result i64)))
     (import "lunatic::process" "drop_config" (func (param i64)))
     (import "lunatic::process" "config_set_max_memory" (func (param i64 i64)))
     (import "lunatic::process" "config_get_max_memory" (func (param i64) (result i64)))
@@ -85,18 +90,25 @@
     (import "lunatic::process" "config_set_can_create_configs" (func (param i64 i32)))
     (import "lunatic::process" "config_can_spawn_processes" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_spawn_processes" (func (param i64 i32)))
+    (import "lunatic::process" "config_deny_namespace" (func (param i64 i32 i32)))
+    (import "lunatic::process" "config_set_expires_after" (func (param i64 i64)))
     (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
+    (import "lunatic::process" "notify_shutdown" (func (param i32)))
     (import "lunatic::process" "process_id" (func (result i64)))
     (import "lunatic::process" "link" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
     (import "lunatic::process" "kill" (func (param i64)))
+    (import "lunatic::process" "exit" (func (param i32 i32 i32)))
     (import "lunatic::process" "exists" (func (param i64) (result i32)))
 
     (import "lunatic::version" "major" (func (result i32)))
     (import "lunatic::version" "minor" (func (result i32)))
     (import "lunatic::version" "patch" (func (result i32)))
+    (import "lunatic::version" "module_hash" (func (param i32)))
+    (import "lunatic::version" "features_size" (func (result i32)))
+    (import "lunatic::version" "features" (func (param i32)))
 
     (import "lunatic::wasi" "config_add_environment_variable" (func (param i64 i32 i32 i32 i32)))
     (import "lunatic::wasi" "config_add_command_line_argument" (func (param i64 i32 i32)))
@@ -106,6 +118,9 @@
     (import "lunatic::registry" "get" (func (param i32 i32 i32 i32) (result i32)))
     (import "lunatic::registry" "remove" (func (param i32 i32)))
 
+    (import "lunatic::seq" "fetch_add" (func (param i32 i32 i64 i32) (result i32)))
+    (import "lunatic::seq" "get" (func (param i32 i32 i32) (result i32)))
+
     (import "lunatic::distributed" "nodes_count" (func (result i32)))
     (import "lunatic::distributed" "get_nodes" (func (param i32 i32) (result i32)))
     (import "lunatic::distributed" "node_id" (func (result i64)))
@@ -121,5 +136,7 @@
     (import "lunatic::metrics" "decrement_gauge" (func (param i32 i32 f64)))
     (import "lunatic::metrics" "histogram" (func (param i32 i32 f64)))
 
+    (import "lunatic::trap" "panic" (func (param i32 i32 i32 i32)))
+
     (func (export "hello") nop)
 )